@@ -0,0 +1,58 @@
+extern crate timely;
+extern crate differential_dataflow;
+
+use timely::dataflow::*;
+use timely::dataflow::operators::*;
+use timely::progress::timestamp::RootTimestamp;
+
+use differential_dataflow::Collection;
+use differential_dataflow::operators::*;
+use differential_dataflow::timestamp::Pair;
+
+// counts words by the event time at which they were said and the system time at which we heard
+// them, so that counts "as of event time e, known by system time s" can be read off the output.
+fn main() {
+
+    timely::execute_from_args(std::env::args(), move |computation| {
+
+        let (mut input, probe) = computation.scoped::<Pair<u64, u64>,_,_>(|scope| {
+
+            // records arrive at the current system time, and are delayed to their event time.
+            let (input, stream) = scope.new_input();
+            let stream = stream.delay(|x: &((u64, String), i32), time| {
+                RootTimestamp::new(Pair::new((x.0).0, time.inner.second))
+            });
+
+            let counts = Collection::new(stream)
+                .map(|(_, word)| (word, ()))
+                .group(|_, s, t| t.push((s.fold(0, |sum, (_, w)| sum + w), 1)));
+
+            let probe = counts.inner
+                              .inspect_batch(|t, xs| println!("event time {}, system time {}: {:?}", t.inner.first, t.inner.second, xs))
+                              .probe();
+
+            (input, probe.0)
+        });
+
+        if computation.index() == 0 {
+
+            // at system time 0 we hear of two events at event time 0, and one at event time 2.
+            input.send(((0, "hello".to_owned()), 1));
+            input.send(((0, "world".to_owned()), 1));
+            input.send(((2, "hello".to_owned()), 1));
+        }
+
+        input.advance_to(Pair::new(0, 1));
+        while probe.le(&RootTimestamp::new(Pair::new(u64::max_value(), 0))) { computation.step(); }
+
+        if computation.index() == 0 {
+
+            // at system time 1 we learn of a late event at event time 1, and retract one at 0.
+            input.send(((1, "world".to_owned()), 1));
+            input.send(((0, "hello".to_owned()), -1));
+        }
+
+        input.advance_to(Pair::new(0, 2));
+        while probe.le(&RootTimestamp::new(Pair::new(u64::max_value(), 1))) { computation.step(); }
+    });
+}
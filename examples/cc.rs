@@ -2,50 +2,63 @@ extern crate rand;
 extern crate time;
 extern crate getopts;
 extern crate timely;
-// extern crate graph_map;
 extern crate differential_dataflow;
 
 use std::hash::Hash;
 use std::mem;
+use std::time::Duration;
 use timely::dataflow::*;
 use timely::dataflow::operators::*;
+use timely::progress::timestamp::RootTimestamp;
 
-use differential_dataflow::Collection;
+use differential_dataflow::{Collection, Data};
 use differential_dataflow::collection::LeastUpperBound;
 use differential_dataflow::operators::*;
 use differential_dataflow::operators::join::JoinUnsigned;
-use differential_dataflow::operators::group::GroupUnsigned;
-
-// use graph_map::GraphMMap;
+use differential_dataflow::operators::group::{Group, GroupUnsigned};
+use differential_dataflow::input::load_edges;
+use differential_dataflow::server::Server;
 
 type Node = u32;
 type Edge = (Node, Node);
 
 fn main() {
 
-    // snag a filename to use for the input graph.
+    // snag a filename to use for the input graph, and optionally a port from which to serve labels.
     let filename = std::env::args().nth(1).unwrap();
+    let port = std::env::args().nth(2).and_then(|x| x.parse::<u16>().ok());
 
     timely::execute_from_args(std::env::args().skip(1), move |computation| {
 
-        let peers = computation.peers();
         let index = computation.index();
+        let peers = computation.peers();
 
-        // // What you might do if you used GraphMMap:
-        // let graph = GraphMMap::new(&filename);
-        // let nodes = graph.nodes();
-        // let edges = (0..nodes).filter(move |node| node % peers == index)
-        //                       .flat_map(move |node| {
-        //                           let vec = graph.edges(node).to_vec();
-        //                           vec.into_iter().map(move |edge| ((node as u32, edge),1))
-        //                       })
-
-        let edges = vec![((0,1),1), ((1,2),1)].into_iter();
+        let (mut input, probe, labels) = computation.scoped::<u64,_,_>(|scope| {
 
-        computation.scoped::<u64,_,_>(|scope| {
+            // each worker loads a disjoint range of the edge file.
+            let (input, edges) = load_edges(scope, &filename).unwrap();
 
-            connected_components(&Collection::new(edges.to_stream(scope)));
+            // arrange each node's label, so that it can be served.
+            let (labels, arranged) = connected_components(&edges).group_arranged(|_, s, t| t.push((*s.peek().unwrap().0, 1)));
+            (input, labels.inner.probe().0, arranged)
         });
+
+        // send the edges a chunk at a time, letting the computation take each before the next.
+        while input.feed().unwrap() { computation.step(); }
+        drop(input);
+
+        // each worker serves the labels of its nodes from its own port, until interrupted; a client
+        // asks for the label of `node` with `get <node>` at port `port + node.hashed() % peers`.
+        if let Some(port) = port {
+            let address = ("127.0.0.1", port + index as u16);
+            let mut server = Server::bind_tcp(address, index, peers, |node: &Node| node.hashed(), labels, probe).unwrap();
+            server.advance_to(RootTimestamp::new(0));
+            loop {
+                computation.step();
+                server.poll().unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
     });
 }
 
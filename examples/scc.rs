@@ -94,7 +94,7 @@ where G::Timestamp: LeastUpperBound {
         graph.iterate(|edges| {
             let inner = graph.enter(&edges.scope());
             edges.map(|(x,_)| x)
-            //   .threshold(|&x| x, |i| (Vec::new(), i), |_, w| if w > 0 { 1 } else { 0 })
+            //   .threshold(|&x| x, |i| DenseMap::new(i), |_, w| if w > 0 { 1 } else { 0 })
                  .group_by_u(|x|(x,()), |&x,_| x, |_,_,target| target.push(((),1)))
                  .join_by_u(&inner, |x| (x,()), |(s,d)| (d,s), |&d,_,&s| (s,d))
         }).map_in_place(|x| mem::swap(&mut x.0, &mut x.1))
@@ -0,0 +1,178 @@
+//! Queries against the traces maintained by differential dataflow operators.
+//!
+//! A `Trace` records every difference an operator installs, and so can report its collection for
+//! any key at any time it has seen. The `group_arranged` and `group_by_core_arranged` methods return
+//! a `Handle` sharing the operator's output trace, from which the worker can read the collection for
+//! a key, or for all keys, at any time the handle reports as readable.
+//!
+//! A time is readable once it is complete, meaning the operator's input frontier has passed it and
+//! no further differences at or before it will arrive, and provided it has not been compacted.
+//! Traces are compacted when operators retire keys whose differences have cancelled: the history of
+//! such a key is discarded, which is unobservable at times greater or equal to the frontier at which
+//! it was retired, but not at earlier times. The handle records this frontier as `since`.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let (counts, _stats, arranged) = words.group_by_core_arranged(...);
+//! ...
+//! // what were the counts three epochs ago?
+//! if let Some(counts) = arranged.scan(&RootTimestamp::new(round - 3)) {
+//!     for (word, count) in counts { println!("{:?}: {:?}", word, count); }
+//! }
+//! ```
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::fmt::Debug;
+
+use collection::{LeastUpperBound, Lookup, Trace, Offset};
+use iterators::merge::Merge;
+use iterators::coalesce::Coalesce;
+
+/// Read access to the collections of a trace, independent of how the trace indexes its keys.
+pub trait Query<K, T, V> {
+    /// The collection for `key` at `time`, as values and their non-zero multiplicities.
+    fn collection(&self, key: &K, time: &T) -> Vec<(V, i32)>;
+    /// The non-empty collections at `time`, in key order.
+    fn scan(&self, time: &T) -> Vec<(K, Vec<(V, i32)>)>;
+}
+
+impl<K, T, V, L> Query<K, T, V> for Trace<K, T, V, L>
+where K: Ord+Clone, V: Ord+Clone, L: Lookup<K, Offset>, T: LeastUpperBound+Debug {
+    fn collection(&self, key: &K, time: &T) -> Vec<(V, i32)> {
+        self.trace(key)
+            .filter(|x| x.0 <= time)
+            .map(|x| x.1)
+            .merge()
+            .coalesce()
+            .map(|(v, w)| (v.clone(), w))
+            .collect()
+    }
+    fn scan(&self, time: &T) -> Vec<(K, Vec<(V, i32)>)> {
+        let mut result = Vec::new();
+        for key in self.keys() {
+            let collection = self.collection(&key, time);
+            if collection.len() > 0 {
+                result.push((key, collection));
+            }
+        }
+        result.sort_by(|x, y| x.0.cmp(&y.0));
+        result
+    }
+}
+
+/// A shared reference to the trace maintained by an operator, with the frontiers bounding the
+/// times at which it may be read.
+///
+/// The operator holds one copy of the handle, updating the trace and frontiers each time it is
+/// scheduled; the other copy is returned to the dataflow constructor, and may be read at any point
+/// from the worker.
+pub struct Handle<K, T, V> {
+    trace: Rc<RefCell<Query<K, T, V>>>,
+    frontier: Rc<RefCell<Vec<T>>>,
+    since: Rc<RefCell<Option<Vec<T>>>>,
+}
+
+impl<K, T, V> Clone for Handle<K, T, V> {
+    fn clone(&self) -> Handle<K, T, V> {
+        Handle {
+            trace: self.trace.clone(),
+            frontier: self.frontier.clone(),
+            since: self.since.clone(),
+        }
+    }
+}
+
+impl<K, T: PartialOrd+Clone+Default, V> Handle<K, T, V> {
+
+    /// Allocates a new handle sharing `trace`, at which no times are yet complete.
+    pub fn new(trace: Rc<RefCell<Query<K, T, V>>>) -> Handle<K, T, V> {
+        Handle {
+            trace: trace,
+            frontier: Rc::new(RefCell::new(vec![Default::default()])),
+            since: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// The operator's input frontier: times greater or equal to an element may yet change.
+    pub fn frontier(&self) -> Vec<T> { self.frontier.borrow().clone() }
+
+    /// The frontier at which the trace was most recently compacted, if it has been: times not
+    /// greater or equal to any element may no longer be read.
+    pub fn since(&self) -> Option<Vec<T>> { self.since.borrow().clone() }
+
+    /// Reports whether `time` is complete and not compacted, so that its collections may be read.
+    pub fn readable(&self, time: &T) -> bool {
+        let complete = !self.frontier.borrow().iter().any(|f| f <= time);
+        let retained = self.since.borrow().as_ref().map(|since| since.iter().any(|s| s <= time)).unwrap_or(true);
+        complete && retained
+    }
+
+    /// The collection for `key` at `time`, if `time` is readable.
+    pub fn get(&self, key: &K, time: &T) -> Option<Vec<(V, i32)>> {
+        if self.readable(time) { Some(self.trace.borrow().collection(key, time)) }
+        else { None }
+    }
+
+    /// The non-empty collections at `time` in key order, if `time` is readable.
+    pub fn scan(&self, time: &T) -> Option<Vec<(K, Vec<(V, i32)>)>> {
+        if self.readable(time) { Some(self.trace.borrow().scan(time)) }
+        else { None }
+    }
+
+    /// Reports the operator's input frontier, replacing that previously reported.
+    pub fn advance(&self, frontier: &[T]) {
+        *self.frontier.borrow_mut() = frontier.to_vec();
+    }
+
+    /// Reports that the trace has been compacted at `frontier`.
+    pub fn compact(&self, frontier: &[T]) {
+        *self.since.borrow_mut() = Some(frontier.to_vec());
+    }
+}
+
+/// Allocates a shared trace, and a handle reading from it.
+///
+/// The operator maintaining the trace keeps the first, and returns the second.
+pub fn shared<K, T, V, L>(trace: Trace<K, T, V, L>) -> (Rc<RefCell<Trace<K, T, V, L>>>, Handle<K, T, V>)
+where K: Ord+Clone+'static, V: Ord+Clone+'static, L: Lookup<K, Offset>+'static, T: LeastUpperBound+Debug+Clone+Default+'static {
+    let trace = Rc::new(RefCell::new(trace));
+    let handle = Handle::new(trace.clone());
+    (trace, handle)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use collection::Trace;
+    use collection::compact::Compact;
+
+    fn compact(updates: Vec<(u64, u64, i32)>) -> Compact<u64, u64> {
+        let mut result = Compact::new(0, 0);
+        result.extend(updates.into_iter().map(|(k, v, w)| ((k, v), w)));
+        result
+    }
+
+    #[test]
+    fn read_complete_times() {
+
+        let (trace, handle) = super::shared(Trace::<u64, u64, u64, _>::new(HashMap::new()));
+        trace.borrow_mut().set_difference(0, compact(vec![(1, 10, 1), (2, 20, 1)]));
+        trace.borrow_mut().set_difference(1, compact(vec![(1, 10, -1), (1, 11, 1)]));
+
+        assert_eq!(handle.get(&1, &0), None);
+        handle.advance(&[2]);
+        assert_eq!(handle.get(&1, &0), Some(vec![(10, 1)]));
+        assert_eq!(handle.get(&1, &1), Some(vec![(11, 1)]));
+        assert_eq!(handle.get(&3, &1), Some(vec![]));
+        assert_eq!(handle.scan(&1), Some(vec![(1, vec![(11, 1)]), (2, vec![(20, 1)])]));
+        assert_eq!(handle.get(&1, &2), None);
+
+        handle.compact(&[1]);
+        assert_eq!(handle.get(&1, &0), None);
+        assert_eq!(handle.get(&1, &1), Some(vec![(11, 1)]));
+    }
+}
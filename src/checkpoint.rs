@@ -0,0 +1,480 @@
+//! Checkpoints of the state of a differential dataflow computation, and restoration from them.
+//!
+//! A worker calls `restore` with a directory before constructing its dataflow. The stateful
+//! operators (`group`, `join`, `cogroup`, and `threshold`) it then constructs write each difference
+//! they install in their traces to a `BatchLog` in that directory, and if the directory holds a
+//! complete checkpoint, they first reload their traces and pending per-time queues from it.
+//! `restore` returns the input time recorded with that checkpoint, to which the worker should
+//! advance its inputs before supplying the input received from that time on.
+//!
+//! A checkpoint is requested with `checkpoint`, and is taken by each operator the next time it is
+//! scheduled; the worker should step until `complete` reports that all operators have saved their
+//! state. Operators record their input frontiers, before which their traces are complete, and
+//! their pending queues: received input (`inputs`), keys to reconsider (`to_do`), and output not
+//! yet sent (`join`'s `outbuf`). Received input an operator has spilled to disk is saved as files
+//! alongside its queues, linked or copied rather than read back into memory. Retirement of cancelled keys is not recorded; keys that would have
+//! been retired remain in the restored traces until they are next found to have cancelled.
+//!
+//! A checkpoint is consistent only if no data are in flight between operators, which holds if the
+//! worker requests it once its probe has caught up with its inputs, and before it supplies further
+//! input. Each worker checkpoints separately, and should use its own directory, for example one
+//! named by its index; a restart should use the same number of workers.
+//!
+//! Operators find their state by name. By default an operator is named by its kind and the number
+//! of operators of that kind constructed before it since `restore`, as in `GroupBy-2`, so a restart
+//! must construct the stateful operators of each kind in the same order; other operators may
+//! change freely. Calling `name` before constructing an operator gives it an explicit name instead,
+//! which remains valid however the rest of the dataflow changes.
+//!
+//! A checkpoint becomes the one `restore` uses only once `complete` has recorded it, so a process
+//! killed part way through a checkpoint restores from the previous one.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let start = checkpoint::restore::<u64, _>(format!("state/worker-{}", worker.index())).unwrap();
+//! let (mut input, probe) = worker.dataflow(|scope| {
+//!     ...
+//!     checkpoint::name("reach");
+//!     let reach = edges.join(&roots).group(...);
+//!     ...
+//! });
+//! let mut round = start.unwrap_or(0);
+//! input.advance_to(round);
+//! loop {
+//!     ... supply input for round, advance to round + 1, and step until the probe catches up ...
+//!     round += 1;
+//!     checkpoint::checkpoint(&round).unwrap();
+//!     while !checkpoint::complete().unwrap() { worker.step(); }
+//! }
+//! ```
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use collection::persist::{BatchLog, write_durably, write_record, read_record, link_or_copy};
+use collection::compact::Compact;
+
+/// The checkpointing state of a worker.
+struct Registry {
+    id: usize,                  // distinguishes the registries of successive calls to `restore`.
+    directory: PathBuf,
+    restored: Option<u64>,      // the generation restored from, if any.
+    recorded: Option<u64>,      // the most recent complete generation, if any.
+    generation: u64,            // the most recent generation requested or restored.
+    operators: usize,           // the number of participating operators.
+    names: Vec<String>,         // the names of participating operators.
+    kinds: Vec<(&'static str, usize)>,  // the number of operators of each kind registered.
+    next_name: Option<String>,  // the explicit name of the next operator to register.
+    request: Option<Request>,
+}
+
+/// A checkpoint requested but not yet complete.
+struct Request {
+    time: Vec<u8>,              // the serialized input time.
+    saved: usize,               // the number of operators that have saved their state.
+    error: Option<io::Error>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Option<Registry>> = RefCell::new(None);
+}
+
+/// Enables checkpoints in `directory` for operators subsequently constructed by this worker, and
+/// returns the input time of the checkpoint they will restore, if the directory holds one.
+pub fn restore<T: ::timely::Data, P: AsRef<Path>>(directory: P) -> io::Result<Option<T>> {
+
+    let directory = directory.as_ref().to_path_buf();
+    try!(fs::create_dir_all(&directory));
+
+    let manifest = directory.join("checkpoint");
+    let (restored, time) = if manifest.exists() {
+        let mut reader = BufReader::new(try!(File::open(&manifest)));
+        let generation: u64 = try!(read_record(&mut reader));
+        let time: T = try!(read_record(&mut reader));
+        (Some(generation), Some(time))
+    }
+    else {
+        (None, None)
+    };
+
+    static REGISTRIES: AtomicUsize = AtomicUsize::new(0);
+    let registry = Registry {
+        id: REGISTRIES.fetch_add(1, Ordering::SeqCst),
+        directory: directory,
+        restored: restored,
+        recorded: restored,
+        generation: restored.unwrap_or(0),
+        operators: 0,
+        names: Vec::new(),
+        kinds: Vec::new(),
+        next_name: None,
+        request: None,
+    };
+    REGISTRY.with(|x| *x.borrow_mut() = Some(registry));
+
+    Ok(time)
+}
+
+/// Requests a checkpoint recording `time` as the time from which input must be re-supplied.
+///
+/// Returns an error if checkpoints are not enabled or a previous checkpoint is incomplete.
+pub fn checkpoint<T: ::timely::Data>(time: &T) -> io::Result<()> {
+    REGISTRY.with(|x| {
+        match *x.borrow_mut() {
+            Some(ref mut registry) => {
+                if registry.request.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "previous checkpoint incomplete"));
+                }
+                let mut bytes = Vec::new();
+                try!(write_record(&mut bytes, &mut time.clone(), &mut Vec::new()));
+                registry.generation += 1;
+                registry.request = Some(Request { time: bytes, saved: 0, error: None });
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::Other, "checkpoints not enabled; see `restore`")),
+        }
+    })
+}
+
+/// Reports whether the requested checkpoint is complete, recording it as the one to restore if so.
+///
+/// Returns `true` if no checkpoint is requested, and an error if any operator failed to save its
+/// state, in which case the checkpoint is abandoned.
+pub fn complete() -> io::Result<bool> {
+    REGISTRY.with(|x| {
+        match *x.borrow_mut() {
+            Some(ref mut registry) => {
+                let (error, done) = match registry.request {
+                    Some(ref mut request) => (request.error.take(), request.saved == registry.operators),
+                    None => return Ok(true),
+                };
+                if let Some(error) = error {
+                    registry.request = None;
+                    return Err(error);
+                }
+                if done {
+                    let request = registry.request.take().unwrap();
+                    let mut generation = registry.generation;
+                    try!(write_durably(&registry.directory.join("checkpoint"), |writer, bytes| {
+                        try!(write_record(writer, &mut generation, bytes));
+                        writer.write_all(&request.time[..])
+                    }));
+                    registry.recorded = Some(generation);
+                }
+                Ok(done)
+            },
+            None => Ok(true),
+        }
+    })
+}
+
+/// Names the next stateful operator this worker constructs `name`, if checkpoints are enabled.
+///
+/// The operator's state is saved under `name`, which must be unique among the worker's operators
+/// and usable as a file name, and is restored only by an operator given the same name.
+pub fn name(name: &str) {
+    assert!(name.len() > 0 && !name.contains(|c| c == '/' || c == '\\' || c == '.'), "invalid checkpoint name: {:?}", name);
+    REGISTRY.with(|x| {
+        if let Some(ref mut registry) = *x.borrow_mut() {
+            registry.next_name = Some(name.to_owned());
+        }
+    });
+}
+
+/// An operator's connection to its worker's checkpoints.
+///
+/// Dropping the participant withdraws the operator from checkpoints; its saved state remains.
+pub struct Participant<T> {
+    registry: usize,            // the id of the registry the operator joined.
+    name: String,
+    directory: PathBuf,
+    frontier: Option<Vec<T>>,   // the frontier recorded by the restored checkpoint, if any.
+    restored: Option<PathBuf>,  // the state file of the restored checkpoint, if any.
+    saved: u64,                 // the most recent generation saved.
+}
+
+/// Registers an operator of kind `kind` to take part in checkpoints, if enabled.
+///
+/// The operator takes the name supplied to `name`, if any, and is otherwise named by its kind and
+/// the number of operators of that kind registered before it.
+pub fn participant<T: ::timely::Data+PartialOrd>(kind: &'static str) -> Option<Participant<T>> {
+    REGISTRY.with(|x| {
+        x.borrow_mut().as_mut().map(|registry| {
+            let name = match registry.next_name.take() {
+                Some(name) => name,
+                None => {
+                    let position = match registry.kinds.iter().position(|x| x.0 == kind) {
+                        Some(position) => position,
+                        None => { registry.kinds.push((kind, 0)); registry.kinds.len() - 1 },
+                    };
+                    registry.kinds[position].1 += 1;
+                    format!("{}-{}", kind, registry.kinds[position].1 - 1)
+                },
+            };
+            assert!(!registry.names.contains(&name), "checkpoint name {:?} used twice", name);
+            registry.names.push(name.clone());
+            registry.operators += 1;
+            let directory = registry.directory.join(&name);
+            fs::create_dir_all(&directory).expect("failed to create checkpoint directory");
+            let restored = registry.restored.map(|generation| directory.join(format!("checkpoint-{}", generation)));
+            let frontier = restored.as_ref().map(|path| {
+                let mut reader = BufReader::new(File::open(path).expect("failed to open checkpoint"));
+                read_record(&mut reader).expect("failed to read checkpoint")
+            });
+            Participant {
+                registry: registry.id,
+                name: name,
+                directory: directory,
+                frontier: frontier,
+                restored: restored,
+                // an operator registering while a checkpoint is requested must also save its state.
+                saved: registry.generation.saturating_sub(1),
+            }
+        })
+    })
+}
+
+impl<T: ::timely::Data+PartialOrd> Participant<T> {
+
+    /// Opens the log of differences named `name`, supplying any restored to `install`.
+    ///
+    /// If no checkpoint is restored, the log is cleared of differences from earlier runs.
+    pub fn log<K, V, F>(&self, name: &str, install: F) -> BatchLog<T>
+    where K: ::timely::Data, V: ::timely::Data, F: FnMut(T, Compact<K, V>) {
+        let mut log = BatchLog::open(self.directory.join(name)).expect("failed to open checkpoint log");
+        match self.frontier {
+            Some(ref frontier) => {
+                log.advance(frontier).expect("failed to restore checkpoint log");
+                log.recover(install).expect("failed to restore checkpoint log");
+            },
+            None => log.clear().expect("failed to clear checkpoint log"),
+        }
+        log
+    }
+
+    /// The directory holding the operator's checkpoints, in which the files saved with its pending
+    /// state are found by the names `Files::save` returned.
+    pub fn directory(&self) -> &Path { &self.directory }
+
+    /// The pending state saved by the operator in the restored checkpoint, if any.
+    pub fn restored<S: ::timely::Data>(&self) -> Option<S> {
+        self.restored.as_ref().map(|path| {
+            let mut reader = BufReader::new(File::open(path).expect("failed to open checkpoint"));
+            let _frontier: Vec<T> = read_record(&mut reader).expect("failed to read checkpoint");
+            read_record(&mut reader).expect("failed to read checkpoint")
+        })
+    }
+
+    /// Reports whether a checkpoint awaits this operator's state.
+    pub fn requested(&self) -> bool {
+        REGISTRY.with(|x| x.borrow().as_ref().map(|r| r.request.is_some() && r.generation > self.saved).unwrap_or(false))
+    }
+
+    /// Saves `frontier`, before which the operator's logs are complete, and the pending state
+    /// produced by `state`, as the operator's part of the requested checkpoint.
+    ///
+    /// `state` may save files with the checkpoint using the supplied `Files`, recording their names
+    /// in the state it produces.
+    pub fn save<S: ::timely::Data, F: FnOnce(&mut Files)->io::Result<S>>(&mut self, frontier: &[T], state: F) {
+        REGISTRY.with(|x| {
+            if let Some(ref mut registry) = *x.borrow_mut() {
+                let generation = registry.generation;
+                let path = self.directory.join(format!("checkpoint-{}", generation));
+                let mut files = Files { directory: &self.directory, generation: generation, saved: 0 };
+                let result = state(&mut files).and_then(|mut state| {
+                    let mut frontier = frontier.to_vec();
+                    write_durably(&path, |writer, bytes| {
+                        try!(write_record(writer, &mut frontier, bytes));
+                        write_record(writer, &mut state, bytes)
+                    })
+                });
+
+                // only the most recent complete generation may yet be restored; remove the others,
+                // along with the files saved with them.
+                if let Ok(entries) = fs::read_dir(&self.directory) {
+                    for entry in entries.filter_map(|x| x.ok()) {
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        if name.starts_with("checkpoint-") {
+                            let saved = name["checkpoint-".len()..].split('-').next().unwrap();
+                            let keep = saved.parse::<u64>().ok().map(|x| x == generation || Some(x) == registry.recorded);
+                            if keep == Some(false) {
+                                let _ = fs::remove_file(entry.path());
+                            }
+                        }
+                    }
+                }
+
+                if let Some(ref mut request) = registry.request {
+                    request.saved += 1;
+                    if let Err(error) = result {
+                        request.error = Some(error);
+                    }
+                }
+                self.saved = generation;
+            }
+        });
+    }
+}
+
+/// Saves files with an operator's pending state, as part of the requested checkpoint.
+pub struct Files<'a> {
+    directory: &'a Path,
+    generation: u64,
+    saved: usize,               // the number of files saved.
+}
+
+impl<'a> Files<'a> {
+    /// Saves the file at `path`, which must not change afterwards, and returns the name by which
+    /// it is found in the operator's `directory` when the checkpoint is restored.
+    pub fn save(&mut self, path: &Path) -> io::Result<String> {
+        let name = format!("checkpoint-{}-file-{}", self.generation, self.saved);
+        self.saved += 1;
+        try!(link_or_copy(path, &self.directory.join(&name)));
+        Ok(name)
+    }
+}
+
+impl<T> Drop for Participant<T> {
+    fn drop(&mut self) {
+        REGISTRY.with(|x| {
+            if let Some(ref mut registry) = *x.borrow_mut() {
+                if registry.id == self.registry {
+                    registry.operators -= 1;
+                    registry.names.retain(|x| x != &self.name);
+                    if let Some(ref mut request) = registry.request {
+                        if self.saved == registry.generation {
+                            request.saved -= 1;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use collection::compact::Compact;
+
+    fn compact(keys: Vec<u64>) -> Compact<u64, u64> {
+        let mut result = Compact::new(0, 0);
+        result.extend(keys.into_iter().map(|k| ((k, k), 1)));
+        result
+    }
+
+    #[test]
+    fn restore_complete_checkpoint() {
+
+        let path = ::std::env::temp_dir().join(format!("differential-checkpoint-{}", ::time::precise_time_ns()));
+
+        assert_eq!(super::restore::<u64, _>(&path).unwrap(), None);
+        let mut participant = super::participant::<u64>("Test").unwrap();
+        let mut log = participant.log("trace", |_, _: Compact<u64, u64>| panic!("nothing to restore"));
+        log.append(&0, &compact(vec![1, 2])).unwrap();
+
+        super::checkpoint(&1u64).unwrap();
+        assert!(!super::complete().unwrap());
+        assert!(participant.requested());
+        participant.save(&[1], |_| Ok(vec![(1u64, vec![5u64])]));
+        assert!(!participant.requested());
+        assert!(super::complete().unwrap());
+
+        // a checkpoint the process is killed part way through is not restored.
+        log.append(&1, &compact(vec![3])).unwrap();
+        super::checkpoint(&2u64).unwrap();
+        participant.save(&[2], |_| Ok(vec![(2u64, vec![6u64])]));
+
+        assert_eq!(super::restore::<u64, _>(&path).unwrap(), Some(1));
+        let participant = super::participant::<u64>("Test").unwrap();
+        let mut times = Vec::new();
+        participant.log("trace", |time, _: Compact<u64, u64>| times.push(time));
+        assert_eq!(times, vec![0]);
+        assert_eq!(participant.restored::<Vec<(u64, Vec<u64>)>>(), Some(vec![(1, vec![5])]));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn names_and_withdrawal() {
+
+        let path = ::std::env::temp_dir().join(format!("differential-checkpoint-names-{}", ::time::precise_time_ns()));
+
+        super::restore::<u64, _>(&path).unwrap();
+        let group0 = super::participant::<u64>("GroupBy").unwrap();
+        let mut join = super::participant::<u64>("Join").unwrap();
+        super::name("reach");
+        let mut reach = super::participant::<u64>("GroupBy").unwrap();
+        let mut group1 = super::participant::<u64>("GroupBy").unwrap();
+        assert!(path.join("GroupBy-0").is_dir());
+        assert!(path.join("GroupBy-1").is_dir());
+        assert!(path.join("Join-0").is_dir());
+        assert!(path.join("reach").is_dir());
+
+        // a dropped operator is not waited for.
+        drop(group0);
+        super::checkpoint(&1u64).unwrap();
+        join.save(&[1], |_| Ok(1u64));
+        reach.save(&[1], |_| Ok(2u64));
+        assert!(!super::complete().unwrap());
+
+        // nor is one dropped after saving its state, whose place is taken by a new operator.
+        drop(reach);
+        super::name("reach");
+        let mut reach = super::participant::<u64>("GroupBy").unwrap();
+        assert!(reach.requested());
+        group1.save(&[1], |_| Ok(3u64));
+        assert!(!super::complete().unwrap());
+        reach.save(&[1], |_| Ok(4u64));
+        assert!(super::complete().unwrap());
+
+        // operators find their state by name, whatever the order of construction.
+        super::restore::<u64, _>(&path).unwrap();
+        super::name("reach");
+        let reach = super::participant::<u64>("GroupBy").unwrap();
+        super::name("GroupBy-1");
+        let group1 = super::participant::<u64>("GroupBy").unwrap();
+        let join = super::participant::<u64>("Join").unwrap();
+        assert_eq!(reach.restored::<u64>(), Some(4));
+        assert_eq!(group1.restored::<u64>(), Some(3));
+        assert_eq!(join.restored::<u64>(), Some(1));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn saved_files() {
+
+        let path = ::std::env::temp_dir().join(format!("differential-checkpoint-files-{}", ::time::precise_time_ns()));
+
+        super::restore::<u64, _>(&path).unwrap();
+        let mut participant = super::participant::<u64>("Test").unwrap();
+        let source = path.join("spilled");
+        File::create(&source).unwrap().write_all(b"spilled run").unwrap();
+
+        // each checkpoint saves its own copy of the file, and those of superseded ones are removed.
+        for time in 1 .. 4u64 {
+            super::checkpoint(&time).unwrap();
+            participant.save(&[time], |files| files.save(&source));
+            assert!(super::complete().unwrap());
+        }
+        fs::remove_file(&source).unwrap();
+        assert!(!path.join("Test").join("checkpoint-1-file-0").exists());
+
+        super::restore::<u64, _>(&path).unwrap();
+        let participant = super::participant::<u64>("Test").unwrap();
+        let name = participant.restored::<String>().unwrap();
+        let mut contents = String::new();
+        File::open(participant.directory().join(&name)).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "spilled run");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,601 @@
+//! Memory-bounded accumulation of the `(key, val, wgt)` triples received at a time.
+//!
+//! Operators receive their input in batches, and must hold on to the batches for each time until
+//! they are notified that the time is complete, at which point the batches are sorted into a
+//! `Compact`. If a time receives a great deal of data, or many times are outstanding at once, this
+//! can require much more memory than is available.
+//!
+//! An `Accumulator` holds the batches for one time, as received, along with the function mapping
+//! each received record to its `(key, val)` pair. Without a budget, it simply retains them, and
+//! maps and sorts them when finished exactly as the operators always have. With a budget, it sorts and
+//! coalesces its batches into runs as they arrive, merging runs of similar size, and once the runs
+//! would exceed the budget it merges them and writes the result to a temporary file. When finished,
+//! the runs in memory and on disk are merged into a `Compact`.
+//!
+//! An accumulator holds at most its budget in sorted runs, and about half its budget in unsorted
+//! batches, which are sealed into a run once they reach that size. Sealing maps the batches into a
+//! new run, and merging two runs writes a new one, so while doing either an accumulator may use up
+//! to about twice its budget.
+//!
+//! Sizes are estimated from the in-memory size of `((key, val), wgt)` triples, and do not account
+//! for any memory the keys and values own (e.g. the contents of a `String`).
+//!
+//! Budgets are set per worker thread, and apply to each accumulator constructed by the thread
+//! afterwards; there is one accumulator for each operator input and each time with pending data.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! // spill pending input beyond 64MB per operator input and time to /tmp.
+//! differential_dataflow::collection::accumulator::set_budget(64 << 20, "/tmp");
+//! ```
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+use timely_sort::{LSBRadixSorter, Unsigned};
+
+use ::Data;
+use checkpoint::Files;
+use collection::compact::Compact;
+use collection::persist::{write_record, read_record, link_or_copy};
+use iterators::coalesce::Coalesce;
+use iterators::merge::Merge;
+
+/// A limit on the memory used by each accumulator, and a directory for data beyond it.
+#[derive(Clone, Debug)]
+pub struct Budget {
+    /// The number of bytes an accumulator may hold in sorted runs before spilling them to disk.
+    pub bytes: usize,
+    /// The directory in which to create temporary files for spilled runs.
+    pub directory: PathBuf,
+}
+
+thread_local! {
+    static BUDGET: RefCell<Option<Budget>> = RefCell::new(None);
+}
+
+/// Sets the budget for accumulators subsequently constructed by this worker thread.
+pub fn set_budget<P: AsRef<Path>>(bytes: usize, directory: P) {
+    let budget = Budget { bytes: bytes, directory: directory.as_ref().to_path_buf() };
+    BUDGET.with(|x| *x.borrow_mut() = Some(budget));
+}
+
+/// Removes any budget for accumulators subsequently constructed by this worker thread.
+pub fn clear_budget() {
+    BUDGET.with(|x| *x.borrow_mut() = None);
+}
+
+/// The contents of an accumulator saved by `snapshot`: triples held in memory, and the names and
+/// lengths of the runs spilled to disk, saved as files alongside them.
+pub type Snapshot<K, V> = (Vec<((K, V), i32)>, Vec<(String, usize)>);
+
+/// Accumulates batches of `(datum, wgt)` pairs, mapped by `kv` to `((key, val), wgt)` triples, and
+/// spills sorted runs to disk if over budget.
+pub struct Accumulator<D, K, V, F: Fn(D)->(K, V)> {
+    kv: Rc<F>,
+    batches: Vec<Vec<(D, i32)>>,        // received batches, not yet mapped or sorted.
+    batch_bytes: usize,
+    runs: Vec<Vec<((K, V), i32)>>,      // sorted and coalesced runs, decreasing in size.
+    run_bytes: usize,
+    spilled: Vec<Spill>,                // sorted and coalesced runs written to disk.
+    budget: Option<Budget>,
+}
+
+impl<K: Data, V: Data> Accumulator<(K, V), K, V, fn((K, V))->(K, V)> {
+
+    /// Constructs a new `Accumulator` of `(key, val)` pairs, using this thread's budget, if any.
+    pub fn new() -> Accumulator<(K, V), K, V, fn((K, V))->(K, V)> {
+        Accumulator::mapped(Rc::new(identity as fn((K, V))->(K, V)))
+    }
+}
+
+impl<D: Data, K: Data, V: Data, F: Fn(D)->(K, V)> Accumulator<D, K, V, F> {
+
+    /// Constructs a new `Accumulator` mapping records with `kv`, using this thread's budget, if any.
+    pub fn mapped(kv: Rc<F>) -> Accumulator<D, K, V, F> {
+        Accumulator::with_budget(kv, BUDGET.with(|x| x.borrow().clone()))
+    }
+
+    /// Constructs a new `Accumulator` with the supplied budget; `None` indicates no limit.
+    pub fn with_budget(kv: Rc<F>, budget: Option<Budget>) -> Accumulator<D, K, V, F> {
+        Accumulator {
+            kv: kv,
+            batches: Vec::new(),
+            batch_bytes: 0,
+            runs: Vec::new(),
+            run_bytes: 0,
+            spilled: Vec::new(),
+            budget: budget,
+        }
+    }
+
+    /// The number of runs written to disk.
+    pub fn spilled(&self) -> usize { self.spilled.len() }
+
+    /// Adds a batch of records, sorting and spilling runs if the budget requires it.
+    ///
+    /// An error indicates a failure to write a run to disk. The accumulated data are unaffected, and
+    /// remain in memory.
+    pub fn push(&mut self, batch: Vec<(D, i32)>) -> io::Result<()> {
+        self.batch_bytes += bytes::<(D, i32)>(batch.len());
+        self.batches.push(batch);
+
+        let limit = self.budget.as_ref().map(|x| x.bytes);
+        if let Some(limit) = limit {
+            if self.batch_bytes > limit / 2 {
+                if let Some(run) = self.seal() {
+                    try!(self.add_run(run));
+                }
+            }
+        }
+
+        self.check_budget()
+    }
+
+    /// Adds a batch of already mapped triples, such as those of a `snapshot`, as a sorted run.
+    ///
+    /// An error indicates a failure to write a run to disk, as for `push`.
+    pub fn push_keyed(&mut self, mut triples: Vec<((K, V), i32)>) -> io::Result<()> {
+        triples.sort_by(|x, y| x.0.cmp(&y.0));
+        coalesce_in_place(&mut triples);
+        try!(self.add_run(triples));
+        self.check_budget()
+    }
+
+    /// Spills the runs in memory to disk if they exceed the budget, as a single run may.
+    fn check_budget(&mut self) -> io::Result<()> {
+        let limit = self.budget.as_ref().map(|x| x.bytes);
+        if let Some(limit) = limit {
+            if self.run_bytes > limit {
+                try!(self.spill());
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves everything received, without disturbing the accumulator.
+    ///
+    /// This is used to checkpoint data received but not yet processed. The triples in memory are
+    /// copied out, in no particular order, and the runs spilled to disk are saved with `files`
+    /// rather than read back into memory.
+    pub fn snapshot(&self, files: &mut Files) -> io::Result<Snapshot<K, V>> {
+        let mut triples = Vec::new();
+        for batch in self.batches.iter() {
+            triples.extend(batch.iter().map(|&(ref datum, wgt)| ((self.kv)(datum.clone()), wgt)));
+        }
+        for run in self.runs.iter() {
+            triples.extend(run.iter().cloned());
+        }
+        let mut runs = Vec::new();
+        for spill in &self.spilled {
+            let name = try!(files.save(&spill.path).map_err(|error| annotate(error, &spill.path)));
+            runs.push((name, spill.records));
+        }
+        Ok((triples, runs))
+    }
+
+    /// Adds the contents of a `snapshot`, whose files are found in `directory`.
+    ///
+    /// The saved runs are linked or copied into new spill files, and are not read until finished;
+    /// an error indicates a failure to write them or the triples to disk.
+    pub fn restore(&mut self, snapshot: Snapshot<K, V>, directory: &Path) -> io::Result<()> {
+        let (triples, runs) = snapshot;
+        try!(self.push_keyed(triples));
+        let spills = self.budget.as_ref().map(|x| x.directory.clone()).unwrap_or_else(::std::env::temp_dir);
+        for (name, records) in runs {
+            let (path, _) = try!(create_spill_file(&spills).map_err(|error| annotate(error, &spills)));
+            let spill = Spill { path: path, records: records };
+            let source = directory.join(name);
+            try!(link_or_copy(&source, &spill.path).map_err(|error| annotate(error, &source)));
+            self.spilled.push(spill);
+        }
+        Ok(())
+    }
+
+    /// Sorts and coalesces everything received into a `Compact`, or `None` if it is empty.
+    ///
+    /// If nothing was sorted early, this sorts the batches as the operators always have: merging
+    /// them if each is already sorted, radix sorting them if there are many, and using `sort_by`
+    /// otherwise. If there are sorted runs, in memory or on disk, they are merged instead.
+    ///
+    /// Batches are mapped into vectors only while they remain sorted. Unsorted input is usually
+    /// detected within a few records, after which the remaining records are mapped as they are
+    /// handed to the sorter, without an intermediate copy.
+    pub fn finish<U: Unsigned+Default, H: Fn(&K)->U>(mut self, sorter: &mut LSBRadixSorter<((K, V), i32)>, key_h: &H) -> Option<Compact<K, V>> {
+
+        if self.runs.len() == 0 && self.spilled.len() == 0 {
+
+            let kv = self.kv.clone();
+            let count = self.batches.len();
+            let mut batches = ::std::mem::replace(&mut self.batches, Vec::new()).into_iter();
+
+            // map batches while they remain sorted, to merge them if all are.
+            let mut sorted = Vec::with_capacity(count);
+            while let Some(batch) = batches.next() {
+
+                let mut batch = batch.into_iter();
+                let mut run: Vec<((K, V), i32)> = Vec::new();
+                let mut ordered = true;
+                while ordered {
+                    match batch.next() {
+                        Some((datum, wgt)) => {
+                            let element = (kv(datum), wgt);
+                            ordered = run.last().map(|x| x.0 <= element.0).unwrap_or(true);
+                            run.push(element);
+                            if run.len() == 16 { run.reserve(batch.len()); }
+                        },
+                        None => break,
+                    }
+                }
+
+                // sort things; radix if many, .sort_by if few.
+                if !ordered {
+                    let kv = kv.clone();
+                    let rest = sorted.into_iter()
+                                     .chain(Some(run).into_iter())
+                                     .flat_map(|x| x.into_iter())
+                                     .chain(batch.chain(batches.flat_map(|x| x.into_iter()))
+                                                 .map(move |(d, w)| (kv(d), w)));
+                    if count > 1 {
+                        sorter.extend(rest, &|x| key_h(&(x.0).0));
+                        let mut sorted = sorter.finish(&|x| key_h(&(x.0).0));
+                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
+                        sorted.truncate(256);
+                        sorter.recycle(sorted);
+                        return result;
+                    }
+                    else {
+                        let mut vec = rest.collect::<Vec<_>>();
+                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
+                        return Compact::from_radix(&mut vec![vec], &|k| key_h(k));
+                    }
+                }
+
+                sorted.push(run);
+            }
+
+            Compact::from_sorted(&mut sorted)
+        }
+        else {
+
+            if let Some(run) = self.seal() {
+                self.runs.push(run);
+            }
+
+            let mut iters: Vec<Box<Iterator<Item=((K, V), i32)>>> = Vec::new();
+            for run in self.runs.drain(..) {
+                iters.push(Box::new(run.into_iter()));
+            }
+            for spill in self.spilled.drain(..) {
+                match SpillReader::open(spill) {
+                    Ok(reader) => iters.push(Box::new(reader)),
+                    Err(error) => panic!("failed to read spilled run: {}", error),
+                }
+            }
+
+            let mut result = Compact::new(0, 0);
+            result.extend(iters.into_iter().merge().coalesce());
+
+            if result.vals.len() > 0 {
+                result.keys.shrink_to_fit();
+                result.cnts.shrink_to_fit();
+                result.vals.shrink_to_fit();
+                Some(result)
+            }
+            else {
+                None
+            }
+        }
+    }
+
+    /// Maps, sorts, and coalesces the received batches into a run, releasing each batch once mapped.
+    fn seal(&mut self) -> Option<Vec<((K, V), i32)>> {
+        if self.batches.len() > 0 {
+            let kv = &self.kv;
+            let mut run = Vec::with_capacity(self.batches.iter().fold(0, |sum, x| sum + x.len()));
+            for batch in self.batches.drain(..) {
+                run.extend(batch.into_iter().map(|(d, w)| (kv(d), w)));
+            }
+            run.sort_by(|x: &((K, V), i32), y: &((K, V), i32)| x.0.cmp(&y.0));
+            coalesce_in_place(&mut run);
+            self.batch_bytes = 0;
+            Some(run)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Adds a sorted and coalesced run, and merges runs of similar sizes.
+    ///
+    /// If the new run would take the runs in memory over budget, those runs are spilled first, so
+    /// that merges copy no more than the budget. An error indicates a failure to spill; the run is
+    /// added regardless.
+    fn add_run(&mut self, run: Vec<((K, V), i32)>) -> io::Result<()> {
+
+        let limit = self.budget.as_ref().map(|x| x.bytes);
+        let spilled = match limit {
+            Some(limit) if self.runs.len() > 0 && self.run_bytes + bytes::<((K, V), i32)>(run.len()) > limit => self.spill(),
+            _ => Ok(()),
+        };
+
+        self.runs.push(run);
+
+        // merge the smallest runs while the smaller is at least half the size of the larger.
+        while self.runs.len() > 1 && 2 * self.runs[self.runs.len() - 1].len() >= self.runs[self.runs.len() - 2].len() {
+            let run1 = self.runs.pop().unwrap();
+            let run2 = self.runs.pop().unwrap();
+            self.runs.push(vec![run1.into_iter(), run2.into_iter()].into_iter().merge().coalesce().collect());
+        }
+
+        self.run_bytes = bytes::<((K, V), i32)>(self.runs.iter().fold(0, |sum, x| sum + x.len()));
+        spilled
+    }
+
+    /// Merges the runs in memory and writes the result to a new temporary file.
+    ///
+    /// An error names the file or directory involved.
+    fn spill(&mut self) -> io::Result<()> {
+        if let Some(budget) = self.budget.as_ref() {
+
+            let (path, file) = try!(create_spill_file(&budget.directory).map_err(|error| annotate(error, &budget.directory)));
+
+            // the file is removed when `spill` is dropped, including on error.
+            let mut spill = Spill { path: path, records: 0 };
+            let written = {
+                let mut writer = BufWriter::new(file);
+                let runs = self.runs.iter().map(|x| x.iter().cloned());
+                write_triples(&mut writer, runs.merge().coalesce()).and_then(|records| { try!(writer.flush()); Ok(records) })
+            };
+            spill.records = try!(written.map_err(|error| annotate(error, &spill.path)));
+
+            self.runs.clear();
+            self.run_bytes = 0;
+            self.spilled.push(spill);
+        }
+        Ok(())
+    }
+}
+
+/// Maps a `(key, val)` pair to itself, for accumulators of pairs.
+fn identity<K, V>(pair: (K, V)) -> (K, V) { pair }
+
+/// Accumulates the weights of adjacent equal records of a sorted `run`, discarding those whose
+/// weights cancel, without copying the run.
+fn coalesce_in_place<T: Eq>(run: &mut Vec<(T, i32)>) {
+    let mut cursor = 0;
+    for index in 0 .. run.len() {
+        if cursor > 0 && run[cursor - 1].0 == run[index].0 {
+            run[cursor - 1].1 += run[index].1;
+        }
+        else {
+            if cursor > 0 && run[cursor - 1].1 == 0 { cursor -= 1; }
+            run.swap(cursor, index);
+            cursor += 1;
+        }
+    }
+    if cursor > 0 && run[cursor - 1].1 == 0 { cursor -= 1; }
+    run.truncate(cursor);
+    run.shrink_to_fit();
+}
+
+/// Adds `path` to the message of `error`, keeping its kind.
+fn annotate(error: io::Error, path: &Path) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+/// The estimated size in bytes of `count` elements of type `T`.
+fn bytes<T>(count: usize) -> usize {
+    count * ::std::mem::size_of::<T>()
+}
+
+/// A run of triples written to a file, which is removed when the `Spill` is dropped.
+struct Spill {
+    path: PathBuf,
+    records: usize,
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Creates a new file with a name unique to this process and thread in `directory`.
+fn create_spill_file(directory: &Path) -> io::Result<(PathBuf, File)> {
+    static SPILLS: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let name = format!("differential-{}-{}.run", ::time::precise_time_ns(), SPILLS.fetch_add(1, Ordering::SeqCst));
+        let path = directory.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => { },
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Writes `triples` to `writer` for `read_triple`, returning the number written.
+fn write_triples<W: Write, K: Data, V: Data, I: Iterator<Item=((K, V), i32)>>(writer: &mut W, triples: I) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    let mut records = 0;
+    for ((mut key, mut val), wgt) in triples {
+        try!(write_record(writer, &mut key, &mut bytes));
+        try!(write_record(writer, &mut val, &mut bytes));
+        let mut word = [0u8; 4];
+        LittleEndian::write_i32(&mut word, wgt);
+        try!(writer.write_all(&word));
+        records += 1;
+    }
+    Ok(records)
+}
+
+/// Reads a triple written by `write_triples`.
+fn read_triple<R: Read, K: Data, V: Data>(reader: &mut R) -> io::Result<((K, V), i32)> {
+    let key = try!(read_record(reader));
+    let val = try!(read_record(reader));
+    let mut word = [0u8; 4];
+    try!(reader.read_exact(&mut word));
+    Ok(((key, val), LittleEndian::read_i32(&word)))
+}
+
+/// Enumerates the triples of a spilled run, removing its file once dropped.
+struct SpillReader<K, V> {
+    reader: BufReader<File>,
+    spill: Spill,
+    phantom: ::std::marker::PhantomData<(K, V)>,
+}
+
+impl<K: Data, V: Data> SpillReader<K, V> {
+    fn open(spill: Spill) -> io::Result<SpillReader<K, V>> {
+        let file = try!(File::open(&spill.path).map_err(|error| annotate(error, &spill.path)));
+        Ok(SpillReader {
+            reader: BufReader::new(file),
+            spill: spill,
+            phantom: ::std::marker::PhantomData,
+        })
+    }
+}
+
+impl<K: Data, V: Data> Iterator for SpillReader<K, V> {
+    type Item = ((K, V), i32);
+    fn next(&mut self) -> Option<((K, V), i32)> {
+        if self.spill.records > 0 {
+            self.spill.records -= 1;
+            match read_triple(&mut self.reader) {
+                Ok(triple) => Some(triple),
+                Err(error) => panic!("failed to read spilled run: {}", annotate(error, &self.spill.path)),
+            }
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use timely_sort::LSBRadixSorter;
+
+    use checkpoint;
+    use collection::compact::Compact;
+    use iterators::coalesce::Coalesce;
+    use super::{Accumulator, Budget, Snapshot};
+
+    // unsorted records with repetitions and cancellations, in batches of twenty.
+    fn batches() -> Vec<Vec<((u64, u64), i32)>> {
+        let records = (0 .. 2000u64).map(|i| (((i * 37) % 101, (i * 11) % 7), if i % 5 == 0 { -1 } else { 1 }));
+        records.collect::<Vec<_>>().chunks(20).map(|x| x.to_vec()).collect()
+    }
+
+    fn consolidate(mut triples: Vec<((u64, u64), i32)>) -> Vec<((u64, u64), i32)> {
+        triples.sort_by(|x, y| x.0.cmp(&y.0));
+        triples.into_iter().coalesce().collect()
+    }
+
+    fn flatten(compact: Option<Compact<u64, u64>>) -> Vec<((u64, u64), i32)> {
+        let mut result = Vec::new();
+        if let Some(compact) = compact {
+            let mut vals = compact.vals.into_iter();
+            for (key, cnt) in compact.keys.into_iter().zip(compact.cnts.into_iter()) {
+                for (val, wgt) in vals.by_ref().take(cnt as usize) {
+                    result.push(((key, val), wgt));
+                }
+            }
+        }
+        consolidate(result)
+    }
+
+    fn directory() -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!("differential-accumulator-{}", ::time::precise_time_ns()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn spill_matches_memory() {
+
+        let expected = consolidate(batches().into_iter().flat_map(|x| x.into_iter()).collect());
+        let path = directory();
+
+        // a budget of a few dozen triples.
+        let budget = Budget { bytes: 1024, directory: path.clone() };
+        let mut spilling = Accumulator::with_budget(Rc::new(|x: (u64, u64)| x), Some(budget));
+        let mut memory = Accumulator::with_budget(Rc::new(|x: (u64, u64)| x), None);
+        for batch in batches() {
+            spilling.push(batch.clone()).unwrap();
+            memory.push(batch).unwrap();
+        }
+
+        assert!(spilling.spilled() > 1);
+        assert_eq!(memory.spilled(), 0);
+
+        let mut sorter = LSBRadixSorter::new();
+        assert_eq!(flatten(spilling.finish(&mut sorter, &|k: &u64| *k)), expected);
+        assert_eq!(flatten(memory.finish(&mut sorter, &|k: &u64| *k)), expected);
+
+        // spilled runs are removed once read.
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+        fs::remove_dir(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_snapshot() {
+
+        let expected = consolidate(batches().into_iter().flat_map(|x| x.into_iter()).collect());
+        let path = directory();
+        let budget = Budget { bytes: 1024, directory: path.clone() };
+
+        // snapshot part way through, with runs on disk, in memory, and unsorted batches.
+        let mut original = Accumulator::with_budget(Rc::new(|x: (u64, u64)| x), Some(budget.clone()));
+        let mut batches = batches().into_iter();
+        for batch in batches.by_ref().take(55) {
+            original.push(batch).unwrap();
+        }
+        assert!(original.spilled() > 0);
+        let checkpoints = directory();
+        checkpoint::restore::<u64, _>(&checkpoints).unwrap();
+        let mut participant = checkpoint::participant::<u64>("Test").unwrap();
+        checkpoint::checkpoint(&1u64).unwrap();
+        participant.save(&[1], |files| original.snapshot(files));
+        assert!(checkpoint::complete().unwrap());
+
+        // restore into a new accumulator, with the spilled runs still on disk, and continue with both.
+        checkpoint::restore::<u64, _>(&checkpoints).unwrap();
+        let participant = checkpoint::participant::<u64>("Test").unwrap();
+        let snapshot = participant.restored::<Snapshot<u64, u64>>().unwrap();
+        assert_eq!(snapshot.1.len(), original.spilled());
+        let mut restored = Accumulator::with_budget(Rc::new(|x: (u64, u64)| x), Some(budget));
+        restored.restore(snapshot, participant.directory()).unwrap();
+        assert!(restored.spilled() >= original.spilled());
+        for batch in batches {
+            original.push(batch.clone()).unwrap();
+            restored.push(batch).unwrap();
+        }
+
+        let mut sorter = LSBRadixSorter::new();
+        assert_eq!(flatten(original.finish(&mut sorter, &|k: &u64| *k)), expected);
+        assert_eq!(flatten(restored.finish(&mut sorter, &|k: &u64| *k)), expected);
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+        fs::remove_dir(&path).unwrap();
+        fs::remove_dir_all(&checkpoints).unwrap();
+    }
+
+    #[test]
+    fn coalesce_in_place() {
+        let mut run = vec![(0, 1), (0, -1), (1, 2), (2, 1), (2, 1), (3, -1), (3, 1), (4, 0), (5, 3)];
+        super::coalesce_in_place(&mut run);
+        assert_eq!(run, vec![(1, 2), (2, 2), (5, 3)]);
+    }
+}
@@ -10,24 +10,25 @@
 //! maintain received data compactly, rather than have to accumulate the large list of triples and
 //! sort it only once complete.
 //!
-//! This module provides a `Accumulator` structure capable of receiving `(key, val, wgt)` triples and
-//! which compacts them as they arrive, maintaining no more than 1.5x the space required for the
-//! list of values. It can be configured to have a minimum capacity, so if there is enough space
-//! the `Accumulator` structure will only accumulate elements in a list, then sort and coalesce, doing
-//! exactly what we would have done in the simple case. As memory gets tighter, it behaves more
-//! responsiby.
+//! The `accumulator` module provides an `Accumulator` structure capable of receiving `(key, val, wgt)`
+//! triples and which compacts them as they arrive, using no more than about twice the space of
+//! its budget. Without a budget the `Accumulator` structure will only accumulate elements in a list,
+//! then sort and coalesce, doing exactly what we would have done in the simple case. As memory
+//! gets tighter, it behaves more responsibly, and spills sorted runs to disk.
 
 use iterators::coalesce::Coalesce;
+use iterators::merge::Merge;
+use iterators::run_length::RunLength;
+use collection::{OffsetInt, to_offset};
 
 use std::fmt::Debug;
 
 use timely_sort::Unsigned;
 
 /// A compressed representation of the accumulation of `(key, val, wgt)` triples.
-// TODO : RLE where a run of two of the same elements means a value in a second array.
-// TODO : this would probably improve compressed representations of small sets (those without much
-// TODO : key repetition). Compressing these better means we can go longer before merging, which
-// TODO : should make most everything else better too.
+///
+/// See `CompactRuns` for a further compressed representation, which run-length encodes values and
+/// weights.
 #[derive(Debug)]
 pub struct Compact<K, V> {
     /// An ordered list of the distinct keys.
@@ -36,7 +37,7 @@ pub struct Compact<K, V> {
     ///
     /// The list is maintained separately in the interest of eventually having run-length coding
     /// treat non-repetitions better.
-    pub cnts: Vec<u32>,
+    pub cnts: Vec<OffsetInt>,
     /// A list of values, ordered within each key group.
     pub vals: Vec<(V, i32)>,
 }
@@ -56,13 +57,6 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
             // wgts: Vec::with_capacity(w),
         }
     }
-    /// Reports the size in bytes, used elsewhere to determine how much space we should use for
-    /// buffering uncompressed elements.
-    pub fn size(&self) -> usize {
-        self.keys.len() * ::std::mem::size_of::<K>() +
-        self.cnts.len() * 4 +
-        self.vals.len() * ::std::mem::size_of::<(V,i32)>()
-    }
 
     /// Populates the `Compact` from an iterator of ordered `(key, val, wgt)` triples.
     ///
@@ -74,7 +68,7 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
         // populate a new `Compact` with merged, coalesced data.
         if let Some(((mut old_key, val), wgt)) = iterator.next() {
 
-            let mut key_cnt = 1;
+            let mut key_cnt = 1usize;
 
             // always stash the val
             self.vals.push((val, wgt));
@@ -87,7 +81,7 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
                 // if the key has changed, stash the key
                 if old_key != key {
                     self.keys.push(old_key);
-                    self.cnts.push(key_cnt);
+                    self.cnts.push(to_offset(key_cnt));
                     old_key = key;
                     key_cnt = 0;
                 }
@@ -96,7 +90,7 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
             }
 
             self.keys.push(old_key);
-            self.cnts.push(key_cnt);
+            self.cnts.push(to_offset(key_cnt));
         }
     }
 
@@ -133,7 +127,7 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
                 // if the key has changed, stash the key
                 if self.keys[self.keys.len() - 1] != key {
                     self.keys.push(key);
-                    self.cnts.push((self.vals.len() - prev_len) as u32);
+                    self.cnts.push(to_offset(self.vals.len() - prev_len));
                     prev_len = self.vals.len();
                 }
 
@@ -141,7 +135,7 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
                 self.vals.push((val,wgt));
             }
 
-            self.cnts.push((self.vals.len() - prev_len) as u32);
+            self.cnts.push(to_offset(self.vals.len() - prev_len));
         }
     }
 
@@ -189,6 +183,40 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
         }
     }
 
+    /// Forms a `Compact` from runs of `((key, val), wgt)` triples, each already sorted by `(key, val)`.
+    ///
+    /// This is the bulk-load path: rather than radix sorting the triples, the runs are merged and
+    /// coalesced in a single pass. Operators use it when each batch they receive is sorted, which
+    /// is the case when the input is read from a pre-sorted source (exchanging records preserves
+    /// the order of records from each sender). The keys of the result are in `Ord` order, rather
+    /// than the hash order produced by `from_radix`.
+    pub fn from_sorted(source: &mut Vec<Vec<((K,V),i32)>>) -> Option<Compact<K,V>> {
+
+        let mut size = 0;
+        for list in source.iter() {
+            size += list.len();
+        }
+
+        let mut result = Compact::new(size, size);
+        if source.len() == 1 {
+            result.extend(source.pop().unwrap().into_iter().coalesce());
+        }
+        else {
+            result.extend(source.drain(..).map(|x| x.into_iter()).merge().coalesce());
+        }
+
+        if result.vals.len() > 0 {
+            result.keys.shrink_to_fit();
+            result.cnts.shrink_to_fit();
+            result.vals.shrink_to_fit();
+
+            Some(result)
+        }
+        else {
+            None
+        }
+    }
+
     pub fn session<'a>(&'a mut self) -> CompactSession<'a, K, V> {
         CompactSession::new(self)
     }
@@ -200,6 +228,86 @@ impl<K: Ord+Debug, V: Ord> Compact<K, V> {
         }
         session.done(key);
     }
+
+    /// Lists the keys with at least one value of negative weight.
+    ///
+    /// Only a key that has received a retraction can have its accumulation return to zero, so
+    /// these are the keys worth checking for retirement once the difference is installed.
+    pub fn retracted_keys(&self) -> Vec<K> where K: Clone {
+        let mut result = Vec::new();
+        let mut vals = self.vals.iter();
+        for (key, &cnt) in self.keys.iter().zip(self.cnts.iter()) {
+            if vals.by_ref().take(cnt as usize).fold(false, |neg, &(_, wgt)| neg || wgt < 0) {
+                result.push(key.clone());
+            }
+        }
+        result
+    }
+}
+
+impl<K, V: Eq> Compact<K, V> {
+    /// Reports the size in bytes, used elsewhere to determine how much space we should use for
+    /// buffering uncompressed elements.
+    pub fn size(&self) -> usize {
+        self.keys.len() * ::std::mem::size_of::<K>() +
+        self.cnts.len() * ::std::mem::size_of::<OffsetInt>() +
+        self.vals.len() * ::std::mem::size_of::<(V,i32)>()
+    }
+
+    /// Reports the size in bytes the `Compact` would have if its values and weights were
+    /// run-length encoded, without encoding them.
+    pub fn compressed_size(&self) -> usize {
+        let val_runs = RunLength::count_runs(self.vals.iter().map(|x| &x.0));
+        let wgt_runs = RunLength::count_runs(self.vals.iter().map(|x| &x.1));
+        self.keys.len() * ::std::mem::size_of::<K>() +
+        self.cnts.len() * ::std::mem::size_of::<OffsetInt>() +
+        val_runs * (::std::mem::size_of::<V>() + ::std::mem::size_of::<OffsetInt>()) +
+        wgt_runs * (4 + ::std::mem::size_of::<OffsetInt>())
+    }
+
+    /// Run-length encodes the values and weights of the `Compact`.
+    pub fn compress(self) -> CompactRuns<K, V> {
+        let mut vals = RunLength::new();
+        let mut wgts = RunLength::new();
+        for (val, wgt) in self.vals.into_iter() {
+            vals.push(val);
+            wgts.push(wgt);
+        }
+        vals.shrink_to_fit();
+        wgts.shrink_to_fit();
+        CompactRuns {
+            keys: self.keys,
+            cnts: self.cnts,
+            vals: vals,
+            wgts: wgts,
+        }
+    }
+}
+
+/// A `Compact` whose values and weights are run-length encoded.
+///
+/// Weights are very often all one, as when the input is a set of records (edges, for example), in
+/// which case they compress to almost nothing. Values compress when adjacent keys share values.
+#[derive(Debug)]
+pub struct CompactRuns<K, V> {
+    /// An ordered list of the distinct keys.
+    pub keys: Vec<K>,
+    /// Counts for each key indicating the number of corresponding values in `self.vals`.
+    pub cnts: Vec<OffsetInt>,
+    /// The run-length encoded values, ordered within each key group.
+    pub vals: RunLength<V>,
+    /// The run-length encoded weights, one for each value.
+    pub wgts: RunLength<i32>,
+}
+
+impl<K, V> CompactRuns<K, V> {
+    /// Reports the size in bytes.
+    pub fn size(&self) -> usize {
+        self.keys.len() * ::std::mem::size_of::<K>() +
+        self.cnts.len() * ::std::mem::size_of::<OffsetInt>() +
+        self.vals.size() +
+        self.wgts.size()
+    }
 }
 
 pub struct CompactSession<'a, K: 'a, V: 'a> {
@@ -222,7 +330,54 @@ impl<'a, K: 'a, V: 'a> CompactSession<'a, K, V> {
     pub fn done(self, key: K) {
         if self.compact.vals.len() > self.len {
             self.compact.keys.push(key);
-            self.compact.cnts.push((self.compact.vals.len() - self.len) as u32);
+            self.compact.cnts.push(to_offset(self.compact.vals.len() - self.len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use collection::Trace;
+    use arrangement::Query;
+    use super::Compact;
+
+    // triples at `time`, in no particular order, with repeated and cancelling updates.
+    fn triples(time: u64) -> Vec<((u64, u64), i32)> {
+        (0 .. 200u64).map(|i| (((i * 37 + time) % 23, (i * 11) % 5), if i % 3 == 0 { -1 } else { 1 })).collect()
+    }
+
+    #[test]
+    fn sorted_matches_radix() {
+
+        let mut radix = Trace::<u64, u64, u64, _>::new(HashMap::new());
+        let mut sorted = Trace::<u64, u64, u64, _>::new(HashMap::new());
+
+        for time in 0 .. 4 {
+
+            let mut hashed = triples(time);
+            hashed.sort_by(|x, y| ((x.0).0 % 7).cmp(&((y.0).0 % 7)));
+            if let Some(compact) = Compact::from_radix(&mut vec![hashed], &|k| k % 7) {
+                radix.set_difference(time, compact);
+            }
+
+            // two sorted runs, as if from two senders.
+            let mut runs = triples(time).chunks(100).map(|x| x.to_vec()).collect::<Vec<_>>();
+            for run in runs.iter_mut() {
+                run.sort_by(|x, y| x.0.cmp(&y.0));
+            }
+            if let Some(compact) = Compact::from_sorted(&mut runs) {
+                sorted.set_difference(time, compact);
+            }
+        }
+
+        for time in 0 .. 4 {
+            assert_eq!(radix.scan(&time), sorted.scan(&time));
+            for key in 0 .. 23 {
+                assert_eq!(radix.collection(&key, &time), sorted.collection(&key, &time));
+            }
         }
     }
 }
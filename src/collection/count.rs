@@ -1,27 +1,29 @@
 //! Like `Count` but with the value type specialized to `()`.
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 
-use collection::{close_under_lub, LeastUpperBound, Lookup};
-use collection::compact::Compact;
+use collection::{close_under_lub, merge_sorted, new_bounds, LeastUpperBound, Lookup, OrderedLookup, OffsetInt, to_offset};
+use collection::compact::{Compact, CompactRuns};
+use stats::Statistics;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Offset {
-    dataz: u32,
+    dataz: OffsetInt,
 }
 
 impl Offset {
     #[inline(always)]
     fn new(offset: usize) -> Offset {
-        assert!(offset < ((!0u32) as usize)); // note strict inequality
-        Offset { dataz: (!0u32) - offset as u32 }
+        assert!((offset as u64) < (OffsetInt::max_value() as u64), "too many links; see feature `wide-offsets`"); // note strict inequality
+        Offset { dataz: OffsetInt::max_value() - offset as OffsetInt }
     }
     #[inline(always)]
-    fn val(&self) -> usize { ((!0u32) - self.dataz) as usize }
+    fn val(&self) -> usize { (OffsetInt::max_value() - self.dataz) as usize }
 }
 
 struct ListEntry {
-    time: u32,
+    time: OffsetInt,
     wgts: i32,
     next: Option<Offset>,
 }
@@ -32,16 +34,25 @@ pub struct Count<K, T, L> {
     times:      Vec<T>,
     pub keys:   L,
     temp:       Vec<T>,
+    closures:   BTreeMap<K, Vec<T>>,    // for each key seen by `interesting_times`, its closed set of times.
+    key_count:  usize,
+    dead_links: usize,  // number of links of retired keys, awaiting compaction.
 }
 
 impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBound+Debug {
 
     /// Installs a supplied set of keys and values as the differences for `time`.
     pub fn set_difference(&mut self, time: T, accumulation: Compact<K, ()>) {
+        self.install(time, accumulation.keys, accumulation.vals.into_iter().map(|(_v,w)| w));
+    }
+
+    /// Installs a supplied set of keys and run-length encoded values as the differences for `time`.
+    pub fn set_difference_runs(&mut self, time: T, accumulation: CompactRuns<K, ()>) {
+        self.install(time, accumulation.keys, accumulation.wgts.iter().cloned());
+    }
 
-        // extract the relevant fields
-        let keys = accumulation.keys;
-        let vals = accumulation.vals;
+    /// Installs keys and their corresponding weights as the differences for `time`.
+    fn install<I: Iterator<Item=i32>>(&mut self, time: T, keys: Vec<K>, wgts: I) {
 
         // index of the self.times entry we are about to insert
         let time_index = self.times.len();
@@ -49,7 +60,7 @@ impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBo
         self.links.reserve(keys.len());
 
         // for each key and count ...
-        for (key, wgt) in keys.into_iter().zip(vals.into_iter().map(|(_v,w)| w)) {
+        for (key, wgt) in keys.into_iter().zip(wgts) {
 
             // prepare a new head cursor, and recover whatever is currently there.
             let next_position = Offset::new(self.links.len());
@@ -57,9 +68,10 @@ impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBo
 
             // if we inserted a previously absent key
             if &prev_position.val() == &next_position.val() {
+                self.key_count += 1;
                 // add the appropriate entry with no next pointer
                 self.links.push(ListEntry {
-                    time: time_index as u32,
+                    time: to_offset(time_index),
                     wgts: wgt,
                     next: None
                 });
@@ -68,7 +80,7 @@ impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBo
             else {
                 // add the appropriate entry
                 self.links.push(ListEntry {
-                    time: time_index as u32,
+                    time: to_offset(time_index),
                     wgts: wgt,
                     next: Some(*prev_position)
                 });
@@ -96,24 +108,110 @@ impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBo
         sum
     }
 
-    // TODO : this could do a better job of returning newly interesting times: those times that are
-    // TODO : now in the least upper bound, but were not previously so. The main risk is that the
-    // TODO : easy way to do this computes the LUB before and after, but this can be expensive:
-    // TODO : the LUB with `index` is often likely to be smaller than the LUB without it.
-    /// Lists times that are the least upper bound of `time` and any subset of existing times.
-    pub fn interesting_times<'a>(&'a mut self, key: &K, index: T) -> &'a [T] {
-        // panic!();
-        let mut temp = ::std::mem::replace(&mut self.temp, Vec::new());
-        temp.clear();
-        temp.push(index);
-        for (time, _) in self.trace(key) {
-            let lub = time.least_upper_bound(&temp[0]);
-            if !temp.contains(&lub) {
-                temp.push(lub);
+    /// Removes `key` if its weights sum to zero and are all at times less or equal to every
+    /// element of `frontier`.
+    ///
+    /// Such a key has a zero count at every time the computation may yet reach, so its removal
+    /// can not be observed. The key is removed from `self.keys`; its links remain, unreachable,
+    /// until they make up more than half of all links, at which point the count is compacted.
+    /// Returns `true` if the key was removed.
+    pub fn retire_key(&mut self, key: &K, frontier: &[T]) -> bool {
+
+        let cancelled = self.trace(key).all(|(time, _)| frontier.iter().all(|f| time <= f))
+                     && self.trace(key).map(|(_, wgt)| wgt).fold(0, |sum, wgt| sum + wgt) == 0;
+
+        if cancelled {
+            if let Some(head) = self.keys.remove_key(key) {
+                self.closures.remove(key);
+                let mut next = Some(head);
+                while let Some(position) = next {
+                    next = self.links[position.val()].next;
+                    self.dead_links += 1;
+                }
+                self.key_count -= 1;
+                if self.dead_links > self.links.len() / 2 {
+                    self.compact();
+                }
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Removes the links of retired keys, and the times left without any keys, and releases the
+    /// memory `self.keys` held for retired keys.
+    ///
+    /// Links keep their order, so each list still leads from later links to earlier ones.
+    fn compact(&mut self) {
+
+        // mark the links reachable from some key, and the times they reference.
+        let mut live = vec![false; self.links.len()];
+        let mut used = vec![false; self.times.len()];
+        for head in self.keys.values_mut() {
+            let mut next = Some(*head);
+            while let Some(position) = next {
+                live[position.val()] = true;
+                used[self.links[position.val()].time as usize] = true;
+                next = self.links[position.val()].next;
             }
         }
-        close_under_lub(&mut temp);
-        ::std::mem::replace(&mut self.temp, temp);
+
+        let mut time_remap = Vec::with_capacity(self.times.len());
+        let mut times = Vec::with_capacity(used.iter().filter(|&&x| x).count());
+        for (time, used) in self.times.drain(..).zip(used.into_iter()) {
+            time_remap.push(times.len());
+            if used { times.push(time); }
+        }
+        self.times = times;
+
+        // positions only decrease, so they fit wherever they did before.
+        let mut remap = vec![0; self.links.len()];
+        let mut links = Vec::with_capacity(self.links.len() - self.dead_links);
+        for (position, link) in self.links.drain(..).enumerate() {
+            if live[position] {
+                remap[position] = links.len();
+                links.push(ListEntry {
+                    time: to_offset(time_remap[link.time as usize]),
+                    wgts: link.wgts,
+                    next: link.next.map(|next| Offset::new(remap[next.val()])),
+                });
+            }
+        }
+        self.links = links;
+
+        for head in self.keys.values_mut() {
+            *head = Offset::new(remap[head.val()]);
+        }
+        self.keys.shrink_to_fit();
+
+        self.dead_links = 0;
+    }
+
+    /// Lists the times at which the accumulated differences for `key` may change as a result of
+    /// differences at `index`, and which have not been listed for `key` before.
+    ///
+    /// The accumulation at a time `t` depends only on which of the key's times are less or equal
+    /// to `t`, and is the same as at the least upper bound of those times. Differences at `index`
+    /// may therefore change the accumulation only at bounds of `index` with subsets of the key's
+    /// times. Bounds not involving `index` were listed when their last time arrived, and those at
+    /// least `index` are yet to be processed, so only the bounds new to the key's closed set of
+    /// times are listed, with `index` itself. The set is kept for each key, built from the trace
+    /// the first time the key is seen, and extended here, so that differences for `key` should be
+    /// installed at `index` after this call. The times are sorted, and `index`, which is less or
+    /// equal to all of them, comes first.
+    pub fn interesting_times<'a>(&'a mut self, key: &K, index: T) -> &'a [T] where K: Clone, T: Clone {
+        if !self.closures.contains_key(key) {
+            let mut closed = self.trace(key).map(|(time, _)| time.clone()).collect::<Vec<_>>();
+            close_under_lub(&mut closed);
+            self.closures.insert(key.clone(), closed);
+        }
+        let closed = self.closures.get_mut(key).unwrap();
+        let added = new_bounds(closed, index.clone());
+        self.temp.clear();
+        if added.is_empty() { self.temp.push(index); }
+        self.temp.extend(added.iter().cloned());
+        merge_sorted(closed, added);
         &self.temp[..]
     }
 
@@ -126,7 +224,22 @@ impl<K, L, T> Count<K, T, L> where K: Ord, L: Lookup<K, Offset>, T: LeastUpperBo
     }
 }
 
-impl<K: Eq, L: Lookup<K, Offset>, T> Count<K, T, L> {
+impl<K, L, T> Count<K, T, L> where K: Ord, L: OrderedLookup<K, Offset>, T: LeastUpperBound+Debug {
+    /// Lists the keys from `lower` to `upper` inclusive whose counts at `time` are non-zero, in key
+    /// order, with their counts.
+    pub fn range<'a>(&'a self, lower: &K, upper: &K, time: &T) -> Vec<(&'a K, i32)> {
+        let mut result = Vec::new();
+        self.keys.range(lower, upper, |key, _| {
+            let count = self.get_count(key, time);
+            if count != 0 {
+                result.push((key, count));
+            }
+        });
+        result
+    }
+}
+
+impl<K: Ord, L: Lookup<K, Offset>, T> Count<K, T, L> {
     pub fn new(l: L) -> Count<K, T, L> {
         Count {
             phantom: ::std::marker::PhantomData,
@@ -134,6 +247,25 @@ impl<K: Eq, L: Lookup<K, Offset>, T> Count<K, T, L> {
             times:   Vec::new(),
             keys:    l,
             temp:    Vec::new(),
+            closures: BTreeMap::new(),
+            key_count: 0,
+            dead_links: 0,
+        }
+    }
+    /// Enumerates the keys present in the trace, in the order of its `Lookup`.
+    pub fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, L: 'a { self.keys.keys() }
+    /// Reports the number of `(key, time)` links in the trace.
+    pub fn link_count(&self) -> usize { self.links.len() }
+    /// Reports the number of differences in the trace, one per call to `set_difference` until
+    /// compaction removes those whose keys have all been retired.
+    pub fn time_count(&self) -> usize { self.times.len() }
+    /// Reports the sizes of the trace's structures; counts have no values, only weights in links.
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            keys: self.key_count,
+            links: self.links.len(),
+            times: self.times.len(),
+            .. Default::default()
         }
     }
 }
@@ -161,3 +293,42 @@ where K:  Ord+'a,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use ::Data;
+    use collection::robin_hood::RHHMap;
+    use collection::compact::Compact;
+    use super::Count;
+
+    fn compact<I: Iterator<Item=u64>>(keys: I, wgt: i32) -> Compact<u64, ()> {
+        Compact::from_sorted(&mut vec![keys.map(|key| ((key, ()), wgt)).collect()]).unwrap()
+    }
+
+    #[test]
+    fn retire_and_reinsert() {
+
+        let mut count = Count::new(RHHMap::new(|x: &u64| x.hashed() as usize));
+        count.set_difference(0u64, compact(0 .. 100, 1));
+        count.set_difference(1u64, compact(0 .. 80, -1));
+        assert_eq!(count.link_count(), 180);
+
+        for key in 0 .. 100 {
+            assert_eq!(count.retire_key(&key, &[1]), key < 80);
+        }
+
+        // each compaction leaves at most as many dead links as live ones.
+        assert!(count.link_count() <= 2 * 20);
+        assert_eq!(count.keys().count(), 20);
+        for key in 0 .. 100 {
+            assert_eq!(count.get_count(&key, &1), (key >= 80) as i32);
+        }
+
+        count.set_difference(2u64, compact(70 .. 90, 1));
+        assert_eq!(count.keys().count(), 30);
+        for key in 0 .. 100 {
+            assert_eq!(count.get_count(&key, &2), (key >= 70 && key < 90) as i32 + (key >= 80) as i32);
+        }
+    }
+}
@@ -1,5 +1,12 @@
 
-pub trait LeastUpperBound : PartialOrd {
+/// A partial order in which each pair of elements has a least upper bound, and a greatest element.
+///
+/// The `Ord` implementation must be a linear extension of `PartialOrd`: if `a <= b` in the partial
+/// order then `a.cmp(&b)` is not `Greater`. It is used only to keep sets of times sorted, so that
+/// membership can be tested by binary search; lexicographic orders on products do this. As the two
+/// orders differ on incomparable elements, sorts must compare with `cmp` explicitly: `sort` itself
+/// may use `PartialOrd`'s `lt`.
+pub trait LeastUpperBound : PartialOrd + Ord {
     fn max() -> Self;
     fn least_upper_bound(&self, &Self) -> Self;
 }
@@ -11,11 +18,27 @@ pub trait LeastUpperBound : PartialOrd {
 //     }
 // }
 
+/// A partial order in which each pair of elements has a greatest lower bound, and a least element.
+///
+/// With `LeastUpperBound` this makes a bounded lattice. Implementations should satisfy the lattice
+/// laws: both bounds are commutative, associative, and idempotent, each absorbs the other (so that
+/// `a.greatest_lower_bound(&a.least_upper_bound(&b)) == a`), and both agree with `PartialOrd`, in
+/// that `a <= b` exactly when `a.least_upper_bound(&b) == b`. Compaction uses greatest lower bounds
+/// to find the times that all of a frontier's elements could still observe.
+pub trait Lattice : LeastUpperBound {
+    /// The element less or equal to all others.
+    fn minimum() -> Self;
+    /// The greatest element less or equal to both `self` and `other`.
+    fn greatest_lower_bound(&self, other: &Self) -> Self;
+}
+
+use std::cmp::Ordering;
+
 use timely::progress::nested::product::Product;
 
 impl<T1: LeastUpperBound, T2: LeastUpperBound> LeastUpperBound for Product<T1, T2> {
     #[inline(always)]
-    fn max() -> Self { Product::new(T1::max(), T2::max()) }
+    fn max() -> Self { Product::new(<T1 as LeastUpperBound>::max(), <T2 as LeastUpperBound>::max()) }
     fn least_upper_bound(&self, other: &Product<T1, T2>) -> Product<T1, T2> {
         Product {
             outer: self.outer.least_upper_bound(&other.outer),
@@ -24,6 +47,17 @@ impl<T1: LeastUpperBound, T2: LeastUpperBound> LeastUpperBound for Product<T1, T
     }
 }
 
+impl<T1: Lattice, T2: Lattice> Lattice for Product<T1, T2> {
+    #[inline(always)]
+    fn minimum() -> Self { Product::new(T1::minimum(), T2::minimum()) }
+    fn greatest_lower_bound(&self, other: &Product<T1, T2>) -> Product<T1, T2> {
+        Product {
+            outer: self.outer.greatest_lower_bound(&other.outer),
+            inner: self.inner.greatest_lower_bound(&other.inner),
+        }
+    }
+}
+
 use timely::progress::timestamp::RootTimestamp;
 
 impl LeastUpperBound for RootTimestamp {
@@ -31,36 +65,283 @@ impl LeastUpperBound for RootTimestamp {
     fn least_upper_bound(&self, _: &RootTimestamp) -> RootTimestamp { RootTimestamp }
 }
 
-impl LeastUpperBound for u64 {
-    fn max() -> u64 { u64::max_value() }
-    fn least_upper_bound(&self, other: &u64) -> u64 { if self < other { *other } else { *self }}
+impl Lattice for RootTimestamp {
+    fn minimum() -> RootTimestamp { RootTimestamp }
+    fn greatest_lower_bound(&self, _: &RootTimestamp) -> RootTimestamp { RootTimestamp }
 }
 
-impl LeastUpperBound for u32 {
-    fn max() -> u32 { u32::max_value() }
-    fn least_upper_bound(&self, other: &u32) -> u32 { if self < other { *other } else { *self }}
+// Integers are totally ordered, so their bounds are the larger and smaller of the two.
+macro_rules! implement_integer {
+    ($($index_type:ty,)*) => (
+        $(
+            impl LeastUpperBound for $index_type {
+                #[inline(always)]
+                fn max() -> $index_type { <$index_type>::max_value() }
+                #[inline(always)]
+                fn least_upper_bound(&self, other: &$index_type) -> $index_type { if self < other { *other } else { *self }}
+            }
+
+            impl Lattice for $index_type {
+                #[inline(always)]
+                fn minimum() -> $index_type { <$index_type>::min_value() }
+                #[inline(always)]
+                fn greatest_lower_bound(&self, other: &$index_type) -> $index_type { if self < other { *self } else { *other }}
+            }
+        )*
+    )
 }
 
-impl LeastUpperBound for i32 {
-    fn max() -> i32 { i32::max_value() }
-    fn least_upper_bound(&self, other: &i32) -> i32 { if self < other { *other } else { *self }}
+implement_integer!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize,);
+
+/// A set of mutually incomparable elements, describing the elements greater or equal to any of them.
+///
+/// Antichains describe frontiers: the times a computation may yet see are those dominated by the
+/// antichain, and an empty antichain dominates nothing. Elements are kept minimal, so inserting an
+/// element removes those it is less than, and inserting an element already dominated has no effect.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Antichain<T> {
+    elements: Vec<T>,
 }
 
+impl<T: Lattice> Antichain<T> {
+    /// Creates a new empty antichain.
+    pub fn new() -> Antichain<T> { Antichain { elements: Vec::new() } }
+
+    /// Creates a new antichain containing only `element`.
+    pub fn from_elem(element: T) -> Antichain<T> { Antichain { elements: vec![element] } }
+
+    /// Inserts `element` unless it is dominated, removing any elements it dominates.
+    ///
+    /// Returns `true` if the element was inserted.
+    pub fn insert(&mut self, element: T) -> bool {
+        if self.dominates(&element) { false }
+        else {
+            self.elements.retain(|x| !(&element <= x));
+            self.elements.push(element);
+            true
+        }
+    }
+
+    /// Reports whether some element of the antichain is less or equal to `time`.
+    pub fn dominates(&self, time: &T) -> bool {
+        self.elements.iter().any(|x| x <= time)
+    }
+
+    /// The antichain dominating times dominated by either `self` or `other`.
+    pub fn meet(&self, other: &Antichain<T>) -> Antichain<T> where T: Clone {
+        let mut result = self.clone();
+        for element in other.elements.iter() {
+            result.insert(element.clone());
+        }
+        result
+    }
+
+    /// The antichain dominating times dominated by both `self` and `other`.
+    pub fn join(&self, other: &Antichain<T>) -> Antichain<T> {
+        let mut result = Antichain::new();
+        for element1 in self.elements.iter() {
+            for element2 in other.elements.iter() {
+                result.insert(element1.least_upper_bound(element2));
+            }
+        }
+        result
+    }
+
+    /// The greatest lower bound of the elements, or `T::max()` if the antichain is empty.
+    ///
+    /// This time is less or equal to every time the antichain dominates.
+    pub fn lower_bound(&self) -> T where T: Clone {
+        let mut iter = self.elements.iter();
+        match iter.next() {
+            Some(first) => iter.fold(first.clone(), |bound, x| bound.greatest_lower_bound(x)),
+            None => <T as LeastUpperBound>::max(),
+        }
+    }
+
+    /// The elements of the antichain, in no particular order.
+    pub fn elements(&self) -> &[T] { &self.elements[..] }
+
+    /// Reports whether the antichain has no elements.
+    pub fn is_empty(&self) -> bool { self.elements.is_empty() }
+}
 
+/// Extends `vector` with least upper bounds of its elements until it is closed under them.
+///
+/// The result is sorted, as required by `insert_and_close`.
 pub fn close_under_lub<T: LeastUpperBound>(vector: &mut Vec<T>) {
-    // compares each element to those elements after it.
-    let mut first = 0;
-    while first < vector.len() {
-        let mut next = first + 1;
-        while next < vector.len() {
-            let lub = vector[first].least_upper_bound(&vector[next]);
-            if !vector.contains(&lub) {
-                vector.push(lub);
+    let elements = ::std::mem::replace(vector, Vec::new());
+    for element in elements {
+        insert_and_close(vector, element);
+    }
+}
+
+/// Inserts `element` into `closed`, a sorted set closed under least upper bounds, and restores
+/// closure and order.
+#[inline]
+pub fn insert_and_close<T: LeastUpperBound>(closed: &mut Vec<T>, element: T) {
+    let added = new_bounds(closed, element);
+    merge_sorted(closed, added);
+}
+
+/// The members that inserting `element` adds to `closed`, a sorted set closed under least upper
+/// bounds, sorted and without repetition; these are `element` and its bounds with members.
+///
+/// If `closed` is closed under least upper bounds, the only new bounds are those of `element` with
+/// each existing member: the bound of `element` with an existing bound `a | b` is the bound of
+/// `a` with `element | b`. We need only consider members incomparable to `element`, as the others
+/// bound it or are bounded by it, and none at all if `element` is already a member. Membership is
+/// tested by binary search, so this costs a number of comparisons linear in the size of the set
+/// plus a logarithmic factor for each new bound.
+pub fn new_bounds<T: LeastUpperBound>(closed: &[T], element: T) -> Vec<T> {
+    let mut added = Vec::new();
+    if closed.binary_search_by(|x| x.cmp(&element)).is_err() {
+        for member in closed.iter() {
+            if !(member <= &element) && !(&element <= member) {
+                added.push(member.least_upper_bound(&element));
+            }
+        }
+        added.push(element);
+        added.sort_by(|x, y| x.cmp(y));
+        added.dedup();
+        added.retain(|x| closed.binary_search_by(|y| y.cmp(x)).is_err());
+    }
+    added
+}
+
+/// Merges `added`, sorted and disjoint from `closed`, into the sorted `closed`.
+pub fn merge_sorted<T: LeastUpperBound>(closed: &mut Vec<T>, added: Vec<T>) {
+    if !added.is_empty() {
+        let mut merged = Vec::with_capacity(closed.len() + added.len());
+        let mut added = added.into_iter().peekable();
+        for member in closed.drain(..) {
+            while added.peek().map(|x| x.cmp(&member) == Ordering::Less).unwrap_or(false) {
+                merged.push(added.next().unwrap());
+            }
+            merged.push(member);
+        }
+        merged.extend(added);
+        *closed = merged;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fmt::Debug;
+
+    use timely::progress::nested::product::Product;
+    use timely::progress::timestamp::RootTimestamp;
+
+    use super::{Antichain, Lattice, LeastUpperBound, close_under_lub, merge_sorted, new_bounds};
+
+    // checks the lattice laws for all pairs and triples drawn from `elements`.
+    fn check_laws<T: Lattice+Clone+Debug>(elements: &[T]) {
+        for a in elements {
+            assert!(T::minimum() <= *a);
+            assert!(*a <= <T as LeastUpperBound>::max());
+            assert_eq!(a.least_upper_bound(a), *a);
+            assert_eq!(a.greatest_lower_bound(a), *a);
+            for b in elements {
+                let lub = a.least_upper_bound(b);
+                let glb = a.greatest_lower_bound(b);
+                assert_eq!(lub, b.least_upper_bound(a));
+                assert_eq!(glb, b.greatest_lower_bound(a));
+                assert_eq!(a.greatest_lower_bound(&lub), *a);
+                assert_eq!(a.least_upper_bound(&glb), *a);
+                assert_eq!(a <= b, lub == *b);
+                assert_eq!(a <= b, glb == *a);
+                for c in elements {
+                    assert_eq!(lub.least_upper_bound(c), a.least_upper_bound(&b.least_upper_bound(c)));
+                    assert_eq!(glb.greatest_lower_bound(c), a.greatest_lower_bound(&b.greatest_lower_bound(c)));
+                }
             }
+        }
+    }
+
+    #[test]
+    fn integer_laws() {
+        check_laws(&[0u8, 1, 7, 255]);
+        check_laws(&[0u64, 3, 10, u64::max_value()]);
+        check_laws(&[i32::min_value(), -4, 0, 9, i32::max_value()]);
+        check_laws(&[isize::min_value(), -1, 0, 1]);
+    }
+
+    #[test]
+    fn product_laws() {
+        let mut elements = Vec::new();
+        for outer in &[0u64, 2, 5] {
+            for inner in &[0u32, 1, 4] {
+                elements.push(Product::new(Product::new(RootTimestamp, *outer), *inner));
+            }
+        }
+        check_laws(&elements);
+    }
+
+    #[test]
+    fn antichain_operations() {
+        let p = |a, b| Product::new(a, b);
+
+        let mut first = Antichain::new();
+        assert!(first.insert(p(2u64, 3u64)));
+        assert!(first.insert(p(3, 1)));
+        assert!(!first.insert(p(4, 4)));
+        assert!(first.insert(p(1, 3)));
+        assert_eq!(first.elements(), &[p(3, 1), p(1, 3)]);
+        assert!(first.dominates(&p(1, 5)));
+        assert!(!first.dominates(&p(0, 9)));
+
+        let second = Antichain::from_elem(p(2, 0));
+        assert_eq!(first.meet(&second).elements(), &[p(1, 3), p(2, 0)]);
+        assert_eq!(first.join(&second).elements(), &[p(3, 1), p(2, 3)]);
+        assert_eq!(first.lower_bound(), p(1, 1));
+        assert_eq!(Antichain::<Product<u64, u64>>::new().lower_bound(), p(u64::max_value(), u64::max_value()));
+    }
+
+    // the closure as originally computed, comparing each element to those after it.
+    fn pairwise_closure<T: LeastUpperBound+Clone>(times: &[T]) -> Vec<T> {
+        let mut vector = Vec::new();
+        for time in times {
+            if !vector.contains(time) { vector.push(time.clone()); }
+        }
+        let mut first = 0;
+        while first < vector.len() {
+            let mut next = first + 1;
+            while next < vector.len() {
+                let lub = vector[first].least_upper_bound(&vector[next]);
+                if !vector.contains(&lub) { vector.push(lub); }
+                next += 1;
+            }
+            first += 1;
+        }
+        vector.sort_by(|x, y| x.cmp(y));
+        vector
+    }
+
+    #[test]
+    fn closure_matches_pairwise() {
+        let p = |a, b| Product::new(a, b);
+
+        // a pseudo-random sequence of partially ordered times, with repeats and comparable pairs.
+        let mut state = 17u64;
+        let mut times = Vec::new();
+        for _ in 0 .. 40 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            times.push(p((state >> 33) % 6, (state >> 45) % 6));
+        }
 
-            next += 1;
+        // each insertion adds exactly the members the closure gains, in order.
+        let mut closed = Vec::new();
+        for length in 0 .. times.len() {
+            let added = new_bounds(&closed, times[length].clone());
+            let before = pairwise_closure(&times[.. length]);
+            let after = pairwise_closure(&times[.. length + 1]);
+            assert_eq!(added, after.iter().filter(|x| !before.contains(x)).cloned().collect::<Vec<_>>());
+            merge_sorted(&mut closed, added);
+            assert_eq!(closed, after);
         }
 
-        first += 1;
+        let mut vector = times.clone();
+        close_under_lub(&mut vector);
+        assert_eq!(vector, closed);
     }
 }
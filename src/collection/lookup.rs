@@ -1,5 +1,6 @@
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::collections::{HashMap, BTreeMap};
 use timely_sort::Unsigned;
 
 use collection::robin_hood::RHHMap;
@@ -9,6 +10,22 @@ pub trait Lookup<K: Eq, V> {
     fn get_mut<'a>(&'a mut self, &K)->Option<&'a mut V>;
     fn entry_or_insert<F: FnMut()->V>(&mut self, K, F) -> &mut V;
     fn remove_key(&mut self, &K) -> Option<V>;
+    /// The number of keys present.
+    fn len(&self) -> usize;
+    /// Enumerates the keys present, in no particular order unless the implementation has one.
+    ///
+    /// Keys are produced by value, as an implementation need not store them.
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, V: 'a;
+    /// Enumerates the values present, mutably, in no particular order.
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where K: 'a, V: 'a;
+    /// Releases memory held for keys that have been removed, where the implementation can.
+    fn shrink_to_fit(&mut self);
+}
+
+/// A `Lookup` whose keys are ordered, and which can enumerate those in a range.
+pub trait OrderedLookup<K: Ord, V> : Lookup<K, V> {
+    /// Applies `logic` to each key from `lower` to `upper` inclusive, and its value, in key order.
+    fn range<'a, F: FnMut(&'a K, &'a V)>(&'a self, lower: &K, upper: &K, logic: F) where K: 'a, V: 'a;
 }
 
 impl<K: Eq+Clone, V, F: Fn(&K)->usize> Lookup<K, V> for RHHMap<K, V, F> {
@@ -29,6 +46,15 @@ impl<K: Eq+Clone, V, F: Fn(&K)->usize> Lookup<K, V> for RHHMap<K, V, F> {
     fn remove_key(&mut self, key: &K) -> Option<V> {
         self.remove(key).map(|x| x.1)
     }
+    #[inline]
+    fn len(&self) -> usize { RHHMap::len(self) }
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, V: 'a {
+        Box::new(self.iter().map(|(k, _)| k.clone()))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where K: 'a, V: 'a {
+        Box::new(self.iter_mut().map(|(_, v)| v))
+    }
+    fn shrink_to_fit(&mut self) { RHHMap::shrink_to_fit(self) }
 }
 
 impl<K: Hash+Eq+'static, V: 'static> Lookup<K,V> for HashMap<K,V> {
@@ -42,6 +68,47 @@ impl<K: Hash+Eq+'static, V: 'static> Lookup<K,V> for HashMap<K,V> {
     }
     #[inline]
     fn remove_key(&mut self, key: &K) -> Option<V> { self.remove(key) }
+    #[inline]
+    fn len(&self) -> usize { self.len() }
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, V: 'a {
+        Box::new(self.keys().cloned())
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where K: 'a, V: 'a {
+        Box::new(self.values_mut())
+    }
+    fn shrink_to_fit(&mut self) { HashMap::shrink_to_fit(self) }
+}
+
+impl<K: Ord, V> Lookup<K, V> for BTreeMap<K, V> {
+    #[inline]
+    fn get_ref<'a>(&'a self, key: &K) -> Option<&'a V> { self.get(key) }
+    #[inline]
+    fn get_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> { self.get_mut(key) }
+    #[inline]
+    fn entry_or_insert<F: FnMut()->V>(&mut self, key: K, func: F) -> &mut V {
+        self.entry(key).or_insert_with(func)
+    }
+    #[inline]
+    fn remove_key(&mut self, key: &K) -> Option<V> { self.remove(key) }
+    #[inline]
+    fn len(&self) -> usize { self.len() }
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, V: 'a {
+        Box::new(self.keys().cloned())
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where K: 'a, V: 'a {
+        Box::new(self.values_mut())
+    }
+    /// B-trees release the nodes of removed keys as they go.
+    fn shrink_to_fit(&mut self) { }
+}
+
+impl<K: Ord+Clone, V> OrderedLookup<K, V> for BTreeMap<K, V> {
+    fn range<'a, F: FnMut(&'a K, &'a V)>(&'a self, lower: &K, upper: &K, mut logic: F) where K: 'a, V: 'a {
+        for (key, val) in BTreeMap::range(self, lower.clone() ..) {
+            if key > upper { break; }
+            logic(key, val);
+        }
+    }
 }
 
 impl<K: Eq, V> Lookup<K, V> for Vec<(K, V)> {
@@ -77,29 +144,120 @@ impl<K: Eq, V> Lookup<K, V> for Vec<(K, V)> {
         }
         else { None }
     }
+    #[inline]
+    fn len(&self) -> usize { self.len() }
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, V: 'a {
+        Box::new(self.iter().map(|x| x.0.clone()))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where K: 'a, V: 'a {
+        Box::new(self.iter_mut().map(|x| &mut x.1))
+    }
+    fn shrink_to_fit(&mut self) { Vec::shrink_to_fit(self) }
 }
 
-impl<V: 'static, U: Unsigned> Lookup<U,V> for (Vec<Option<V>>, u64) {
+/// An `Unsigned` type whose values can be rebuilt from their `as_u64`.
+pub trait FromU64 : Unsigned {
+    /// The value `x` for which `x.as_u64() == value`; `value` must be in range for the type.
+    fn from_u64(value: u64) -> Self;
+}
+
+impl FromU64 for u8 { #[inline] fn from_u64(value: u64) -> u8 { value as u8 } }
+impl FromU64 for u16 { #[inline] fn from_u64(value: u64) -> u16 { value as u16 } }
+impl FromU64 for u32 { #[inline] fn from_u64(value: u64) -> u32 { value as u32 } }
+impl FromU64 for u64 { #[inline] fn from_u64(value: u64) -> u64 { value } }
+impl FromU64 for usize { #[inline] fn from_u64(value: u64) -> usize { value as usize } }
+
+/// A dense map from unsigned keys to values, with a slot for each key shifted right by `shift`.
+///
+/// Keys are not stored: each is rebuilt from its slot index and the bits discarded by the shift,
+/// which are recorded as keys are inserted. The shift must therefore only discard bits that all
+/// keys have in common, as when keys are partitioned among workers by their low bits.
+pub struct DenseMap<U, V> {
+    slots: Vec<Option<V>>,
+    shift: u64,
+    low: u64,       // the bits discarded by the shift, common to all keys.
+    count: usize,   // the number of occupied slots.
+    phantom: PhantomData<U>,
+}
+
+impl<U, V> DenseMap<U, V> {
+    /// Constructs a new empty `DenseMap` indexing keys shifted right by `shift`.
+    pub fn new(shift: u64) -> DenseMap<U, V> {
+        DenseMap { slots: Vec::new(), shift: shift, low: 0, count: 0, phantom: PhantomData }
+    }
+}
+
+impl<V: 'static, U: FromU64> Lookup<U,V> for DenseMap<U, V> {
     #[inline]
     fn get_ref<'a>(&'a self, key: &U) -> Option<&'a V> {
-        let key = (key.as_u64() >> self.1) as usize;
-        if self.0.len() > key { self.0[key].as_ref() } else { None }
+        let key = (key.as_u64() >> self.shift) as usize;
+        if self.slots.len() > key { self.slots[key].as_ref() } else { None }
     }
     #[inline]
     fn get_mut<'a>(&'a mut self, key: &U) -> Option<&'a mut V> {
-        let key = (key.as_u64() >> self.1) as usize;
-        if self.0.len() > key { self.0[key].as_mut() } else { None }
+        let key = (key.as_u64() >> self.shift) as usize;
+        if self.slots.len() > key { self.slots[key].as_mut() } else { None }
     }
     #[inline]
     fn entry_or_insert<F: FnMut()->V>(&mut self, key: U, mut func: F) -> &mut V {
-        let key = (key.as_u64() >> self.1) as usize;
-        while self.0.len() <= key { self.0.push(None); }
-        if self.0[key].is_none() { self.0[key] = Some(func()); }
-        self.0[key].as_mut().unwrap()
+        let index = (key.as_u64() >> self.shift) as usize;
+        while self.slots.len() <= index { self.slots.push(None); }
+        if self.slots[index].is_none() {
+            let low = key.as_u64() ^ ((index as u64) << self.shift);
+            debug_assert!(self.count == 0 || self.low == low, "DenseMap shift discards bits keys do not share");
+            self.low = low;
+            self.count += 1;
+            self.slots[index] = Some(func());
+        }
+        self.slots[index].as_mut().unwrap()
     }
     #[inline]
     fn remove_key(&mut self, key: &U) -> Option<V> {
-        let key = (key.as_u64() >> self.1) as usize;
-        if self.0.len() > key { self.0[key].take() } else { None }
+        let key = (key.as_u64() >> self.shift) as usize;
+        let result = if self.slots.len() > key { self.slots[key].take() } else { None };
+        if result.is_some() { self.count -= 1; }
+        result
+    }
+    #[inline]
+    fn len(&self) -> usize { self.count }
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=U>+'a> where U: Clone+'a, V: 'a {
+        let (shift, low) = (self.shift, self.low);
+        Box::new(self.slots.iter()
+                           .enumerate()
+                           .filter(|&(_, slot)| slot.is_some())
+                           .map(move |(index, _)| U::from_u64(((index as u64) << shift) | low)))
+    }
+    fn values_mut<'a>(&'a mut self) -> Box<Iterator<Item=&'a mut V>+'a> where U: 'a, V: 'a {
+        Box::new(self.slots.iter_mut().filter_map(|x| x.as_mut()))
+    }
+    /// Releases the empty slots beyond the largest key present.
+    fn shrink_to_fit(&mut self) {
+        while self.slots.last().map(|x| x.is_none()).unwrap_or(false) { self.slots.pop(); }
+        self.slots.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{DenseMap, Lookup};
+
+    #[test]
+    fn dense_keys() {
+
+        // keys as if partitioned to worker three of four, by their low two bits.
+        let mut map = DenseMap::<u32, u64>::new(2);
+        for key in (0 .. 100u32).filter(|x| x % 4 == 3) {
+            *map.entry_or_insert(key, || 0) += key as u64;
+        }
+        map.remove_key(&7);
+
+        assert_eq!(map.len(), 24);
+        let mut keys = map.keys().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, (0 .. 100u32).filter(|x| x % 4 == 3 && *x != 7).collect::<Vec<_>>());
+        for key in keys {
+            assert_eq!(map.get_ref(&key), Some(&(key as u64)));
+        }
     }
 }
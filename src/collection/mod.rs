@@ -11,9 +11,29 @@ pub mod compact;
 pub mod tier;
 pub mod count;
 pub mod robin_hood;
+pub mod accumulator;
+pub mod persist;
 
-pub use collection::lookup::Lookup;
-pub use collection::least_upper_bound::LeastUpperBound;
-pub use collection::least_upper_bound::close_under_lub;
+pub use collection::lookup::{Lookup, OrderedLookup, DenseMap, FromU64};
+pub use collection::least_upper_bound::{LeastUpperBound, Lattice, Antichain};
+pub use collection::least_upper_bound::{close_under_lub, insert_and_close, new_bounds, merge_sorted};
 pub use collection::trace::Trace;
 pub use collection::trace::Offset;
+
+/// The unsigned integer type used for offsets into, and indices within, traces.
+///
+/// By default traces use `u32`, which keeps links small but limits each worker's trace to about
+/// four billion links, and each of its times to about four billion values. The `wide-offsets`
+/// feature uses `u64` instead, for workers whose traces are larger than this.
+#[cfg(not(feature = "wide-offsets"))]
+pub type OffsetInt = u32;
+/// The unsigned integer type used for offsets into, and indices within, traces.
+#[cfg(feature = "wide-offsets")]
+pub type OffsetInt = u64;
+
+/// Converts `value` to an `OffsetInt`, panicking if it does not fit rather than truncating it.
+#[inline(always)]
+pub fn to_offset(value: usize) -> OffsetInt {
+    assert!((value as u64) <= (OffsetInt::max_value() as u64), "offset {} too large; see feature `wide-offsets`", value);
+    value as OffsetInt
+}
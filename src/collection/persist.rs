@@ -0,0 +1,344 @@
+//! Persistence of trace differences to a local directory, and their recovery after a restart.
+//!
+//! A `Trace` or `Count` is built from immutable differences, each a `Compact` installed at a time
+//! by `set_difference`. A `BatchLog` writes each difference to its own file before it is
+//! installed, and records a frontier once the times before it are complete. After a restart, the
+//! differences at times preceding the recorded frontier are read back and installed in a new
+//! trace, and the computation resumes from the frontier; differences at other times are discarded,
+//! as they will be produced again.
+//!
+//! Each difference is written to a temporary file which is synced and then renamed into place, and
+//! the frontier is replaced in the same way, so a process killed at any point leaves the directory
+//! with complete differences and a complete frontier. Temporary files left behind are removed when
+//! the log is next opened.
+//!
+//! The log does not hold the data received but not yet installed, nor any other state of the
+//! operators; whoever restarts the computation must re-supply its input from the frontier on.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let mut log = try!(BatchLog::open("state/reach"));
+//! let mut trace = Trace::new(HashMap::new());
+//! try!(log.recover(|time, compact| trace.set_difference(time, compact)));
+//! ...
+//! try!(log.append(&time, &compact));
+//! trace.set_difference(time, compact);
+//! ...
+//! try!(log.advance(notificator.frontier(0)));
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use timely_communication::Serialize;
+
+use collection::OffsetInt;
+use collection::compact::Compact;
+
+/// A directory of differences, and the frontier before which they are complete.
+pub struct BatchLog<T> {
+    directory: PathBuf,
+    batches: Vec<usize>,    // identifiers of the batch files present, in the order written.
+    next: usize,            // identifier for the next batch file.
+    frontier: Option<Vec<T>>,   // `None` until a frontier is first recorded.
+}
+
+impl<T: ::timely::Data+PartialOrd> BatchLog<T> {
+
+    /// Opens the log in `directory`, creating it if it does not exist.
+    ///
+    /// A new log has no recorded frontier, meaning that no times are known to be complete.
+    pub fn open<P: AsRef<Path>>(directory: P) -> io::Result<BatchLog<T>> {
+
+        let directory = directory.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&directory));
+
+        let mut batches = Vec::new();
+        for entry in try!(fs::read_dir(&directory)) {
+            let path = try!(entry).path();
+            let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("").to_owned();
+            if name.ends_with(".tmp") {
+                try!(fs::remove_file(&path));
+            }
+            else if name.starts_with("batch-") {
+                if let Ok(id) = name["batch-".len()..].parse::<usize>() {
+                    batches.push(id);
+                }
+            }
+        }
+        batches.sort();
+
+        let frontier_path = directory.join("frontier");
+        let frontier = if frontier_path.exists() {
+            let mut reader = BufReader::new(try!(File::open(&frontier_path)));
+            Some(try!(read_record(&mut reader)))
+        }
+        else {
+            None
+        };
+
+        Ok(BatchLog {
+            next: batches.last().map(|&x| x + 1).unwrap_or(0),
+            directory: directory,
+            batches: batches,
+            frontier: frontier,
+        })
+    }
+
+    /// The most recently recorded frontier, if any.
+    ///
+    /// An empty frontier indicates that all times are complete.
+    pub fn frontier(&self) -> Option<&[T]> { self.frontier.as_ref().map(|x| &x[..]) }
+
+    /// The number of differences in the log.
+    pub fn len(&self) -> usize { self.batches.len() }
+
+    /// Supplies each difference at a time preceding the recorded frontier to `install`, in the
+    /// order they were appended, and removes any other differences from the log. If no frontier
+    /// has been recorded, all differences are removed.
+    ///
+    /// Returns the number of differences supplied.
+    pub fn recover<K, V, F>(&mut self, mut install: F) -> io::Result<usize>
+    where K: ::timely::Data, V: ::timely::Data, F: FnMut(T, Compact<K, V>) {
+
+        let mut recovered = Vec::new();
+        for &id in &self.batches {
+            let path = self.batch_path(id);
+            let mut reader = BufReader::new(try!(File::open(&path)));
+            let time: T = try!(read_record(&mut reader));
+            let complete = self.frontier.as_ref().map(|x| !x.iter().any(|f| f <= &time)).unwrap_or(false);
+            if !complete {
+                try!(fs::remove_file(&path));
+            }
+            else {
+                install(time, try!(read_compact(&mut reader)));
+                recovered.push(id);
+            }
+        }
+
+        self.batches = recovered;
+        Ok(self.batches.len())
+    }
+
+    /// Writes `compact` as the difference at `time`, returning once it is durable.
+    pub fn append<K, V>(&mut self, time: &T, compact: &Compact<K, V>) -> io::Result<()>
+    where K: ::timely::Data, V: ::timely::Data {
+
+        let id = self.next;
+        let path = self.batch_path(id);
+        try!(write_durably(&path, |writer, bytes| {
+            try!(write_record(writer, &mut time.clone(), bytes));
+            write_compact(writer, compact, bytes)
+        }));
+
+        self.next += 1;
+        self.batches.push(id);
+        Ok(())
+    }
+
+    /// Records `frontier` as the frontier before which all differences have been appended.
+    pub fn advance(&mut self, frontier: &[T]) -> io::Result<()> {
+        if self.frontier() != Some(frontier) {
+            let path = self.directory.join("frontier");
+            let mut frontier = frontier.to_vec();
+            try!(write_durably(&path, |writer, bytes| write_record(writer, &mut frontier, bytes)));
+            self.frontier = Some(frontier);
+        }
+        Ok(())
+    }
+
+    /// Removes all differences and any recorded frontier, as for a computation starting afresh.
+    pub fn clear(&mut self) -> io::Result<()> {
+        for &id in &self.batches {
+            try!(fs::remove_file(self.batch_path(id)));
+        }
+        let frontier_path = self.directory.join("frontier");
+        if frontier_path.exists() {
+            try!(fs::remove_file(&frontier_path));
+        }
+        self.batches.clear();
+        self.frontier = None;
+        Ok(())
+    }
+
+    fn batch_path(&self, id: usize) -> PathBuf {
+        self.directory.join(format!("batch-{:010}", id))
+    }
+}
+
+/// Writes a temporary file using `logic`, syncs it, and renames it to `path`.
+///
+/// Once this returns, the file at `path` holds the complete new contents, and if the process is
+/// killed before then it holds its previous contents, if any.
+pub fn write_durably<F>(path: &Path, logic: F) -> io::Result<()>
+where F: FnOnce(&mut BufWriter<File>, &mut Vec<u8>)->io::Result<()> {
+    let temp = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(try!(File::create(&temp)));
+        try!(logic(&mut writer, &mut Vec::new()));
+        try!(writer.flush());
+        try!(writer.get_ref().sync_all());
+    }
+    try!(fs::rename(&temp, path));
+    // make the rename durable, where the platform lets us open directories.
+    if let Some(directory) = path.parent() {
+        if let Ok(directory) = File::open(directory) {
+            let _ = directory.sync_all();
+        }
+    }
+    Ok(())
+}
+
+/// Makes the file at `from` also available at `to`, replacing any file there, and syncs it.
+///
+/// The file is hard linked if the platform allows, and copied otherwise; either way, it should
+/// not be modified afterwards.
+pub fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        try!(fs::remove_file(to));
+    }
+    if fs::hard_link(from, to).is_err() {
+        try!(fs::copy(from, to));
+    }
+    try!(try!(File::open(to)).sync_all());
+    Ok(())
+}
+
+/// Writes the length of the serialized `item` followed by its bytes, using `bytes` as scratch space.
+pub fn write_record<W: Write, T: Serialize>(writer: &mut W, item: &mut T, bytes: &mut Vec<u8>) -> io::Result<()> {
+    bytes.clear();
+    item.into_bytes(bytes);
+    let mut word = [0u8; 8];
+    LittleEndian::write_u64(&mut word, bytes.len() as u64);
+    try!(writer.write_all(&word));
+    writer.write_all(&bytes[..])
+}
+
+/// Reads an item written by `write_record`.
+pub fn read_record<R: Read, T: Serialize>(reader: &mut R) -> io::Result<T> {
+    let mut word = [0u8; 8];
+    try!(reader.read_exact(&mut word));
+    let mut bytes = vec![0u8; LittleEndian::read_u64(&word) as usize];
+    try!(reader.read_exact(&mut bytes[..]));
+    Ok(T::from_bytes(&mut bytes))
+}
+
+fn write_compact<W: Write, K: ::timely::Data, V: ::timely::Data>(writer: &mut W, compact: &Compact<K, V>, bytes: &mut Vec<u8>) -> io::Result<()> {
+    let mut word = [0u8; 8];
+    LittleEndian::write_u64(&mut word, compact.keys.len() as u64);
+    try!(writer.write_all(&word));
+    for (key, &cnt) in compact.keys.iter().zip(compact.cnts.iter()) {
+        try!(write_record(writer, &mut key.clone(), bytes));
+        LittleEndian::write_u64(&mut word, cnt as u64);
+        try!(writer.write_all(&word));
+    }
+    LittleEndian::write_u64(&mut word, compact.vals.len() as u64);
+    try!(writer.write_all(&word));
+    for &(ref val, wgt) in compact.vals.iter() {
+        try!(write_record(writer, &mut val.clone(), bytes));
+        let mut word = [0u8; 4];
+        LittleEndian::write_i32(&mut word, wgt);
+        try!(writer.write_all(&word));
+    }
+    Ok(())
+}
+
+fn read_compact<R: Read, K: ::timely::Data, V: ::timely::Data>(reader: &mut R) -> io::Result<Compact<K, V>> {
+    let mut word = [0u8; 8];
+    try!(reader.read_exact(&mut word));
+    let keys = LittleEndian::read_u64(&word) as usize;
+    let mut result = Compact { keys: Vec::with_capacity(keys), cnts: Vec::with_capacity(keys), vals: Vec::new() };
+    for _ in 0 .. keys {
+        result.keys.push(try!(read_record(reader)));
+        try!(reader.read_exact(&mut word));
+        let cnt = LittleEndian::read_u64(&word);
+        if cnt > OffsetInt::max_value() as u64 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "too many values for one key; see feature `wide-offsets`"));
+        }
+        result.cnts.push(cnt as OffsetInt);
+    }
+    try!(reader.read_exact(&mut word));
+    let vals = LittleEndian::read_u64(&word) as usize;
+    result.vals.reserve(vals);
+    for _ in 0 .. vals {
+        let val = try!(read_record(reader));
+        let mut word = [0u8; 4];
+        try!(reader.read_exact(&mut word));
+        result.vals.push((val, LittleEndian::read_i32(&word)));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use collection::compact::Compact;
+    use super::BatchLog;
+
+    fn directory(name: &str) -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!("differential-persist-{}-{}", name, ::time::precise_time_ns()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn compact(keys: Vec<u64>) -> Compact<u64, u64> {
+        let mut result = Compact::new(0, 0);
+        result.extend(keys.into_iter().map(|k| ((k, k + 1), 1)));
+        result
+    }
+
+    #[test]
+    fn recover_before_frontier() {
+
+        let path = directory("frontier");
+        {
+            let mut log = BatchLog::<u64>::open(&path).unwrap();
+            log.append(&0, &compact(vec![1, 2, 3])).unwrap();
+            log.append(&1, &compact(vec![2, 4])).unwrap();
+            log.advance(&[2]).unwrap();
+            log.append(&2, &compact(vec![5])).unwrap();
+            // a difference being written when the process was killed.
+            fs::File::create(path.join("batch-0000000003.tmp")).unwrap();
+        }
+
+        let mut log = BatchLog::<u64>::open(&path).unwrap();
+        assert_eq!(log.frontier(), Some(&[2][..]));
+        let mut recovered = Vec::new();
+        assert_eq!(log.recover(|time, compact: Compact<u64, u64>| recovered.push((time, compact.keys))).unwrap(), 2);
+        assert_eq!(recovered, vec![(0, vec![1, 2, 3]), (1, vec![2, 4])]);
+
+        // differences at or beyond the frontier stay discarded until it advances past them.
+        log.append(&2, &compact(vec![6])).unwrap();
+        drop(log);
+        let mut log = BatchLog::<u64>::open(&path).unwrap();
+        let mut times = Vec::new();
+        log.recover(|time, _compact: Compact<u64, u64>| times.push(time)).unwrap();
+        assert_eq!(times, vec![0, 1]);
+        assert_eq!(log.len(), 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_values() {
+
+        let path = directory("values");
+        let mut log = BatchLog::<u64>::open(&path).unwrap();
+        log.append(&3, &compact(vec![7, 8])).unwrap();
+        log.advance(&[4]).unwrap();
+
+        let mut log = BatchLog::<u64>::open(&path).unwrap();
+        let mut recovered = Vec::new();
+        log.recover(|_time, compact: Compact<u64, u64>| recovered.push(compact)).unwrap();
+        assert_eq!(recovered[0].cnts, vec![1, 1]);
+        assert_eq!(recovered[0].vals, vec![(8, 1), (9, 1)]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ pub struct RHHMap<K: Eq, V, F: Fn(&K)->usize> {
     buffer: Vec<Option<(K,V)>>,
     shift:  usize,
     slop:   usize,
+    len:    usize,
 }
 
 impl<K: Eq, V, F: Fn(&K)->usize> RHHMap<K, V, F> {
@@ -15,9 +16,20 @@ impl<K: Eq, V, F: Fn(&K)->usize> RHHMap<K, V, F> {
             buffer: buffer,
             shift: 63,
             slop: slop,
+            len: 0,
         }
     }
     pub fn capacity(&self) -> usize { self.buffer.capacity() }
+    /// The number of key-value pairs in the map.
+    pub fn len(&self) -> usize { self.len }
+    /// Enumerates the key-value pairs in the map, in order of their hashes.
+    pub fn iter<'a>(&'a self) -> RHHIterator<'a, K, V> {
+        RHHIterator { slots: self.buffer.iter() }
+    }
+    /// Enumerates the key-value pairs in the map, with mutable values, in order of their hashes.
+    pub fn iter_mut<'a>(&'a mut self) -> Box<Iterator<Item=(&'a K, &'a mut V)>+'a> {
+        Box::new(self.buffer.iter_mut().filter_map(|x| x.as_mut().map(|&mut (ref k, ref mut v)| (k, v))))
+    }
 
     #[inline]
     pub fn get_ref<'a>(&'a self, query: &K) -> Option<&'a V> {
@@ -41,7 +53,10 @@ impl<K: Eq, V, F: Fn(&K)->usize> RHHMap<K, V, F> {
             insert(&mut self.buffer[..], key, val, &|x| bucket(x), location)
         };
         match result {
-            Ok(result)      => result,
+            Ok(result)      => {
+                if result.is_none() { self.len += 1; }
+                result
+            },
             Err((key, val)) => {
 
                 let old_length = self.buffer.len() - self.slop;
@@ -70,10 +85,105 @@ impl<K: Eq, V, F: Fn(&K)->usize> RHHMap<K, V, F> {
             }
         }
     }
+    /// Halves the capacity of the map for as long as it would remain at most a quarter full.
+    ///
+    /// Maps never shrink on their own, as they double when an insertion finds no room. This may be
+    /// called after removing many keys to release their memory, as traces do when they compact.
+    pub fn shrink_to_fit(&mut self) {
+
+        let mut length = self.buffer.len() - self.slop;
+        let mut shift = self.shift;
+        while length > 2 && 4 * self.len <= length / 2 {
+            length /= 2;
+            shift += 1;
+        }
+
+        if length < self.buffer.len() - self.slop {
+
+            // confirm each record has a position before rearranging any, as in `insert`.
+            let mut cursor = 0;
+            for &(ref key, _) in self.buffer.iter().filter_map(|x| x.as_ref()) {
+                cursor = ::std::cmp::max(cursor, (self.bucket)(key) >> shift) + 1;
+            }
+
+            if cursor <= length + self.slop {
+                let mut new_buffer = Vec::with_capacity(length + self.slop);
+                for _ in 0..new_buffer.capacity() {
+                    new_buffer.push(None);
+                }
+                let old_buffer = ::std::mem::replace(&mut self.buffer, new_buffer);
+                self.shift = shift;
+
+                let mut cursor = 0;
+                for (oldkey, oldval) in old_buffer.into_iter().filter_map(|x| x) {
+                    let target = (self.bucket)(&oldkey) >> self.shift;
+                    cursor = ::std::cmp::max(cursor, target);
+                    self.buffer[cursor] = Some((oldkey, oldval));
+                    cursor += 1;
+                }
+            }
+        }
+    }
+    /// Reports the occupancy of the map, and how far records sit from their preferred locations.
+    pub fn probe_statistics(&self) -> ProbeStatistics {
+        let mut stats = ProbeStatistics { len: self.len, capacity: self.buffer.len(), max: 0, total: 0 };
+        for (position, slot) in self.buffer.iter().enumerate() {
+            if let Some((ref key, _)) = *slot {
+                let distance = position - ((self.bucket)(key) >> self.shift);
+                stats.max = ::std::cmp::max(stats.max, distance);
+                stats.total += distance;
+            }
+        }
+        stats
+    }
     pub fn remove(&mut self, key: &K) -> Option<(K,V)> {
         let shift = self.shift;
-        let bucket = &self.bucket;
-        remove(&mut self.buffer[..], key, &|x| bucket(x), bucket(key) >> shift)
+        let result = {
+            let bucket = &self.bucket;
+            remove(&mut self.buffer[..], key, &|x| bucket(x), shift)
+        };
+        if result.is_some() { self.len -= 1; }
+        result
+    }
+}
+
+/// The occupancy of an `RHHMap`, and the distances of its records from their preferred locations.
+///
+/// The distance of a record bounds the number of slots a lookup of its key must probe.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProbeStatistics {
+    /// The number of records.
+    pub len: usize,
+    /// The number of slots, including those past the end of the hash range.
+    pub capacity: usize,
+    /// The largest distance of any record.
+    pub max: usize,
+    /// The sum of the distances of all records.
+    pub total: usize,
+}
+
+impl ProbeStatistics {
+    /// The fraction of slots occupied.
+    pub fn load(&self) -> f64 { self.len as f64 / self.capacity as f64 }
+    /// The average distance of a record from its preferred location.
+    pub fn mean(&self) -> f64 { if self.len > 0 { self.total as f64 / self.len as f64 } else { 0.0 } }
+}
+
+/// Enumerates the key-value pairs of an `RHHMap`.
+pub struct RHHIterator<'a, K: 'a, V: 'a> {
+    slots: ::std::slice::Iter<'a, Option<(K,V)>>,
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for RHHIterator<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        while let Some(slot) = self.slots.next() {
+            if let Some((ref key, ref val)) = *slot {
+                return Some((key, val));
+            }
+        }
+        None
     }
 }
 
@@ -217,19 +327,19 @@ pub fn insert<'a, K: Eq, V, F: Fn(&K)->usize>(slice: &'a mut [Option<(K,V)>], ke
 /// Returns either the `(key,val)` pair associated with `key`, or `None` if it does not exist.
 ///
 /// The `remove` method operates over a slice of `Option<(K,V)>`, given a target key and a hash
-/// function `function`. It also takes an initial `location` from which to start its search, for
-/// some reason.
+/// function `function`. The preferred location of a key is its hash shifted right by `shift`,
+/// which is where the search starts.
+///
+/// Having removed the pair, subsequent records are shifted back one position for as long as they
+/// are past their preferred location, so that no record is separated from its preferred location
+/// by an empty slot, and the records remain sorted by hash.
 #[inline]
-pub fn remove<'a, K: Eq, V, F: Fn(&K)->usize>(slice: &mut [Option<(K,V)>], key: &K, function: &F, location: usize) -> Option<(K,V)> {
-
-    if true {
-        panic!("rhhmap::remove is buggy; see code for details");
-    }
+pub fn remove<'a, K: Eq, V, F: Fn(&K)->usize>(slice: &mut [Option<(K,V)>], key: &K, function: &F, shift: usize) -> Option<(K,V)> {
 
     let target = function(&key);
 
     let mut success = false;
-    let mut position = location;
+    let mut position = target >> shift;
     while position < slice.len() {
         if let Some(ref mut kv) = slice[position].as_mut() {
             let found = function(&kv.0);
@@ -248,10 +358,9 @@ pub fn remove<'a, K: Eq, V, F: Fn(&K)->usize>(slice: &mut [Option<(K,V)>], key:
         let result = slice[position].take();
 
         // now propagate the None forward as long as records are past their preferred location
-        // BUG : `function` isn't the desired position, it is the hash. it should be shifted or something.
         while position + 1 < slice.len()
            && slice[position + 1].is_some()
-           && function(&slice[position + 1].as_ref().unwrap().0) <= position {
+           && (function(&slice[position + 1].as_ref().unwrap().0) >> shift) <= position {
             slice.swap(position, position + 1);
             position += 1;
         }
@@ -262,3 +371,48 @@ pub fn remove<'a, K: Eq, V, F: Fn(&K)->usize>(slice: &mut [Option<(K,V)>], key:
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use ::Data;
+    use super::RHHMap;
+
+    #[test]
+    fn remove_and_reinsert() {
+        let mut map = RHHMap::new(|x: &u64| x.hashed() as usize);
+        for i in 0 .. 1000u64 { map.insert(i, i + 1); }
+        for i in 0 .. 500u64 { assert_eq!(map.remove(&(2 * i)), Some((2 * i, 2 * i + 1))); }
+        for i in 0 .. 1000u64 {
+            assert_eq!(map.get_ref(&i).cloned(), if i % 2 == 0 { None } else { Some(i + 1) });
+        }
+        assert_eq!(map.remove(&0), None);
+        for i in 0 .. 500u64 { map.insert(2 * i, 0); }
+        for i in 0 .. 1000u64 {
+            assert_eq!(map.get_ref(&i).cloned(), Some(if i % 2 == 0 { 0 } else { i + 1 }));
+        }
+        assert_eq!(map.len(), 1000);
+        let mut keys = map.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+        keys.sort();
+        assert!(keys == (0 .. 1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shrink_after_removal() {
+        let mut map = RHHMap::new(|x: &u64| x.hashed() as usize);
+        for i in 0 .. 10000u64 { map.insert(i, i + 1); }
+        let capacity = map.capacity();
+        for i in 100 .. 10000u64 { map.remove(&i); }
+        map.shrink_to_fit();
+        assert!(map.capacity() < capacity / 8);
+        assert_eq!(map.len(), 100);
+        for i in 0 .. 10000u64 {
+            assert_eq!(map.get_ref(&i).cloned(), if i < 100 { Some(i + 1) } else { None });
+        }
+        let stats = map.probe_statistics();
+        assert_eq!(stats.len, 100);
+        assert!(stats.max >= stats.total / 100);
+        for i in 100 .. 1000u64 { map.insert(i, i + 1); }
+        assert_eq!(map.len(), 1000);
+    }
+}
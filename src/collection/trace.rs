@@ -1,11 +1,14 @@
 use std::iter::Peekable;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 
-use collection::{close_under_lub, LeastUpperBound, Lookup};
+use collection::{close_under_lub, merge_sorted, new_bounds, LeastUpperBound, Lookup, OrderedLookup, OffsetInt, to_offset};
 
-use iterators::merge::{MergeUsing, MergeUsingIterator};
+use iterators::merge::{Merge, MergeUsing, MergeUsingIterator};
 use iterators::coalesce::{Coalesce, CoalesceIterator};
-use collection::compact::Compact;
+use iterators::run_length::{RunLength, RunLengthIterator};
+use collection::compact::{Compact, CompactRuns};
+use stats::Statistics;
 
 /// Enumerates the elements of a collection for a given key at a given time.
 ///
@@ -15,17 +18,17 @@ pub type CollectionIterator<'a, V> = Peekable<CoalesceIterator<MergeUsingIterato
 
 #[derive(Copy, Clone, Debug)]
 pub struct Offset {
-    dataz: u32,
+    dataz: OffsetInt,
 }
 
 impl Offset {
     #[inline(always)]
     fn new(offset: usize) -> Offset {
-        assert!(offset < ((!0u32) as usize)); // note strict inequality
-        Offset { dataz: (!0u32) - offset as u32 }
+        assert!((offset as u64) < (OffsetInt::max_value() as u64), "too many links; see feature `wide-offsets`"); // note strict inequality
+        Offset { dataz: OffsetInt::max_value() - offset as OffsetInt }
     }
     #[inline(always)]
-    fn val(&self) -> usize { ((!0u32) - self.dataz) as usize }
+    fn val(&self) -> usize { (OffsetInt::max_value() - self.dataz) as usize }
 }
 
 /// A map from keys to time-indexed collection differences.
@@ -44,14 +47,48 @@ impl Offset {
 /// received at each.
 
 struct ListEntry {
-    time: u32,
-    vals: u32,
+    time: OffsetInt,
+    vals: OffsetInt,
     next: Option<Offset>,
 }
 
 struct TimeEntry<T, V> {
     time: T,
-    vals: Vec<(V, i32)>,
+    vals: Diffs<V>,
+    live: OffsetInt,  // number of keys with differences at this time that have not been retired.
+}
+
+/// The values and weights at a time, either as a list or run-length encoded.
+enum Diffs<V> {
+    Plain(Vec<(V, i32)>),
+    Runs(RunLength<V>, RunLength<i32>),
+}
+
+impl<V> Diffs<V> {
+    fn len(&self) -> usize {
+        match *self {
+            Diffs::Plain(ref vals) => vals.len(),
+            Diffs::Runs(ref vals, _) => vals.len(),
+        }
+    }
+    fn size(&self) -> usize {
+        match *self {
+            Diffs::Plain(ref vals) => vals.len() * ::std::mem::size_of::<(V, i32)>(),
+            Diffs::Runs(ref vals, ref wgts) => vals.size() + wgts.size(),
+        }
+    }
+}
+
+/// Retains only the items at positions within `ranges`, which must be sorted and disjoint.
+fn retain_ranges<T>(items: Vec<T>, ranges: &[(usize, usize)]) -> Vec<T> {
+    let mut result = Vec::with_capacity(ranges.iter().fold(0, |sum, &(lower, upper)| sum + upper - lower));
+    let mut range = 0;
+    for (index, item) in items.into_iter().enumerate() {
+        while range < ranges.len() && ranges[range].1 <= index { range += 1; }
+        if range == ranges.len() { break; }
+        if ranges[range].0 <= index { result.push(item); }
+    }
+    result
 }
 
 /// A collection of values indexed by `key` and `time`.
@@ -68,6 +105,10 @@ pub struct Trace<K, T, V, L> {
     times:      Vec<TimeEntry<T, V>>,
     pub keys:       L,
     temp:       Vec<T>,
+    closures:   BTreeMap<K, Vec<T>>,    // for each key seen by `interesting_times`, its closed set of times.
+    key_count:  usize,
+    value_bytes: usize,
+    dead_links: usize,  // number of links of retired keys, awaiting compaction.
 }
 
 // impl<K, T, V, L> Drop for Trace<K, T, V, L> {
@@ -79,18 +120,42 @@ pub struct Trace<K, T, V, L> {
 impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T: LeastUpperBound+Debug {
 
     /// Installs a supplied set of keys and values as the differences for `time`.
+    ///
+    /// The values and weights are stored run-length encoded if this would use less space, as it
+    /// typically does when all weights are one.
     pub fn set_difference(&mut self, time: T, accumulation: Compact<K, V>) {
+        if accumulation.compressed_size() < accumulation.size() {
+            self.set_difference_runs(time, accumulation.compress());
+        }
+        else {
+            let time_keys = self.install_links(accumulation.keys, accumulation.cnts);
+            let diffs = Diffs::Plain(accumulation.vals);
+            self.value_bytes += diffs.size();
+            self.times.push(TimeEntry { time: time, vals: diffs, live: to_offset(time_keys) });
+        }
+    }
 
-        // extract the relevant fields
-        let keys = accumulation.keys;
-        let cnts = accumulation.cnts;
-        let vals = accumulation.vals;
+    /// Installs a supplied set of keys and run-length encoded values as the differences for `time`.
+    pub fn set_difference_runs(&mut self, time: T, accumulation: CompactRuns<K, V>) {
+        let time_keys = self.install_links(accumulation.keys, accumulation.cnts);
+        let diffs = Diffs::Runs(accumulation.vals, accumulation.wgts);
+        self.value_bytes += diffs.size();
+        self.times.push(TimeEntry { time: time, vals: diffs, live: to_offset(time_keys) });
+    }
+
+    /// Adds links for each key to the values of the time about to be pushed to `self.times`.
+    ///
+    /// Returns the number of keys, each of which has `cnts` values at the new time.
+    fn install_links(&mut self, keys: Vec<K>, cnts: Vec<OffsetInt>) -> usize {
 
         // index of the self.times entry we are about to insert
         let time_index = self.times.len();
 
         // counters for offsets in vals and wgts
-        let mut vals_offset = 0;
+        let mut vals_offset: OffsetInt = 0;
+
+        // number of keys with differences at this time
+        let time_keys = keys.len();
 
         self.links.reserve(keys.len());
 
@@ -103,9 +168,10 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
 
             // if we inserted a previously absent key
             if &prev_position.val() == &next_position.val() {
+                self.key_count += 1;
                 // add the appropriate entry with no next pointer
                 self.links.push(ListEntry {
-                    time: time_index as u32,
+                    time: to_offset(time_index),
                     vals: vals_offset,
                     next: None
                 });
@@ -114,7 +180,7 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
             else {
                 // add the appropriate entry
                 self.links.push(ListEntry {
-                    time: time_index as u32,
+                    time: to_offset(time_index),
                     vals: vals_offset,
                     next: Some(*prev_position)
                 });
@@ -122,11 +188,10 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
             }
 
             // advance offsets.
-            vals_offset += cnt;
+            vals_offset = vals_offset.checked_add(cnt).expect("too many values for one time; see feature `wide-offsets`");
         }
 
-        // add the values and weights to the list of timed differences.
-        self.times.push(TimeEntry { time: time, vals: vals });
+        time_keys
     }
 
     #[inline]
@@ -134,18 +199,25 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
 
         let time = self.links[position.val()].time as usize;
         let vals_lower = self.links[position.val()].vals as usize;
+        let vals_upper = self.vals_upper(position.val());
 
-        // upper limit can be read if next link exists and of the same index. else, is last elt.
-        let vals_upper = if (position.val() + 1) < self.links.len()
-                                        && time == self.links[position.val() + 1].time as usize {
+        match self.times[time].vals {
+            Diffs::Plain(ref vals) => DifferenceIterator::new(&vals[vals_lower..vals_upper]),
+            Diffs::Runs(ref vals, ref wgts) => DifferenceIterator::from_runs(vals, wgts, vals_lower, vals_upper),
+        }
+    }
 
-            self.links[position.val() + 1].vals as usize
+    /// The end of the values of the link at `position`.
+    #[inline]
+    fn vals_upper(&self, position: usize) -> usize {
+        // upper limit can be read if next link exists and of the same index. else, is last elt.
+        let time = self.links[position].time as usize;
+        if (position + 1) < self.links.len() && time == self.links[position + 1].time as usize {
+            self.links[position + 1].vals as usize
         }
         else {
             self.times[time].vals.len()
-        };
-
-        DifferenceIterator::new(&self.times[time].vals[vals_lower..vals_upper])
+        }
     }
 
     /// Enumerates the differences for `key` at `time`.
@@ -183,27 +255,148 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
             .peekable()
     }
 
-    // TODO : this could do a better job of returning newly interesting times: those times that are
-    // TODO : now in the least upper bound, but were not previously so. The main risk is that the
-    // TODO : easy way to do this computes the LUB before and after, but this can be expensive:
-    // TODO : the LUB with `index` is often likely to be smaller than the LUB without it.
-    /// Lists times that are the least upper bound of `time` and any subset of existing times.
-    pub fn interesting_times<'a>(&'a mut self, key: &K, index: T) -> &'a [T] {
-        // panic!();
-        let mut temp = ::std::mem::replace(&mut self.temp, Vec::new());
-        temp.clear();
-        temp.push(index);
-        for (time, _) in self.trace(key) {
-            let lub = time.least_upper_bound(&temp[0]);
-            if !temp.contains(&lub) {
-                temp.push(lub);
-            }
+    /// Lists the times at which the accumulated differences for `key` may change as a result of
+    /// differences at `index`, and which have not been listed for `key` before.
+    ///
+    /// The accumulation at a time `t` depends only on which of the key's times are less or equal
+    /// to `t`, and is the same as at the least upper bound of those times. Differences at `index`
+    /// may therefore change the accumulation only at bounds of `index` with subsets of the key's
+    /// times. Bounds not involving `index` were listed when their last time arrived, and those at
+    /// least `index` are yet to be processed, so only the bounds new to the key's closed set of
+    /// times are listed, with `index` itself. The set is kept for each key, built from the trace
+    /// the first time the key is seen, and extended here, so that differences for `key` should be
+    /// installed at `index` after this call. The times are sorted, and `index`, which is less or
+    /// equal to all of them, comes first.
+    pub fn interesting_times<'a>(&'a mut self, key: &K, index: T) -> &'a [T] where K: Clone, T: Clone {
+        if !self.closures.contains_key(key) {
+            let mut closed = self.trace(key).map(|(time, _)| time.clone()).collect::<Vec<_>>();
+            close_under_lub(&mut closed);
+            self.closures.insert(key.clone(), closed);
         }
-        close_under_lub(&mut temp);
-        ::std::mem::replace(&mut self.temp, temp);
+        let closed = self.closures.get_mut(key).unwrap();
+        let added = new_bounds(closed, index.clone());
+        self.temp.clear();
+        if added.is_empty() { self.temp.push(index); }
+        self.temp.extend(added.iter().cloned());
+        merge_sorted(closed, added);
         &self.temp[..]
     }
 
+    /// Removes `key` if its differences accumulate to nothing and are all at times less or equal
+    /// to every element of `frontier`.
+    ///
+    /// Such a key has an empty collection at every time the computation may yet reach, and any
+    /// time it might contribute to `interesting_times` is already among them, so its removal can
+    /// not be observed. The key is removed from `self.keys`, and the values of any time whose keys
+    /// have all been removed are released. The links themselves remain, unreachable, until they
+    /// make up more than half of all links, at which point the trace is compacted.
+    /// Returns `true` if the key was removed.
+    pub fn retire_key(&mut self, key: &K, frontier: &[T]) -> bool {
+
+        let cancelled = self.trace(key).all(|(time, _)| frontier.iter().all(|f| time <= f))
+                     && self.trace(key).map(|(_, diffs)| diffs).merge().coalesce().next().is_none();
+
+        if cancelled {
+            if let Some(head) = self.keys.remove_key(key) {
+                self.closures.remove(key);
+                let mut next = Some(head);
+                while let Some(position) = next {
+                    let time = self.links[position.val()].time as usize;
+                    self.times[time].live -= 1;
+                    if self.times[time].live == 0 {
+                        self.value_bytes -= self.times[time].vals.size();
+                        self.times[time].vals = Diffs::Plain(Vec::new());
+                    }
+                    next = self.links[position.val()].next;
+                    self.dead_links += 1;
+                }
+                self.key_count -= 1;
+                if self.dead_links > self.links.len() / 2 {
+                    self.compact();
+                }
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Removes the links of retired keys and their values, and the times left without any keys,
+    /// and releases the memory `self.keys` held for retired keys.
+    ///
+    /// Links keep their order, so the links of each time remain adjacent and delimit their values
+    /// as before, and each list still leads from later links to earlier ones.
+    fn compact(&mut self) {
+
+        // mark the links reachable from some key.
+        let mut live = vec![false; self.links.len()];
+        for head in self.keys.values_mut() {
+            let mut next = Some(*head);
+            while let Some(position) = next {
+                live[position.val()] = true;
+                next = self.links[position.val()].next;
+            }
+        }
+
+        // note the values each live link retains, and where they will start once compacted.
+        let mut ranges = vec![Vec::new(); self.times.len()];
+        let mut filled = vec![0; self.times.len()];
+        let mut offsets = vec![0; self.links.len()];
+        for position in 0 .. self.links.len() {
+            if live[position] {
+                let time = self.links[position].time as usize;
+                let lower = self.links[position].vals as usize;
+                let upper = self.vals_upper(position);
+                ranges[time].push((lower, upper));
+                offsets[position] = filled[time];
+                filled[time] += upper - lower;
+            }
+        }
+
+        // retain the live values of each time, and the times that have any.
+        let mut time_remap = Vec::with_capacity(self.times.len());
+        let mut times = Vec::with_capacity(ranges.iter().filter(|x| x.len() > 0).count());
+        for (entry, ranges) in self.times.drain(..).zip(ranges.into_iter()) {
+            time_remap.push(times.len());
+            if ranges.len() > 0 {
+                let vals = match entry.vals {
+                    Diffs::Plain(vals) => Diffs::Plain(retain_ranges(vals, &ranges[..])),
+                    Diffs::Runs(mut vals, mut wgts) => {
+                        vals.retain_ranges(&ranges[..]);
+                        wgts.retain_ranges(&ranges[..]);
+                        Diffs::Runs(vals, wgts)
+                    },
+                };
+                times.push(TimeEntry { time: entry.time, vals: vals, live: entry.live });
+            }
+        }
+        self.times = times;
+
+        // retain the live links, pointing at their new times, values, and successors. positions
+        // and offsets only decrease, so they fit wherever they did before.
+        let mut remap = vec![0; self.links.len()];
+        let mut links = Vec::with_capacity(self.links.len() - self.dead_links);
+        for (position, link) in self.links.drain(..).enumerate() {
+            if live[position] {
+                remap[position] = links.len();
+                links.push(ListEntry {
+                    time: to_offset(time_remap[link.time as usize]),
+                    vals: to_offset(offsets[position]),
+                    next: link.next.map(|next| Offset::new(remap[next.val()])),
+                });
+            }
+        }
+        self.links = links;
+
+        for head in self.keys.values_mut() {
+            *head = Offset::new(remap[head.val()]);
+        }
+        self.keys.shrink_to_fit();
+
+        self.dead_links = 0;
+        self.value_bytes = self.times.iter().map(|x| x.vals.size()).fold(0, |sum, size| sum + size);
+    }
+
     /// Enumerates pairs of time `&T` and differences `DifferenceIterator<V>` for `key`.
     pub fn trace<'a>(&'a self, key: &K) -> TraceIterator<'a, K, T, V, L> {
         TraceIterator {
@@ -213,7 +406,27 @@ impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: Lookup<K, Offset>, T
     }
 }
 
-impl<K: Eq, L: Lookup<K, Offset>, T, V> Trace<K, T, V, L> {
+impl<K, V, L, T> Trace<K, T, V, L> where K: Ord, V: Ord, L: OrderedLookup<K, Offset>, T: LeastUpperBound+Debug {
+    /// Lists the keys from `lower` to `upper` inclusive whose collections at `time` are non-empty,
+    /// in key order, with their collections.
+    pub fn range<'a>(&'a self, lower: &K, upper: &K, time: &T) -> Vec<(&'a K, Vec<(&'a V, i32)>)> {
+        let mut result = Vec::new();
+        self.keys.range(lower, upper, |key, _| {
+            let collection = self.trace(key)
+                                 .filter(|x| x.0 <= time)
+                                 .map(|x| x.1)
+                                 .merge()
+                                 .coalesce()
+                                 .collect::<Vec<_>>();
+            if collection.len() > 0 {
+                result.push((key, collection));
+            }
+        });
+        result
+    }
+}
+
+impl<K: Ord, L: Lookup<K, Offset>, T, V> Trace<K, T, V, L> {
     pub fn new(l: L) -> Trace<K, T, V, L> {
         // println!("allocating trace");
         Trace {
@@ -222,6 +435,27 @@ impl<K: Eq, L: Lookup<K, Offset>, T, V> Trace<K, T, V, L> {
             times:   Vec::new(),
             keys:    l,
             temp:    Vec::new(),
+            closures: BTreeMap::new(),
+            key_count: 0,
+            value_bytes: 0,
+            dead_links: 0,
+        }
+    }
+    /// Enumerates the keys present in the trace, in the order of its `Lookup`.
+    pub fn keys<'a>(&'a self) -> Box<Iterator<Item=K>+'a> where K: Clone+'a, L: 'a { self.keys.keys() }
+    /// Reports the number of `(key, time)` links in the trace.
+    pub fn link_count(&self) -> usize { self.links.len() }
+    /// Reports the number of differences in the trace, one per call to `set_difference` until
+    /// compaction removes those whose keys have all been retired.
+    pub fn time_count(&self) -> usize { self.times.len() }
+    /// Reports the sizes of the trace's structures.
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            keys: self.key_count,
+            links: self.links.len(),
+            times: self.times.len(),
+            value_bytes: self.value_bytes,
+            .. Default::default()
         }
     }
 }
@@ -254,15 +488,27 @@ where K:  Ord+'a,
 
 /// Enumerates `(&V,i32)` elements of a difference.
 pub struct DifferenceIterator<'a, V: 'a> {
-    vals: &'a [(V,i32)],
-    next: usize,            // index of next entry in vals,
+    source: DifferenceSource<'a, V>,
+    remaining: usize,       // number of elements yet to enumerate.
+}
+
+/// The storage a `DifferenceIterator` reads from.
+enum DifferenceSource<'a, V: 'a> {
+    Plain(&'a [(V, i32)], usize),   // a slice and the index of its next entry.
+    Runs(RunLengthIterator<'a, V>, RunLengthIterator<'a, i32>),
 }
 
 impl<'a, V: 'a> DifferenceIterator<'a, V> {
     fn new(vals: &'a [(V, i32)]) -> DifferenceIterator<'a, V> {
         DifferenceIterator {
-            vals: vals,
-            next: 0,
+            source: DifferenceSource::Plain(vals, 0),
+            remaining: vals.len(),
+        }
+    }
+    fn from_runs(vals: &'a RunLength<V>, wgts: &'a RunLength<i32>, lower: usize, upper: usize) -> DifferenceIterator<'a, V> {
+        DifferenceIterator {
+            source: DifferenceSource::Runs(vals.iter_from(lower), wgts.iter_from(lower)),
+            remaining: upper - lower,
         }
     }
 }
@@ -270,8 +516,11 @@ impl<'a, V: 'a> DifferenceIterator<'a, V> {
 impl<'a, V: 'a> Clone for DifferenceIterator<'a, V> {
     fn clone(&self) -> Self {
         DifferenceIterator {
-            vals: self.vals,
-            next: self.next,
+            source: match self.source {
+                DifferenceSource::Plain(vals, next) => DifferenceSource::Plain(vals, next),
+                DifferenceSource::Runs(ref vals, ref wgts) => DifferenceSource::Runs(vals.clone(), wgts.clone()),
+            },
+            remaining: self.remaining,
         }
     }
 }
@@ -281,12 +530,135 @@ impl<'a, V: 'a> Iterator for DifferenceIterator<'a, V> {
 
     #[inline]
     fn next(&mut self) -> Option<(&'a V, i32)> {
-        if self.next < self.vals.len() {
-            self.next += 1;
-            Some((&self.vals[self.next - 1].0, self.vals[self.next - 1].1))
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            match self.source {
+                DifferenceSource::Plain(vals, ref mut next) => {
+                    *next += 1;
+                    Some((&vals[*next - 1].0, vals[*next - 1].1))
+                },
+                DifferenceSource::Runs(ref mut vals, ref mut wgts) => {
+                    Some((vals.next().unwrap(), *wgts.next().unwrap()))
+                },
+            }
         }
         else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use ::Data;
+    use collection::robin_hood::RHHMap;
+    use collection::compact::Compact;
+    use arrangement::Query;
+    use collection::{OffsetInt, to_offset};
+    use super::{Offset, Trace};
+    use timely::progress::nested::product::Product;
+
+    // two values for each key in `keys`, with weights `wgt` and `-wgt` for retractions.
+    fn compact<I: Iterator<Item=u64>>(keys: I, wgt: &Fn(u64)->i32) -> Compact<u64, u64> {
+        let mut updates = Vec::new();
+        for key in keys {
+            updates.push(((key, 10 * key), wgt(key)));
+            updates.push(((key, 10 * key + 1), wgt(key + 1)));
+        }
+        Compact::from_sorted(&mut vec![updates]).unwrap()
+    }
+
+    // weights of one are stored run-length encoded, and alternating weights are not.
+    #[test] fn retire_runs() { retire_and_reinsert(&|_| 1); }
+    #[test] fn retire_plain() { retire_and_reinsert(&|x| (x % 2) as i32 + 1); }
+
+    fn retire_and_reinsert(wgt: &Fn(u64)->i32) {
+
+        let mut trace = Trace::new(RHHMap::new(|x: &u64| x.hashed() as usize));
+        trace.set_difference(0u64, compact(0 .. 100, wgt));
+        trace.set_difference(1u64, compact(0 .. 80, &|x| -wgt(x)));
+        let bytes = trace.statistics().value_bytes;
+        assert_eq!(trace.link_count(), 180);
+
+        for key in 0 .. 100 {
+            assert_eq!(trace.retire_key(&key, &[1]), key < 80);
+        }
+
+        // each compaction leaves at most as many dead links as live ones.
+        assert!(trace.link_count() <= 2 * 20);
+        assert!(trace.statistics().value_bytes < bytes);
+        assert_eq!(trace.keys().count(), 20);
+        for key in 0 .. 100 {
+            let expected = if key < 80 { vec![] } else { vec![(10 * key, wgt(key)), (10 * key + 1, wgt(key + 1))] };
+            assert_eq!(trace.collection(&key, &0), expected);
+            assert_eq!(trace.collection(&key, &1), expected);
+        }
+
+        trace.set_difference(2u64, compact(70 .. 90, wgt));
+        assert_eq!(trace.keys().count(), 30);
+        for key in 0 .. 100 {
+            let copies = (key >= 70 && key < 90) as i32 + (key >= 80) as i32;
+            let expected = if copies == 0 { vec![] } else { vec![(10 * key, copies * wgt(key)), (10 * key + 1, copies * wgt(key + 1))] };
+            assert_eq!(trace.collection(&key, &2), expected);
+        }
+    }
+
+    #[test]
+    fn retire_shrinks_keys() {
+        let mut trace = Trace::new(RHHMap::new(|x: &u64| x.hashed() as usize));
+        trace.set_difference(0u64, compact(0 .. 10000, &|_| 1));
+        trace.set_difference(1u64, compact(0 .. 9900, &|_| -1));
+        let capacity = trace.keys.capacity();
+        for key in 0 .. 10000 {
+            trace.retire_key(&key, &[1]);
+        }
+        assert!(trace.keys.capacity() < capacity / 8);
+        for key in 9900 .. 10000 {
+            assert_eq!(trace.collection(&key, &1), vec![(10 * key, 1), (10 * key + 1, 1)]);
+        }
+    }
+
+    #[test]
+    fn interesting_times_once() {
+        let p = |a, b| Product::new(a, b);
+        let mut trace = Trace::new(RHHMap::new(|x: &u64| x.hashed() as usize));
+
+        // each time is listed once, when the first time bounding to it arrives.
+        assert_eq!(trace.interesting_times(&1, p(0u64, 1u64)), &[p(0, 1)]);
+        trace.set_difference(p(0, 1), compact(1 .. 2, &|_| 1));
+        assert_eq!(trace.interesting_times(&1, p(1, 0)), &[p(1, 0), p(1, 1)]);
+        trace.set_difference(p(1, 0), compact(1 .. 2, &|_| 1));
+        assert_eq!(trace.interesting_times(&1, p(0, 2)), &[p(0, 2), p(1, 2)]);
+        trace.set_difference(p(0, 2), compact(1 .. 2, &|_| 1));
+        assert_eq!(trace.interesting_times(&1, p(1, 1)), &[p(1, 1)]);
+        trace.set_difference(p(1, 1), compact(1 .. 2, &|_| 1));
+
+        // differences installed without listing, as when restored, are closed over when first seen.
+        trace.set_difference(p(0, 1), compact(2 .. 3, &|_| 1));
+        trace.set_difference(p(1, 0), compact(2 .. 3, &|_| 1));
+        assert_eq!(trace.interesting_times(&2, p(0, 2)), &[p(0, 2), p(1, 2)]);
+
+        // a retired key starts afresh.
+        trace.set_difference(p(2, 2), compact(1 .. 2, &|_| -4));
+        assert!(trace.retire_key(&1, &[p(2, 2)]));
+        assert_eq!(trace.interesting_times(&1, p(0, 3)), &[p(0, 3)]);
+    }
+
+    #[cfg(feature = "wide-offsets")]
+    #[test]
+    fn wide_offsets() {
+        let position = (1u64 << 40) as usize;
+        assert_eq!(to_offset(position), position as OffsetInt);
+        assert_eq!(Offset::new(position).val(), position);
+    }
+
+    #[cfg(not(feature = "wide-offsets"))]
+    #[test]
+    fn narrow_offsets() {
+        let position = OffsetInt::max_value() as usize;
+        assert_eq!(to_offset(position), OffsetInt::max_value());
+        assert!(::std::panic::catch_unwind(|| to_offset(position + 1)).is_err());
+        assert!(::std::panic::catch_unwind(|| Offset::new(position)).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Reading change-data-capture streams of JSON lines.
+//!
+//! Change-data-capture tools report each change to a table as a JSON object on its own line, with
+//! the row as it was before the change, the row as it is after, and the timestamp of the change's
+//! transaction. An insert has no `before` image (it is absent or `null`), a delete has no `after`
+//! image, and an update has both. A `CdcReader` turns such lines into differences: an insert gives
+//! the new row with weight `+1`, a delete the old row with weight `-1`, and an update both.
+//!
+//! Rows are extracted from the JSON images by a closure, so the reader need not know the schema.
+//! Transaction timestamps must be unsigned integers, and must not decrease from one line to the
+//! next; they become the epochs of a timely input, and the `feed` method sends the differences of
+//! each transaction and advances the input past it once a later transaction begins.
+//!
+//! A file or pipe can be read only once, so it should be fed to the input of a single worker; the
+//! other workers should close their inputs, and the collection is exchanged by the operators that
+//! use it as usual.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! timely::execute_from_args(std::env::args().skip(2), move |computation| {
+//!     let (mut input, probe) = computation.scoped::<u64,_,_>(|scope| {
+//!         let (input, rows) = scope.new_input();
+//!         let probe = Collection::new(rows).consolidate().inner.inspect(|x| println!("{:?}", x)).probe().0;
+//!         (input, probe)
+//!     });
+//!     if computation.index() == 0 {
+//!         let filename = std::env::args().nth(1).unwrap();
+//!         let parse = |row: &Value| row.get("id").and_then(|x| x.as_u64()).ok_or("missing id".to_owned());
+//!         let mut reader = CdcReader::open(&filename, "ts", parse).unwrap();
+//!         while reader.feed(&mut input).unwrap() {
+//!             let epoch = *input.epoch();
+//!             while probe.lt(&RootTimestamp::new(epoch)) { computation.step(); }
+//!         }
+//!     }
+//! });
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::path::Path;
+
+use timely::dataflow::operators::input::Handle;
+
+use ::Data;
+use input::json::{self, Value};
+
+/// Reads `(row, time, weight)` differences from change-data-capture JSON lines.
+pub struct CdcReader<R: BufRead, D, F: Fn(&Value)->Result<D, String>> {
+    reader: R,
+    time_field: String,
+    parse: F,
+    line: usize,
+    pending: VecDeque<(D, u64, i32)>,
+    last_time: u64,
+}
+
+impl<D, F: Fn(&Value)->Result<D, String>> CdcReader<BufReader<File>, D, F> {
+    /// Opens the file at `path`, whose lines hold transaction timestamps in `time_field` and whose
+    /// row images are read by `parse`.
+    pub fn open<P: AsRef<Path>>(path: P, time_field: &str, parse: F) -> io::Result<CdcReader<BufReader<File>, D, F>> {
+        let file = try!(File::open(path));
+        Ok(CdcReader::new(BufReader::new(file), time_field, parse))
+    }
+}
+
+impl<R: BufRead, D, F: Fn(&Value)->Result<D, String>> CdcReader<R, D, F> {
+
+    /// Constructs a new `CdcReader` reading lines from `reader`, whose lines hold transaction
+    /// timestamps in `time_field` and whose row images are read by `parse`.
+    pub fn new(reader: R, time_field: &str, parse: F) -> CdcReader<R, D, F> {
+        CdcReader {
+            reader: reader,
+            time_field: time_field.to_owned(),
+            parse: parse,
+            line: 0,
+            pending: VecDeque::new(),
+            last_time: 0,
+        }
+    }
+
+    /// Reads lines until one holds a change, and queues its differences. Returns false if the
+    /// reader has no more lines.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            if try!(self.reader.read_line(&mut text)) == 0 {
+                return Ok(false);
+            }
+            self.line += 1;
+            if text.trim().len() > 0 {
+                let line = self.line;
+                return self.change(&text)
+                           .map(|_| true)
+                           .map_err(|message| io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, message)));
+            }
+        }
+    }
+
+    /// Parses the change `text`, and queues its differences.
+    fn change(&mut self, text: &str) -> Result<(), String> {
+
+        let change = try!(json::parse(text));
+        let time = match change.get(&self.time_field).and_then(|x| x.as_u64()) {
+            Some(time) => time,
+            None => return Err(format!("expected an unsigned integer `{}`", self.time_field)),
+        };
+        if time < self.last_time {
+            return Err(format!("time {} is before the previous time {}", time, self.last_time));
+        }
+
+        let image = |name: &str| change.get(name).and_then(|x| if x.is_null() { None } else { Some(x) });
+        let before = match image("before") { Some(row) => Some(try!((self.parse)(row))), None => None };
+        let after = match image("after") { Some(row) => Some(try!((self.parse)(row))), None => None };
+        if before.is_none() && after.is_none() {
+            return Err("expected a `before` or `after` image".to_owned());
+        }
+
+        self.last_time = time;
+        if let Some(row) = before { self.pending.push_back((row, time, -1)); }
+        if let Some(row) = after { self.pending.push_back((row, time, 1)); }
+        Ok(())
+    }
+}
+
+impl<R: BufRead, D: Data, F: Fn(&Value)->Result<D, String>> CdcReader<R, D, F> {
+    /// Sends the differences of the current transaction to `input`, and advances `input` to the
+    /// time of the next transaction once it begins.
+    ///
+    /// Returns true having advanced the input, and false once the reader has no more lines, in
+    /// which case the input remains at the time of the last transaction and should be closed by
+    /// the caller when it is done. A transaction time less than the input's epoch is an error.
+    /// Reading from a pipe blocks until the next line, or the end of the data, arrives.
+    pub fn feed(&mut self, input: &mut Handle<u64, (D, i32)>) -> io::Result<bool> {
+        loop {
+            if self.pending.is_empty() && !try!(self.fill()) {
+                return Ok(false);
+            }
+            let time = self.pending.front().map(|x| x.1).unwrap();
+            let epoch = *input.epoch();
+            if time < epoch {
+                return Err(io::Error::new(ErrorKind::InvalidData, format!("time {} is before the input's epoch {}", time, epoch)));
+            }
+            if time > epoch {
+                input.advance_to(time);
+                return Ok(true);
+            }
+            let (row, _, wgt) = self.pending.pop_front().unwrap();
+            input.send((row, wgt));
+        }
+    }
+}
+
+impl<R: BufRead, D, F: Fn(&Value)->Result<D, String>> Iterator for CdcReader<R, D, F> {
+    type Item = io::Result<(D, u64, i32)>;
+
+    fn next(&mut self) -> Option<io::Result<(D, u64, i32)>> {
+        if self.pending.is_empty() {
+            match self.fill() {
+                Ok(true) => { },
+                Ok(false) => return None,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    use timely::{self, Configuration};
+    use timely::dataflow::Scope;
+    use timely::dataflow::operators::{Input, Inspect};
+
+    use super::CdcReader;
+    use input::json::Value;
+
+    #[test]
+    fn read_changes() {
+        let lines = "{\"before\": null, \"after\": {\"id\": 1}, \"ts\": 5}\n\
+                     \n\
+                     {\"before\": {\"id\": 1}, \"after\": {\"id\": 2}, \"ts\": 5}\n\
+                     {\"before\": {\"id\": 2}, \"ts\": 7}\n\
+                     {\"after\": {\"id\": 3}, \"ts\": 6}\n";
+
+        let parse = |row: &Value| row.get("id").and_then(|x| x.as_u64()).ok_or("missing id".to_owned());
+        let mut reader = CdcReader::new(Cursor::new(lines), "ts", parse);
+        assert_eq!(reader.next().unwrap().unwrap(), (1, 5, 1));
+        assert_eq!(reader.next().unwrap().unwrap(), (1, 5, -1));
+        assert_eq!(reader.next().unwrap().unwrap(), (2, 5, 1));
+        assert_eq!(reader.next().unwrap().unwrap(), (2, 7, -1));
+        assert!(reader.next().unwrap().unwrap_err().to_string().starts_with("line 5:"));
+    }
+
+    fn id(row: &Value) -> Result<u64, String> {
+        row.get("id").and_then(|x| x.as_u64()).ok_or("missing id".to_owned())
+    }
+
+    #[test]
+    fn feed_input() {
+        timely::execute(Configuration::Thread, |root| {
+
+            let updates = Rc::new(RefCell::new(Vec::new()));
+            let sink = updates.clone();
+            let mut input = root.scoped::<u64,_,_>(move |scope| {
+                let (input, stream) = scope.new_input::<(u64, i32)>();
+                stream.inspect_batch(move |time, data| {
+                    for &(record, delta) in data {
+                        sink.borrow_mut().push(((time.inner, record), delta));
+                    }
+                });
+                input
+            });
+
+            let lines = "{\"after\": {\"id\": 1}, \"ts\": 3}\n\
+                         {\"after\": {\"id\": 2}, \"ts\": 3}\n\
+                         {\"before\": {\"id\": 1}, \"ts\": 5}\n";
+            let mut reader = CdcReader::new(Cursor::new(lines), "ts", id);
+
+            // each new transaction advances the input; the end of the lines leaves it at the last.
+            assert!(reader.feed(&mut input).unwrap());
+            assert_eq!(*input.epoch(), 3);
+            assert!(reader.feed(&mut input).unwrap());
+            assert_eq!(*input.epoch(), 5);
+            assert!(!reader.feed(&mut input).unwrap());
+            assert_eq!(*input.epoch(), 5);
+            assert!(!reader.feed(&mut input).unwrap());
+
+            // a transaction before the input's epoch is refused, and sends nothing.
+            let mut late = CdcReader::new(Cursor::new("{\"after\": {\"id\": 4}, \"ts\": 4}\n"), "ts", id);
+            let error = late.feed(&mut input).unwrap_err();
+            assert_eq!(error.to_string(), "time 4 is before the input's epoch 5");
+
+            drop(input);
+            while root.step() { }
+
+            let mut updates = ::std::mem::replace(&mut *updates.borrow_mut(), Vec::new());
+            updates.sort();
+            assert_eq!(updates, vec![((3, 1), 1), ((3, 2), 1), ((5, 1), -1)]);
+        });
+    }
+}
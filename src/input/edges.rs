@@ -0,0 +1,278 @@
+//! Loading graphs from binary edge files.
+//!
+//! An edge file is a flat sequence of `(src, dst)` pairs, each a little-endian `u32`, with no
+//! header or other framing. This is the format produced by most of the graph preprocessing tools
+//! we use, and it has the appealing property that the `i`th edge lives at byte offset `8 * i`.
+//!
+//! Each worker reads a disjoint contiguous range of edges, determined by its index and the number
+//! of peers, so that the file is read exactly once in total and no worker needs to discard the
+//! edges of others. The edges are then introduced at the initial time through an input, a bounded
+//! number at a time so that no worker holds its whole range in memory, and it is up to the `join`
+//! and `group` operators downstream to exchange them to the workers responsible for their keys.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! timely::execute_from_args(std::env::args().skip(2), move |computation| {
+//!     let filename = std::env::args().nth(1).unwrap();
+//!     let mut input = computation.scoped::<u64,_,_>(|scope| {
+//!         let (input, edges) = load_edges(scope, &filename).unwrap();
+//!         edges.group_u(|_, s, t| t.push((s.count() as u32, 1)))
+//!              .inspect(|x| println!("degree: {:?}", x));
+//!         input
+//!     });
+//!     while input.feed().unwrap() { computation.step(); }
+//! });
+//! ```
+
+use std::io::{Read, Seek, SeekFrom, BufReader};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use timely::progress::Timestamp;
+use timely::dataflow::Scope;
+use timely::dataflow::scopes::{Child, Root};
+use timely::dataflow::operators::Input;
+use timely::dataflow::operators::input::Handle;
+use timely_communication::Allocate;
+
+use ::Collection;
+
+/// The number of bytes used to represent each edge.
+const EDGE_BYTES: u64 = 8;
+
+/// The number of edges to read from the underlying reader at a time.
+const BUFFER_EDGES: usize = 1 << 16;
+
+/// Reads `(u32, u32)` edges from a source of little-endian binary data.
+///
+/// An `EdgeReader` yields at most a fixed number of edges, which lets several readers share one
+/// file by starting at different offsets. It stops early if the underlying reader runs out of data,
+/// discarding a truncated trailing edge, and yields any error the underlying reader reports, after
+/// which it yields nothing further.
+pub struct EdgeReader<R: Read> {
+    reader: R,
+    buffer: Vec<u8>,
+    offset: usize,
+    valid: usize,
+    remaining: u64,
+}
+
+impl EdgeReader<BufReader<File>> {
+    /// Opens the edge file at `path` for the `index`th of `peers` workers.
+    ///
+    /// The reader is positioned at the start of the worker's range of edges, and will yield only
+    /// the edges in that range.
+    pub fn open<P: AsRef<Path>>(path: P, index: usize, peers: usize) -> ::std::io::Result<EdgeReader<BufReader<File>>> {
+        let mut file = try!(File::open(path));
+        let edges = try!(file.metadata()).len() / EDGE_BYTES;
+        let (lower, upper) = shard(edges, index, peers);
+        try!(file.seek(SeekFrom::Start(lower * EDGE_BYTES)));
+        Ok(EdgeReader::new(BufReader::new(file), upper - lower))
+    }
+}
+
+impl<R: Read> EdgeReader<R> {
+    /// Constructs a new `EdgeReader` yielding at most `edges` edges from `reader`.
+    pub fn new(reader: R, edges: u64) -> EdgeReader<R> {
+        EdgeReader {
+            reader: reader,
+            buffer: vec![0u8; BUFFER_EDGES * EDGE_BYTES as usize],
+            offset: 0,
+            valid: 0,
+            remaining: edges,
+        }
+    }
+
+    /// Refills `self.buffer` with as many whole edges as the reader will provide, up to the number
+    /// of edges remaining. Returns false if no complete edge could be read, and an error if the
+    /// reader reports one.
+    fn refill(&mut self) -> ::std::io::Result<bool> {
+
+        // move any partially read edge to the front of the buffer.
+        let leftover = self.valid - self.offset;
+        for i in 0 .. leftover {
+            self.buffer[i] = self.buffer[self.offset + i];
+        }
+        self.offset = 0;
+        self.valid = leftover;
+
+        let wanted = ::std::cmp::min(self.buffer.len() as u64, self.remaining * EDGE_BYTES) as usize;
+        while self.valid < wanted {
+            match self.reader.read(&mut self.buffer[self.valid .. wanted]) {
+                Ok(0)     => break,
+                Ok(read)  => self.valid += read,
+                Err(ref error) if error.kind() == ::std::io::ErrorKind::Interrupted => { },
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(self.valid >= EDGE_BYTES as usize)
+    }
+}
+
+impl<R: Read> Iterator for EdgeReader<R> {
+    type Item = ::std::io::Result<(u32, u32)>;
+
+    #[inline]
+    fn next(&mut self) -> Option<::std::io::Result<(u32, u32)>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        if self.valid - self.offset < EDGE_BYTES as usize {
+            match self.refill() {
+                Ok(true) => { },
+                Ok(false) => { self.remaining = 0; return None; },
+                Err(error) => { self.remaining = 0; return Some(Err(error)); },
+            }
+        }
+
+        let src = LittleEndian::read_u32(&self.buffer[self.offset ..]);
+        let dst = LittleEndian::read_u32(&self.buffer[self.offset + 4 ..]);
+        self.offset += EDGE_BYTES as usize;
+        self.remaining -= 1;
+        Some(Ok((src, dst)))
+    }
+}
+
+/// Determines the range `[lower, upper)` of `edges` the `index`th of `peers` workers should read.
+///
+/// The ranges are contiguous, disjoint, and cover all edges; their sizes differ by at most one.
+pub fn shard(edges: u64, index: usize, peers: usize) -> (u64, u64) {
+    assert!(index < peers);
+    let lower = (edges * index as u64) / peers as u64;
+    let upper = (edges * (index + 1) as u64) / peers as u64;
+    (lower, upper)
+}
+
+/// Creates a collection at the initial time for this worker's share of the edges in the file at
+/// `path`, and the `EdgeInput` that loads them into it.
+///
+/// Each edge is introduced with weight one. Duplicate edges in the file are not coalesced here,
+/// and will simply be reflected in the edge's multiplicity. An error opening the file is reported
+/// here, and an error reading it by `EdgeInput::feed`.
+pub fn load_edges<A: Allocate, T: Timestamp+Ord, P: AsRef<Path>>(scope: &mut Child<Root<A>, T>, path: P)
+    -> ::std::io::Result<(EdgeInput<BufReader<File>, T>, Collection<Child<Root<A>, T>, (u32, u32)>)> {
+    let reader = try!(EdgeReader::open(path, scope.index(), scope.peers()));
+    let (input, stream) = scope.new_input();
+    Ok((EdgeInput { reader: reader, input: input }, Collection::new(stream)))
+}
+
+/// Sends the edges of an `EdgeReader` to an input at its initial time, a bounded number at a time.
+///
+/// The input is closed when the `EdgeInput` is dropped.
+pub struct EdgeInput<R: Read, T: Timestamp+Ord> {
+    reader: EdgeReader<R>,
+    input: Handle<T, ((u32, u32), i32)>,
+}
+
+impl<R: Read, T: Timestamp+Ord> EdgeInput<R, T> {
+    /// Sends at most `BUFFER_EDGES` edges to the input.
+    ///
+    /// Returns true if there may be more edges to send, in which case the caller should step the
+    /// computation, so that it can take the edges sent, before calling `feed` again. Returns false
+    /// once all edges have been sent, and an error if reading them fails, after which no further
+    /// edges are sent.
+    pub fn feed(&mut self) -> ::std::io::Result<bool> {
+        for _ in 0 .. BUFFER_EDGES {
+            match self.reader.next() {
+                Some(edge) => self.input.send((try!(edge), 1)),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use timely::{self, Configuration};
+    use timely::dataflow::operators::Inspect;
+
+    use super::{EdgeReader, BUFFER_EDGES, load_edges, shard};
+
+    #[test]
+    fn shard_partitions() {
+        for &edges in &[0, 1, 7, 100, 101] {
+            for peers in 1 .. 6 {
+                let mut next = 0;
+                for index in 0 .. peers {
+                    let (lower, upper) = shard(edges, index, peers);
+                    assert_eq!(lower, next);
+                    assert!(upper - lower <= edges / peers as u64 + 1);
+                    next = upper;
+                }
+                assert_eq!(next, edges);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_edge() {
+        let mut bytes = vec![1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0];
+        bytes.extend_from_slice(&[5, 0, 0, 0, 6]);
+        let edges = EdgeReader::new(Cursor::new(bytes), 10).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(edges, vec![(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn read_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "disk on fire"))
+            }
+        }
+        let mut reader = EdgeReader::new(Failing, 10);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn load_in_chunks() {
+        let path = ::std::env::temp_dir().join(format!("differential-edges-{}", ::time::precise_time_ns()));
+        let edges = (0 .. BUFFER_EDGES as u32 + 5).map(|x| (x, x + 1)).collect::<Vec<_>>();
+        let mut bytes = vec![0u8; 8 * edges.len()];
+        for (index, &(src, dst)) in edges.iter().enumerate() {
+            LittleEndian::write_u32(&mut bytes[8 * index ..], src);
+            LittleEndian::write_u32(&mut bytes[8 * index + 4 ..], dst);
+        }
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+
+        let file = path.clone();
+        timely::execute(Configuration::Thread, move |root| {
+
+            let received = Rc::new(RefCell::new(Vec::new()));
+            let sink = received.clone();
+            let mut input = root.scoped::<u64,_,_>(|scope| {
+                let (input, edges) = load_edges(scope, &file).unwrap();
+                edges.inner.inspect(move |x| sink.borrow_mut().push(x.clone()));
+                input
+            });
+
+            // a full chunk, and then the remaining five edges.
+            assert!(input.feed().unwrap());
+            root.step();
+            assert!(!input.feed().unwrap());
+            drop(input);
+            while root.step() { }
+
+            let mut received = ::std::mem::replace(&mut *received.borrow_mut(), Vec::new());
+            received.sort();
+            assert_eq!(received, (0 .. BUFFER_EDGES as u32 + 5).map(|x| ((x, x + 1), 1)).collect::<Vec<_>>());
+        });
+
+        fs::remove_file(&path).unwrap();
+    }
+}
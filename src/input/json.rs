@@ -0,0 +1,280 @@
+//! A small parser for JSON values.
+//!
+//! The input formats in this module exchange records as JSON, but only ever need to pick a few
+//! fields out of each line, so rather than depend on a serialization framework we parse each line
+//! into a `Value` and let the caller extract what it wants. Numbers are kept as the text they were
+//! written as, and converted on request, so that large integers such as timestamps lose nothing.
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// `null`.
+    Null,
+    /// `true` or `false`.
+    Bool(bool),
+    /// A number, as written.
+    Number(String),
+    /// A string, with escapes resolved.
+    String(String),
+    /// An array of values.
+    Array(Vec<Value>),
+    /// An object, as its fields in the order written.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The value of the field `name`, if this is an object with such a field.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref fields) => fields.iter().find(|x| x.0 == name).map(|x| &x.1),
+            _ => None,
+        }
+    }
+    /// Reports whether this is `null`.
+    pub fn is_null(&self) -> bool {
+        match *self { Value::Null => true, _ => false }
+    }
+    /// The boolean, if this is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self { Value::Bool(b) => Some(b), _ => None }
+    }
+    /// The string, if this is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self { Value::String(ref s) => Some(&s[..]), _ => None }
+    }
+    /// The number, if this is a number representable as a `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self { Value::Number(ref n) => n.parse().ok(), _ => None }
+    }
+    /// The number, if this is a number representable as an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self { Value::Number(ref n) => n.parse().ok(), _ => None }
+    }
+    /// The number, if this is a number, as the nearest `f64`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self { Value::Number(ref n) => n.parse().ok(), _ => None }
+    }
+    /// The elements, if this is an array.
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self { Value::Array(ref a) => Some(&a[..]), _ => None }
+    }
+}
+
+/// The greatest depth of nested arrays and objects `parse` accepts.
+pub const MAX_DEPTH: usize = 128;
+
+/// Parses `text` as a single JSON value, surrounded by nothing other than whitespace.
+///
+/// Arrays and objects nested more than `MAX_DEPTH` deep are an error, rather than a risk of
+/// exhausting the stack.
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { bytes: text.as_bytes(), position: 0, depth: 0 };
+    let value = try!(parser.value());
+    parser.whitespace();
+    if parser.position < parser.bytes.len() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    depth: usize,   // the number of arrays and objects enclosing the position.
+}
+
+impl<'a> Parser<'a> {
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at offset {}", message, self.position)
+    }
+
+    fn whitespace(&mut self) {
+        while self.position < self.bytes.len() && (self.bytes[self.position] as char).is_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).map(|&x| x)
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.bytes[self.position..].starts_with(literal.as_bytes()) {
+            self.position += literal.len();
+            Ok(())
+        }
+        else {
+            Err(self.error(&format!("expected `{}`", literal)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'[') | Some(b'{') if self.depth == MAX_DEPTH => Err(self.error("nesting too deep")),
+            Some(b'n') => { try!(self.expect("null")); Ok(Value::Null) },
+            Some(b't') => { try!(self.expect("true")); Ok(Value::Bool(true)) },
+            Some(b'f') => { try!(self.expect("false")); Ok(Value::Bool(false)) },
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => { self.depth += 1; let result = self.array(); self.depth -= 1; result },
+            Some(b'{') => { self.depth += 1; let result = self.object(); self.depth -= 1; result },
+            Some(x) if x == b'-' || (x >= b'0' && x <= b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        if self.peek() == Some(b'-') { self.position += 1; }
+        let digits = self.digits();
+        if digits == 0 { return Err(self.error("expected digits")); }
+        if digits > 1 && self.bytes[self.position - digits] == b'0' {
+            self.position -= digits;
+            return Err(self.error("leading zero"));
+        }
+        if self.peek() == Some(b'.') {
+            self.position += 1;
+            if self.digits() == 0 { return Err(self.error("expected digits")); }
+        }
+        if self.peek() == Some(b'e') || self.peek() == Some(b'E') {
+            self.position += 1;
+            if self.peek() == Some(b'+') || self.peek() == Some(b'-') { self.position += 1; }
+            if self.digits() == 0 { return Err(self.error("expected digits")); }
+        }
+        // the bytes are all ASCII, so this cannot fail.
+        Ok(Value::Number(String::from_utf8(self.bytes[start .. self.position].to_vec()).unwrap()))
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.position;
+        while self.peek().map(|x| x >= b'0' && x <= b'9').unwrap_or(false) {
+            self.position += 1;
+        }
+        self.position - start
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.expect("\""));
+        let mut result = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => { self.position += 1; break; },
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.position += 1;
+                            let mut code = try!(self.hex());
+                            // a high surrogate must be followed by an escaped low surrogate.
+                            if code >= 0xD800 && code < 0xDC00 {
+                                try!(self.expect("\\u"));
+                                let low = try!(self.hex());
+                                if low < 0xDC00 || low >= 0xE000 { return Err(self.error("invalid surrogate pair")); }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            self.position -= 1;
+                            try!(::std::char::from_u32(code).ok_or_else(|| self.error("invalid escape")))
+                        },
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.position += 1;
+                    let mut buffer = [0u8; 4];
+                    result.extend_from_slice(escaped.encode_utf8(&mut buffer).as_bytes());
+                },
+                Some(x) if x < 0x20 => return Err(self.error("unescaped control character")),
+                Some(x) => { result.push(x); self.position += 1; },
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+        // the input was a `str`, and we only split it at ASCII characters.
+        String::from_utf8(result).map_err(|_| self.error("invalid string"))
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        if self.position + 4 > self.bytes.len() { return Err(self.error("unexpected end of input")); }
+        let text = try!(::std::str::from_utf8(&self.bytes[self.position .. self.position + 4]).map_err(|_| self.error("invalid escape")));
+        let code = try!(u32::from_str_radix(text, 16).map_err(|_| self.error("invalid escape")));
+        self.position += 4;
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        try!(self.expect("["));
+        let mut elements = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(try!(self.value()));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => { self.position += 1; return Ok(Value::Array(elements)); },
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        try!(self.expect("{"));
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let name = try!(self.string());
+            self.whitespace();
+            try!(self.expect(":"));
+            fields.push((name, try!(self.value())));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => { self.position += 1; return Ok(Value::Object(fields)); },
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{parse, Value, MAX_DEPTH};
+
+    #[test]
+    fn parse_values() {
+        let value = parse(r#" {"id": 12345678901234567, "name": "a\"bé😀", "tags": [true, null, -1.5e3], "empty": {}} "#).unwrap();
+        assert_eq!(value.get("id").and_then(|x| x.as_u64()), Some(12345678901234567));
+        assert_eq!(value.get("name").and_then(|x| x.as_str()), Some("a\"b\u{e9}\u{1F600}"));
+        assert_eq!(value.get("tags"), Some(&Value::Array(vec![Value::Bool(true), Value::Null, Value::Number("-1.5e3".to_owned())])));
+        assert_eq!(value.get("empty"), Some(&Value::Object(vec![])));
+        assert_eq!(value.get("missing"), None);
+
+        assert!(parse("{\"a\": 1,}").is_err());
+        assert!(parse("[1 2]").is_err());
+        assert!(parse("\"open").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("01").is_err());
+        assert!(parse("-00").is_err());
+        assert_eq!(parse("-0.5"), Ok(Value::Number("-0.5".to_owned())));
+
+        let nested = |depth| (0 .. depth).map(|_| "[").chain((0 .. depth).map(|_| "]")).collect::<String>();
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(parse(&nested(MAX_DEPTH + 1)), Err(format!("nesting too deep at offset {}", MAX_DEPTH)));
+    }
+}
@@ -0,0 +1,14 @@
+//! Sources of differential dataflow collections from outside the computation.
+//!
+//! The examples mostly generate their inputs in-process, which is fine for experimentation but
+//! not how real data arrive. The modules here read data from external formats and introduce them
+//! as `Collection`s, taking care of partitioning the work among the workers of the computation.
+
+pub mod edges;
+pub mod json;
+pub mod cdc;
+pub mod socket;
+
+pub use self::edges::{EdgeReader, EdgeInput, load_edges};
+pub use self::cdc::CdcReader;
+pub use self::socket::SocketSource;
@@ -0,0 +1,216 @@
+//! Receiving updates from other local processes over a socket.
+//!
+//! A `SocketSource` listens on a loopback TCP address or a Unix socket, and applies the commands it
+//! receives to a timely input, so that a separate process can feed a long-running computation, for
+//! demonstrations or integration tests. Like the `server`, it is not a thread of its own: the worker
+//! calls `poll` between steps of the computation, which accepts connections, reads commands, and
+//! applies them without blocking.
+//!
+//! The protocol is line oriented. Each command is one of
+//!
+//! * `+ <record>`, which introduces the record with weight `+1` at the input's current epoch,
+//! * `- <record>`, which introduces the record with weight `-1` at the input's current epoch,
+//! * `advance <time>`, which advances the input to `time`, parsed with `FromStr`,
+//! * `close`, which indicates that no further commands will be sent.
+//!
+//! Records are parsed from the text following the sign by a closure supplied to the source, so
+//! that `+ 3 5` might introduce the edge `(3, 5)`. Each command is answered with a line `ok`, or a
+//! line `error <message>` if it could not be applied, in which case it has no effect. Commands
+//! from one connection are applied in the order sent; commands from different connections are
+//! interleaved in no particular order.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let (mut input, probe) = computation.scoped::<u64,_,_>(|scope| { ... });
+//! let parse = |text: &str| {
+//!     let mut words = text.split_whitespace().map(|x| x.parse::<u32>());
+//!     match (words.next(), words.next(), words.next()) {
+//!         (Some(Ok(src)), Some(Ok(dst)), None) => Ok((src, dst)),
+//!         _ => Err("expected `<src> <dst>`".to_owned()),
+//!     }
+//! };
+//! let mut source = SocketSource::bind_tcp("127.0.0.1:7001", parse).unwrap();
+//! while source.poll(&mut input).unwrap() {
+//!     let epoch = *input.epoch();
+//!     if probe.lt(&RootTimestamp::new(epoch)) { computation.step(); }
+//!     else { std::thread::sleep(std::time::Duration::from_millis(1)); }
+//! }
+//! ```
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::str::FromStr;
+
+use timely::progress::Timestamp;
+use timely::dataflow::operators::input::Handle;
+
+use ::Data;
+use socket::{Listener, Connection};
+
+/// Applies commands received from local processes to a timely input.
+pub struct SocketSource<D, F: Fn(&str)->Result<D, String>> {
+    listener: Listener,
+    connections: Vec<Connection>,
+    parse: F,
+    closed: bool,
+}
+
+impl<D: Data, F: Fn(&str)->Result<D, String>> SocketSource<D, F> {
+
+    /// Listens at `address`, which must be a loopback address, parsing records with `parse`.
+    pub fn bind_tcp<A: ToSocketAddrs>(address: A, parse: F) -> io::Result<SocketSource<D, F>> {
+        let listener = try!(Listener::bind_tcp(address));
+        Ok(SocketSource::new(listener, parse))
+    }
+
+    /// Listens at a Unix socket created at `path`, parsing records with `parse`.
+    ///
+    /// A socket left at `path` by an earlier process is replaced; any other file is an error. The
+    /// socket is removed when the source is dropped.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P, parse: F) -> io::Result<SocketSource<D, F>> {
+        let listener = try!(Listener::bind_unix(path));
+        Ok(SocketSource::new(listener, parse))
+    }
+
+    fn new(listener: Listener, parse: F) -> SocketSource<D, F> {
+        SocketSource {
+            listener: listener,
+            connections: Vec::new(),
+            parse: parse,
+            closed: false,
+        }
+    }
+
+    /// The address the source listens at, if it listens on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> { self.listener.local_addr() }
+
+    /// Accepts connections, reads commands, and applies them to `input`, without blocking.
+    ///
+    /// Returns false once a client has sent `close`, after which the caller should close the input;
+    /// later commands are refused. Returns an error only if accepting connections fails; failed
+    /// connections are dropped.
+    pub fn poll<T: Timestamp+Ord+FromStr>(&mut self, input: &mut Handle<T, (D, i32)>) -> io::Result<bool> {
+
+        try!(self.listener.accept(&mut self.connections));
+
+        for connection in self.connections.iter_mut() {
+            connection.read();
+            while let Some(command) = connection.lines.pop_front() {
+                let reply = match apply(&self.parse, &command, input, &mut self.closed) {
+                    Ok(()) => "ok\n".to_owned(),
+                    Err(message) => format!("error {}\n", message),
+                };
+                connection.output.extend_from_slice(reply.as_bytes());
+            }
+            connection.write();
+        }
+
+        self.connections.retain(|x| !x.done());
+        Ok(!self.closed)
+    }
+}
+
+// applies `command` to `input`, parsing records with `parse`.
+fn apply<D, F, T>(parse: &F, command: &str, input: &mut Handle<T, (D, i32)>, closed: &mut bool) -> Result<(), String>
+where D: Data, F: Fn(&str)->Result<D, String>, T: Timestamp+Ord+FromStr {
+
+    if *closed {
+        return Err("input closed".to_owned());
+    }
+
+    let (verb, rest) = match command.find(char::is_whitespace) {
+        Some(position) => (&command[.. position], command[position ..].trim()),
+        None => (command, ""),
+    };
+
+    match verb {
+        "+" => { input.send((try!(parse(rest)), 1)); Ok(()) },
+        "-" => { input.send((try!(parse(rest)), -1)); Ok(()) },
+        "advance" => {
+            let time = try!(rest.parse::<T>().map_err(|_| "invalid time".to_owned()));
+            if time > *input.epoch() {
+                input.advance_to(time);
+                Ok(())
+            }
+            else {
+                Err("time must be greater than the current epoch".to_owned())
+            }
+        },
+        "close" if rest.len() == 0 => { *closed = true; Ok(()) },
+        _ => Err("expected `+ <record>`, `- <record>`, `advance <time>`, or `close`".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::io::{Write, BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use timely::{self, Configuration};
+    use timely::dataflow::Scope;
+    use timely::dataflow::operators::{Input, Inspect};
+
+    use testing::accumulate;
+    use super::SocketSource;
+
+    #[test]
+    fn apply_commands() {
+        timely::execute(Configuration::Thread, |root| {
+
+            let updates = Rc::new(RefCell::new(Vec::new()));
+            let sink = updates.clone();
+            let mut input = root.scoped::<u64,_,_>(move |scope| {
+                let (input, stream) = scope.new_input::<(u64, i32)>();
+                stream.inspect_batch(move |time, data| {
+                    for &(record, delta) in data {
+                        sink.borrow_mut().push(((time.inner, record), delta));
+                    }
+                });
+                input
+            });
+
+            let parse = |text: &str| text.parse::<u64>().map_err(|_| "invalid record".to_owned());
+            let mut source = SocketSource::bind_tcp("127.0.0.1:0", parse).unwrap();
+            let client = TcpStream::connect(source.local_addr().unwrap()).unwrap();
+            (&client).write_all(b"+ 1\n+ 2\n- 1\nadvance 1\n+ x\n+ 3\nadvance 1\nadvance 0\n- 2\nadvance 2\nfoo\nclose\n+ 4\nadvance 5\n").unwrap();
+
+            // apply commands until the client closes the input, and then any sent after that.
+            let mut polls = 0;
+            while source.poll(&mut input).unwrap() && polls < 1000 {
+                ::std::thread::sleep(Duration::from_millis(1));
+                polls += 1;
+            }
+            for _ in 0 .. 10 {
+                source.poll(&mut input).unwrap();
+                ::std::thread::sleep(Duration::from_millis(1));
+            }
+
+            let replies = BufReader::new(&client).lines().take(14).map(|x| x.unwrap()).collect::<Vec<_>>();
+            assert_eq!(replies, vec![
+                "ok", "ok", "ok", "ok",
+                "error invalid record", "ok",
+                "error time must be greater than the current epoch",
+                "error time must be greater than the current epoch",
+                "ok", "ok",
+                "error expected `+ <record>`, `- <record>`, `advance <time>`, or `close`",
+                "ok", "error input closed", "error input closed",
+            ]);
+
+            drop(input);
+            while root.step() { }
+
+            // the refused commands have no effect: nothing at time 2 or later, nor any `x` or `4`.
+            let updates = ::std::mem::replace(&mut *updates.borrow_mut(), Vec::new());
+            let expected = vec![((0, 2), 1), ((1, 2), -1), ((1, 3), 1)];
+            assert_eq!(accumulate(updates.into_iter()).into_iter().collect::<Vec<_>>(), expected);
+        });
+    }
+}
@@ -1,3 +1,3 @@
 pub mod merge;
 pub mod coalesce;
-// pub mod run_length;
+pub mod run_length;
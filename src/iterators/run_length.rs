@@ -1,135 +1,199 @@
-pub struct WeightDecoder<I: Iterator<Item=i32>> {
-    items: Peekable<I>,
+//! Run-length encoded sequences with efficient access from arbitrary positions.
+//!
+//! Many of the sequences we store repeat themselves: weights are almost always `1` for inputs that
+//! are sets (edge lists, for example), and values may repeat across adjacent keys. A `RunLength`
+//! stores each maximal run of equal items once, along with the position at which the run ends.
+//!
+//! Unlike encodings that interleave counts with items, recording run ends allows us to start
+//! decoding at any position with a binary search, which is what a `Trace` needs when it reads the
+//! differences of a single key from the middle of a time's values.
+
+use collection::{OffsetInt, to_offset};
+
+/// A run-length encoded sequence of items.
+///
+/// Run ends are `OffsetInt`s, so a sequence may hold as many items as a trace may hold values for
+/// one time; pushing more panics rather than wrapping around.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunLength<T> {
+    items: Vec<T>,
+    ends: Vec<OffsetInt>,
 }
 
-impl<I: Iterator<Item=i32>> Iterator for WeightDecoder {
-    type Item=(i32, usize);
-    fn next(&mut self) -> Option<(i32, usize)> {
-        self.items.next().map(|item| {
-            let count = if let Some(next) = self.items.peek() {
-                if item == next {
-                    self.items.next();
-                    self.items.next().unwrap() as usize
+impl<T: Eq> RunLength<T> {
+    /// Constructs a new empty `RunLength`.
+    pub fn new() -> RunLength<T> {
+        RunLength { items: Vec::new(), ends: Vec::new() }
+    }
+    /// Appends `item` to the sequence, extending the final run if it is equal to `item`.
+    #[inline]
+    pub fn push(&mut self, item: T) {
+        let len = to_offset(self.len() + 1);
+        if self.items.last() == Some(&item) {
+            let last = self.ends.len() - 1;
+            self.ends[last] = len;
+        }
+        else {
+            self.items.push(item);
+            self.ends.push(len);
+        }
+    }
+    /// Retains only the items at positions within `ranges`, which must be sorted and disjoint.
+    ///
+    /// The retained items of each run are adjacent in the result, so no item need be copied.
+    pub fn retain_ranges(&mut self, ranges: &[(usize, usize)]) {
+        let items = ::std::mem::replace(&mut self.items, Vec::new());
+        let ends = ::std::mem::replace(&mut self.ends, Vec::new());
+        let mut start = 0;
+        let mut range = 0;
+        let mut len = 0;
+        for (item, end) in items.into_iter().zip(ends.into_iter()) {
+            let end = end as usize;
+            // count the positions of the run `start .. end` within ranges; each range remaining ends
+            // after `start`.
+            let mut kept = 0;
+            while range < ranges.len() && ranges[range].0 < end {
+                kept += ::std::cmp::min(ranges[range].1, end) - ::std::cmp::max(ranges[range].0, start);
+                if ranges[range].1 <= end { range += 1; } else { break; }
+            }
+            if kept > 0 {
+                len += kept;
+                if self.items.last() == Some(&item) {
+                    let last = self.ends.len() - 1;
+                    self.ends[last] = to_offset(len);
+                }
+                else {
+                    self.items.push(item);
+                    self.ends.push(to_offset(len));
                 }
-                else { 1 }
             }
-            else { 1 };
-            Some((item, count))
-        })
+            start = end;
+        }
     }
-}
-
-pub struct WeightEncoder<'a> {
-    items: &'a mut Vec<i32>,
-    count: i32,
-}
-
-impl<'a> WeightEncoder<'a> {
-    pub fn push(&mut self, value: i32) {
-        let len = self.items.len();
-        if self.count == 0 || self.items[len - 1] != value {
-            if self.count > 1 {
-                // repeat last element, push count
-                let last = self.items[len - 1];
-                self.items.push(last);
-                self.items.push(count);
+    /// Counts the runs in a sequence of items, without encoding them.
+    ///
+    /// This is useful for determining whether encoding would save space before doing so.
+    pub fn count_runs<'a, I: Iterator<Item=&'a T>>(iterator: I) -> usize where T: 'a {
+        let mut runs = 0;
+        let mut last = None;
+        for item in iterator {
+            if last != Some(item) {
+                runs += 1;
+                last = Some(item);
             }
-
-            // push value, reset count
-            self.items.push(value);
-            self.count = 0;
         }
+        runs
+    }
+}
 
-        self.count += 1;
+impl<T> RunLength<T> {
+    /// The number of items in the decoded sequence.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ends.last().map(|&x| x as usize).unwrap_or(0)
+    }
+    /// The number of runs in the encoded sequence.
+    #[inline]
+    pub fn runs(&self) -> usize { self.items.len() }
+    /// Reports the size in bytes of the encoded sequence.
+    pub fn size(&self) -> usize {
+        self.items.len() * ::std::mem::size_of::<T>() + self.ends.len() * ::std::mem::size_of::<OffsetInt>()
     }
-    pub fn done(self) {
-        if self.count > 1 {
-            // repeat last element, push count
-            let last = self.items[len - 1];
-            self.items.push(last);
-            self.items.push(count);
+    /// Releases any excess capacity.
+    pub fn shrink_to_fit(&mut self) {
+        self.items.shrink_to_fit();
+        self.ends.shrink_to_fit();
+    }
+    /// Enumerates the items of the decoded sequence starting from position `index`.
+    #[inline]
+    pub fn iter_from<'a>(&'a self, index: usize) -> RunLengthIterator<'a, T> {
+        // the first run whose end exceeds `index` contains it.
+        // an index past every run end lies beyond the last run.
+        if index >= self.len() {
+            return RunLengthIterator { runs: self, run: self.ends.len(), index: index };
+        }
+        let run = match self.ends.binary_search(&to_offset(index)) {
+            Ok(run) => run + 1,
+            Err(run) => run,
+        };
+        RunLengthIterator {
+            runs: self,
+            run: run,
+            index: index,
         }
     }
+    /// Enumerates the items of the decoded sequence.
+    pub fn iter<'a>(&'a self) -> RunLengthIterator<'a, T> { self.iter_from(0) }
 }
 
-
-/// A run-length decoder, given an iterator over items and an iterator over counts, notices repeats
-/// in the items and yields (item, usize) pairs indicating the intended multiplicity.
-pub struct Decoder<I1: Iterator, I2: Iterator<Item=usize>> where I1::Item: Eq {
-    items: Peekable<I1>,
-    counts: I2,
+/// Enumerates the items of a `RunLength` sequence.
+pub struct RunLengthIterator<'a, T: 'a> {
+    runs: &'a RunLength<T>,
+    run: usize,     // index of the run containing `index`
+    index: usize,   // position in the decoded sequence
 }
 
-impl<I1: Iterator, I2: Iterator<Item=usize>> Iterator for Decoder<I1, I2> where I1::Item: Eq {
-    type Item=(I1::Item, usize);
-    fn next(&mut self) -> Option<(I1::Item, usize)> {
-        self.items.next().map(|item| {
-            if &item == self.items.peek() {
-                // drop next item
-                self.items.next();
-                Some((item, self.counts.next().unwrap()))
-            }
-            else {
-                Some((item, 1))
-            }
-        })
+impl<'a, T: 'a> Clone for RunLengthIterator<'a, T> {
+    fn clone(&self) -> Self {
+        RunLengthIterator {
+            runs: self.runs,
+            run: self.run,
+            index: self.index,
+        }
     }
 }
 
-// repetitions in items indicate a meaningful number of repetitions in counts.
-pub struct Encoder<T> {
-    items: Vec<T>,
-    counts: Vec<usize>,
-}
-
-impl<T: Eq> Encoder {
-    pub fn new() -> Encoder<T> { Encoder { items: vec![], counts: vec![] } }
-    pub fn push(&mut self, item: T) {
-        // if there is a previous equivalent item, just increment the last count.
-        if self.items.len() > 0 && item == self.items[self.items.len() - 1] {
-            let counts_len = self.counts.len();
-            // if this is the first repetition, we need to signal that by repeating the element.
-            if self.counts[counts_len - 1] == 1 {
-                self.items.push(item);
+impl<'a, T: 'a> Iterator for RunLengthIterator<'a, T> {
+    type Item = &'a T;
+    #[inline]
+    fn next(&mut self) -> Option<&'a T> {
+        if self.run < self.runs.ends.len() {
+            let result = &self.runs.items[self.run];
+            self.index += 1;
+            if self.index == self.runs.ends[self.run] as usize {
+                self.run += 1;
             }
-            self.counts[counts_len - 1] += 1;
+            Some(result)
         }
-        else {
-            self.items.push(item);
-            // if the previous count is > 1, push a new one to work with.
-            // otherwise, just keep that previous one there and hope someone increments it.
-            if self.counts.len() > 0 && self.counts[self.counts.len() - 1] > 1 {
-                self.counts.push(1);
-            }
-        }
-    }
-    pub fn done(self) -> (Vec<T>, Vec<usize>) {
-        // pretty harmless to leave the one there, but might as well clean up.
-        if self.counts.len() > 0 && self.counts[self.counts.len() - 1] == 1 {
-            self.counts.pop();
-        }
-        (self.items, self.counts)
+        else { None }
     }
-    pub fn decode(self) -> Decoder
 }
 
 #[cfg(test)]
 mod tests {
 
+    use super::RunLength;
+
     #[test] fn distinct() { encode_decode(vec![0, 1, 2, 3, 4]); }
     #[test] fn sequence() { encode_decode(vec![0,0,0,0, 1, 2, 2, 2, 0, 0, 3, 4]); }
     #[test] fn repeats() { encode_decode(vec![0,0,0,0]); }
     #[test] fn empty() { encode_decode(vec![]); }
 
-    fn encode_decode<T:Eq+Clone>(items: Vec<T>) {
-        let mut encoder = Encoder::new();
+    #[test]
+    fn retain() {
+        let items = vec![0,0,0,0, 1, 2, 2, 2, 0, 0, 3, 4];
+        for ranges in &[vec![], vec![(0, 12)], vec![(0, 2), (3, 5), (9, 11)], vec![(1, 2), (8, 9)], vec![(4, 5), (10, 12)]] {
+            let mut runs = RunLength::new();
+            for item in &items { runs.push(item.clone()); }
+            runs.retain_ranges(&ranges[..]);
+            let mut expected = Vec::new();
+            for &(lower, upper) in ranges { expected.extend_from_slice(&items[lower .. upper]); }
+            assert!(runs.iter().cloned().collect::<Vec<_>>() == expected);
+            assert_eq!(runs.runs(), RunLength::count_runs(expected.iter()));
+        }
+    }
+
+    fn encode_decode(items: Vec<i32>) {
+        let mut runs = RunLength::new();
         for item in &items {
-            encoder.push(item.clone());
+            runs.push(item.clone());
         }
 
-        let (i, c) = encode.done();
-        let mut decoder = Decoder { items: i.into_iter(), counts: c.into_iter() };
-        let results = decoder.flat_map(|i,c| ::std::iter::repeat(i).take(c)).collect::<Vec<_>>();
-        assert!(items == results);
+        assert_eq!(runs.len(), items.len());
+        assert_eq!(runs.runs(), RunLength::count_runs(items.iter()));
+        for index in 0 .. items.len() + 1 {
+            assert!(runs.iter_from(index).cloned().collect::<Vec<_>>() == &items[index..]);
+        }
+        assert_eq!(runs.iter_from(items.len() + 5).count(), 0);
     }
 }
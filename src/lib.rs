@@ -134,13 +134,24 @@ impl<T: timely::Data + ::std::hash::Hash + Ord + Debug> Data for T { }
 
 
 extern crate fnv;
+extern crate byteorder;
 extern crate time;
 extern crate timely;
 extern crate itertools;
 extern crate timely_sort;
 extern crate timely_communication;
+extern crate abomonation;
 
 pub mod collection;
 pub mod operators;
+pub mod input;
+pub mod testing;
+pub mod logging;
+pub mod stats;
+pub mod checkpoint;
+pub mod timestamp;
+pub mod arrangement;
+pub mod server;
 mod iterators;
 mod stream;
+mod socket;
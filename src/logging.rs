@@ -0,0 +1,96 @@
+//! Differential dataflow events, reported when the `logging` feature is enabled.
+//!
+//! Timely dataflow's logging reports scheduling and messages, which tells us which operators are
+//! busy but not why. The operators in this crate additionally report events describing their own
+//! work: how many records arrive at each time, how many keys must be reconsidered, how large their
+//! traces have grown, and how long they spend sorting input as opposed to evaluating user logic.
+//!
+//! Events are delivered to a sink installed with `set_sink`. Sinks are per worker thread, as are
+//! operator identifiers, which are assigned in order of construction. Workers that construct the
+//! same dataflow therefore use the same identifiers for the same operators, and their events can
+//! be aggregated across workers.
+//!
+//! Without the `logging` feature, or without an installed sink, logging an event does nothing.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! differential_dataflow::logging::set_sink(|event| println!("{:?}", event));
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::fmt::Debug;
+
+/// A differential dataflow event.
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// Identifies the operator instance on this worker, in order of construction.
+    pub operator: usize,
+    /// The name of the operator, e.g. `"GroupBy"`.
+    pub name: &'static str,
+    /// The logical time the event concerns, formatted using `Debug`.
+    pub time: String,
+    /// What happened.
+    pub kind: Kind,
+}
+
+/// The types of differential dataflow event.
+#[derive(Clone, Debug)]
+pub enum Kind {
+    /// A batch of `records` records was received on `input`.
+    Batch { input: usize, records: usize },
+    /// The records received on `input` at a time were sorted into `keys` keys.
+    Sort { input: usize, keys: usize, nanoseconds: u64 },
+    /// A difference of `size` bytes was installed in the trace for `input`, which now has `links`
+    /// links and `times` distinct times.
+    Install { input: usize, links: usize, times: usize, size: usize },
+    /// `keys` keys were processed at a time, taking `nanoseconds` including evaluation of logic.
+    Keys { keys: usize, nanoseconds: u64 },
+}
+
+thread_local! {
+    static SINK: RefCell<Option<Box<FnMut(&Event)>>> = RefCell::new(None);
+    static OPERATORS: Cell<usize> = Cell::new(0);
+}
+
+/// Installs `sink` as the destination of events logged by this worker thread.
+pub fn set_sink<F: FnMut(&Event)+'static>(sink: F) {
+    SINK.with(|x| *x.borrow_mut() = Some(Box::new(sink)));
+}
+
+/// Removes any sink installed for this worker thread.
+pub fn clear_sink() {
+    SINK.with(|x| *x.borrow_mut() = None);
+}
+
+/// Allocates an identifier for a newly constructed operator.
+pub fn new_operator() -> usize {
+    OPERATORS.with(|x| {
+        let result = x.get();
+        x.set(result + 1);
+        result
+    })
+}
+
+/// Delivers an event to this thread's sink, if the `logging` feature is enabled and a sink is set.
+#[inline]
+pub fn log<T: Debug>(operator: usize, name: &'static str, time: &T, kind: Kind) {
+    if cfg!(feature = "logging") {
+        SINK.with(|x| {
+            if let Some(ref mut sink) = *x.borrow_mut() {
+                sink(&Event {
+                    operator: operator,
+                    name: name,
+                    time: format!("{:?}", time),
+                    kind: kind,
+                });
+            }
+        });
+    }
+}
+
+/// Reports the current time in nanoseconds, for measuring durations of operator work.
+#[inline]
+pub fn now() -> u64 {
+    if cfg!(feature = "logging") { ::time::precise_time_ns() } else { 0 }
+}
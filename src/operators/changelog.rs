@@ -0,0 +1,285 @@
+//! Materialization of collections as the current value of each key.
+//!
+//! Downstream systems often want the latest value of each key rather than signed differences. The
+//! `changelog` sink accumulates a `Collection<G, (K, V)>` at each completed time, and reports the
+//! keys whose collections may have changed at the time, as `Change::Upsert` if the key has a single
+//! value with multiplicity one, `Change::Delete` if it has no values, and `Change::Invalid` with its
+//! values and multiplicities otherwise. Invalid keys usually indicate that the collection is not
+//! a function from keys to values, and are worth alerting on.
+//!
+//! A time is reported once the operator's input frontier has passed it, and times are reported in
+//! an order consistent with their partial order. As with `group`, a key may be reported at a time
+//! even though its collection has not changed, for example if its differences at the time cancel.
+//! Keys whose differences all cancel are retired from the operator's trace once no future time can
+//! see them, so that keys deleted and never re-introduced do not accumulate.
+//!
+//! The `snapshots` sink writes the whole of the collection to a file at each completed time a key
+//! may have changed. Files are written durably and renamed into place, so that readers see either
+//! none or all of each.
+//!
+//! Both sinks return a stream with no data, whose frontier may be probed to learn which times have
+//! been reported.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! labels.changelog(|time, changes| {
+//!     for change in changes {
+//!         match change {
+//!             Change::Upsert(key, val) => println!("{:?}: {} = {}", time, key, val),
+//!             Change::Delete(key) => println!("{:?}: {} deleted", time, key),
+//!             Change::Invalid(key, vals) => println!("{:?}: {} is invalid: {:?}", time, key, vals),
+//!         }
+//!     }
+//! });
+//! ```
+
+use std::fmt::Display;
+use std::io::Write;
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+
+use ::{Collection, Data};
+use timely::dataflow::*;
+use timely::dataflow::operators::Unary;
+use timely::dataflow::channels::pact::Exchange;
+
+use collection::{LeastUpperBound, Lookup, Trace};
+use collection::accumulator::Accumulator;
+use collection::persist::write_durably;
+use collection::robin_hood::RHHMap;
+use arrangement::Query;
+use logging::{self, Kind};
+
+use timely_sort::LSBRadixSorter;
+
+/// The state of a key at a completed time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change<K, V> {
+    /// The key has the single value `V`, with multiplicity one.
+    Upsert(K, V),
+    /// The key has no values.
+    Delete(K),
+    /// The key has values other than a single value with multiplicity one.
+    Invalid(K, Vec<(V, i32)>),
+}
+
+impl<K, V> Change<K, V> {
+    /// Classifies the collection `vals` of `key`.
+    pub fn new(key: K, mut vals: Vec<(V, i32)>) -> Change<K, V> {
+        if vals.len() == 0 { Change::Delete(key) }
+        else if vals.len() == 1 && vals[0].1 == 1 { Change::Upsert(key, vals.pop().unwrap().0) }
+        else { Change::Invalid(key, vals) }
+    }
+}
+
+/// Extension trait for the `changelog` and `snapshots` differential dataflow sinks.
+pub trait Changelog<G: Scope, K: Data, V: Data> where G::Timestamp: LeastUpperBound {
+
+    /// Supplies `logic` with each completed time and the changes at that time.
+    fn changelog<F: FnMut(&G::Timestamp, Vec<Change<K, V>>)+'static>(&self, mut logic: F) -> Stream<G, ()> {
+        self.changelog_core(move |time, changes, _| logic(time, changes))
+    }
+
+    /// Writes the collection at each completed time to a file in `directory`, named by `name`.
+    ///
+    /// Each line of the file is a key and its value separated by a tab, in key order. A key with
+    /// values other than a single value with multiplicity one instead has a line for each of its
+    /// values, of the form `!` followed by the key, value, and multiplicity, separated by tabs.
+    ///
+    /// Each worker writes the keys assigned to it, so `name` should distinguish workers if there
+    /// are several. Failing to write a file is fatal.
+    fn snapshots<P, N>(&self, directory: P, name: N) -> Stream<G, ()>
+    where P: AsRef<Path>, N: Fn(&G::Timestamp)->String+'static, K: Display, V: Display {
+        let directory = directory.as_ref().to_path_buf();
+        ::std::fs::create_dir_all(&directory).expect("failed to create snapshot directory");
+        self.changelog_core(move |time, _, trace| {
+            let path: PathBuf = directory.join(name(time));
+            write_durably(&path, |writer, _| {
+                for (key, vals) in trace.scan(time) {
+                    if vals.len() == 1 && vals[0].1 == 1 {
+                        try!(writeln!(writer, "{}\t{}", key, vals[0].0));
+                    }
+                    else {
+                        for (val, wgt) in vals {
+                            try!(writeln!(writer, "!{}\t{}\t{}", key, val, wgt));
+                        }
+                    }
+                }
+                Ok(())
+            }).expect("failed to write snapshot");
+        })
+    }
+
+    /// Supplies `logic` with each completed time, the changes at that time, and the accumulated
+    /// collection, from which the state of any key at that time may be read.
+    fn changelog_core<F>(&self, logic: F) -> Stream<G, ()>
+    where F: FnMut(&G::Timestamp, Vec<Change<K, V>>, &Query<K, G::Timestamp, V>)+'static;
+}
+
+impl<G: Scope, K: Data, V: Data> Changelog<G, K, V> for Collection<G, (K, V)> where G::Timestamp: LeastUpperBound {
+
+    fn changelog_core<F>(&self, mut logic: F) -> Stream<G, ()>
+    where F: FnMut(&G::Timestamp, Vec<Change<K, V>>, &Query<K, G::Timestamp, V>)+'static {
+
+        let mut trace = Trace::new(RHHMap::new(|x: &K| x.hashed() as usize));
+
+        // A map from times to received (key, val, wgt) triples.
+        let mut inputs = Vec::new();
+
+        // A map from times to a list of keys whose collections may change at that time.
+        let mut to_do = Vec::new();
+
+        // A list of times and keys reported deleted at them, which may be retired from the trace
+        // once the frontier has passed the time.
+        let mut retire = Vec::new();
+
+        let mut sorter = LSBRadixSorter::new();
+
+        let operator = logging::new_operator();
+
+        let exch = Exchange::new(|x: &((K, V), i32)| (x.0).0.hashed());
+        self.inner.unary_notify(exch, "Changelog", vec![], move |input, _output, notificator| {
+
+            // 1. read each input, and stash it in our staging area.
+            while let Some((time, data)) = input.next() {
+                logging::log(operator, "Changelog", &time, Kind::Batch { input: 0, records: data.len() });
+                notificator.notify_at(&time);
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs.entry_or_insert(time.clone(), || Accumulator::new())
+                      .push(batch)
+                      .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
+            }
+
+            // 2. report completed times, each only after those of times before it.
+            let mut ready = Vec::new();
+            while let Some((index, _count)) = notificator.next() {
+                ready.push(index);
+            }
+            while let Some(position) = (0 .. ready.len()).position(|i| !ready.iter().any(|x| x < &ready[i])) {
+
+                let index = ready.swap_remove(position);
+
+                // 2a. install any data at this time, noting the times at which keys may change.
+                if let Some(accumulator) = inputs.remove_key(&index) {
+                    if let Some(compact) = accumulator.finish(&mut sorter, &|k: &K| k.hashed()) {
+                        for key in &compact.keys {
+                            for time in trace.interesting_times(key, index.clone()).iter() {
+                                let queue = to_do.entry_or_insert((*time).clone(), || {
+                                    if time != &index { notificator.notify_at(time); }
+                                    Vec::new()
+                                });
+                                queue.push((*key).clone());
+                            }
+                        }
+                        trace.set_difference(index.clone(), compact);
+                    }
+                }
+
+                // 2b. report the state of each key that may have changed.
+                let mut keys = to_do.remove_key(&index).unwrap_or(Vec::new());
+                keys.sort();
+                keys.dedup();
+
+                let start = logging::now();
+                let processed = keys.len();
+                let changes = keys.into_iter()
+                                  .map(|key| { let vals = trace.collection(&key, &index); Change::new(key, vals) })
+                                  .collect::<Vec<_>>();
+                let deleted = changes.iter()
+                                     .filter_map(|change| if let Change::Delete(ref key) = *change { Some(key.clone()) } else { None })
+                                     .collect::<Vec<_>>();
+                logic(&index, changes, &trace);
+                logging::log(operator, "Changelog", &index, Kind::Keys { keys: processed, nanoseconds: logging::now() - start });
+
+                if deleted.len() > 0 {
+                    retire.push((index.clone(), deleted));
+                }
+            }
+
+            // 3. retire deleted keys, once no future time can see them.
+            let frontier = notificator.frontier(0);
+            while let Some(position) = retire.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                let (_, keys) = retire.swap_remove(position);
+                for key in keys {
+                    trace.retire_key(&key, frontier);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::File;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    use timely::dataflow::operators::Map;
+
+    use ::Collection;
+    use testing::execute;
+    use super::{Change, Changelog};
+
+    // differences at each time, as `((key, val), delta)`.
+    fn updates() -> Vec<Vec<((u64, u64), i32)>> {
+        vec![
+            // valid keys, a key with two values, and a key with multiplicity two.
+            vec![((1, 10), 1), ((2, 20), 1), ((3, 30), 1), ((3, 31), 1), ((4, 40), 2)],
+            // change a value, retract a key to nothing, and make an invalid key valid.
+            vec![((1, 10), -1), ((1, 11), 1), ((2, 20), -1), ((3, 31), -1)],
+            // re-introduce the retracted key.
+            vec![((2, 22), 1)],
+        ]
+    }
+
+    #[test]
+    fn changelog_changes() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let sink = changes.clone();
+        execute(1, updates(), move |input| {
+            let sink = sink.clone();
+            let reported = input.changelog(move |time, changes| {
+                sink.lock().unwrap().extend(changes.into_iter().map(|change| (time.inner, change)));
+            });
+            // an empty collection with the changelog's frontier, so that each time is reported
+            // before the next is introduced.
+            Collection::new(reported.map(|_| (0u64, 0)))
+        });
+
+        let changes = ::std::mem::replace(&mut *changes.lock().unwrap(), Vec::new());
+        assert_eq!(changes, vec![
+            (0, Change::Upsert(1, 10)),
+            (0, Change::Upsert(2, 20)),
+            (0, Change::Invalid(3, vec![(30, 1), (31, 1)])),
+            (0, Change::Invalid(4, vec![(40, 2)])),
+            (1, Change::Upsert(1, 11)),
+            (1, Change::Delete(2)),
+            (1, Change::Upsert(3, 30)),
+            (2, Change::Upsert(2, 22)),
+        ]);
+    }
+
+    #[test]
+    fn snapshot_files() {
+        let directory = ::std::env::temp_dir().join(format!("differential-snapshots-{}", ::time::precise_time_ns()));
+        let target = directory.clone();
+        execute(1, updates(), move |input| {
+            let written = input.snapshots(&target, |time| format!("{}", time.inner));
+            Collection::new(written.map(|_| (0u64, 0)))
+        });
+
+        let expected = [
+            "1\t10\n2\t20\n!3\t30\t1\n!3\t31\t1\n!4\t40\t2\n",
+            "1\t11\n3\t30\n!4\t40\t2\n",
+            "1\t11\n2\t22\n3\t30\n!4\t40\t2\n",
+        ];
+        for (time, expected) in expected.iter().enumerate() {
+            let mut contents = String::new();
+            File::open(directory.join(format!("{}", time))).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(&contents, expected);
+        }
+        ::std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -49,6 +49,10 @@ use collection::trace::CollectionIterator;
 
 use iterators::coalesce::Coalesce;
 use collection::compact::Compact;
+use collection::accumulator::{Accumulator, Snapshot};
+use logging::{self, Kind};
+use stats::{self, Statistics};
+use checkpoint;
 
 /// Extension trait for the `group_by` and `group_by_u` differential dataflow methods.
 pub trait CoGroupBy<G: Scope, K: Data, V1: Data> where G::Timestamp: LeastUpperBound {
@@ -60,7 +64,7 @@ pub trait CoGroupBy<G: Scope, K: Data, V1: Data> where G::Timestamp: LeastUpperB
     /// the user must specify a function implmenting `Fn(u64) -> Look`, where `Look: Lookup<K, Offset>` is something you shouldn't have to know about yet.
     /// The right thing to use here, for the moment, is `|_| HashMap::new()`.
     ///
-    /// There are better options if you know your key is an unsigned integer, namely `|x| (Vec::new(), x)`.
+    /// There are better options if you know your key is an unsigned integer, namely `|x| DenseMap::new(x)`.
     fn cogroup_by_inner<
         D:     Data,
         V2:    Data+Default,
@@ -72,12 +76,28 @@ pub trait CoGroupBy<G: Scope, K: Data, V1: Data> where G::Timestamp: LeastUpperB
         Logic: Fn(&K, &mut CollectionIterator<V1>, &mut CollectionIterator<V2>, &mut Vec<(V3, i32)>)+'static,
         Reduc: Fn(&K, &V3)->D+'static,
     >
-    (&self, other: &Collection<G, (K, V2)>, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D>;
+    (&self, other: &Collection<G, (K, V2)>, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D> {
+        self.cogroup_by_inner_with_stats(other, key_h, reduc, look, logic).0
+    }
+
+    /// As `cogroup_by_inner`, but also returns a handle reporting the sizes of the operator's state.
+    fn cogroup_by_inner_with_stats<
+        D:     Data,
+        V2:    Data+Default,
+        V3:    Data+Default,
+        U:     Unsigned+Default,
+        KH:    Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        Logic: Fn(&K, &mut CollectionIterator<V1>, &mut CollectionIterator<V2>, &mut Vec<(V3, i32)>)+'static,
+        Reduc: Fn(&K, &V3)->D+'static,
+    >
+    (&self, other: &Collection<G, (K, V2)>, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> (Collection<G, D>, stats::Handle);
 }
 
 impl<G: Scope, K: Data, V1: Data> CoGroupBy<G, K, V1> for Collection<G, (K, V1)>
 where G::Timestamp: LeastUpperBound {
-    fn cogroup_by_inner<
+    fn cogroup_by_inner_with_stats<
         D:     Data,
         V2:    Data+Default,
         V3:    Data+Default,
@@ -88,7 +108,7 @@ where G::Timestamp: LeastUpperBound {
         Logic: Fn(&K, &mut CollectionIterator<V1>, &mut CollectionIterator<V2>, &mut Vec<(V3, i32)>)+'static,
         Reduc: Fn(&K, &V3)->D+'static,
     >
-    (&self, other: &Collection<G, (K, V2)>, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D> {
+    (&self, other: &Collection<G, (K, V2)>, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> (Collection<G, D>, stats::Handle) {
 
         let mut source1 = Trace::new(look(0));
         let mut source2 = Trace::new(look(0));
@@ -101,6 +121,10 @@ where G::Timestamp: LeastUpperBound {
         // A map from times to a list of keys that need processing at that time.
         let mut to_do = Vec::new();
 
+        // A map from times to a list of keys whose inputs were empty at that time, and which may be
+        // retired from all traces once the frontiers have passed the time.
+        let mut retire = Vec::new();
+
         // temporary storage for operator implementations to populate
         let mut buffer = vec![];
         let mut heap1 = vec![];
@@ -118,21 +142,59 @@ where G::Timestamp: LeastUpperBound {
         let mut sorter1 = LSBRadixSorter::new();
         let mut sorter2 = LSBRadixSorter::new();
 
+        let operator = logging::new_operator();
+
+        // log differences for checkpoints, and restore the traces and queues of any checkpoint.
+        let mut participant = checkpoint::participant("CoGroupBy");
+        let mut source1_log = participant.as_ref().map(|x| x.log("source1", |time, compact| source1.set_difference(time, compact)));
+        let mut source2_log = participant.as_ref().map(|x| x.log("source2", |time, compact| source2.set_difference(time, compact)));
+        let mut result_log = participant.as_ref().map(|x| x.log("result", |time, compact| result.set_difference(time, compact)));
+        let mut notify = Vec::new();
+        if let Some(ref participant) = participant {
+            if let Some((pending1, pending2, keys)) = participant.restored::<(Vec<(G::Timestamp, Snapshot<K, V1>)>, Vec<(G::Timestamp, Snapshot<K, V2>)>, Vec<(G::Timestamp, Vec<K>)>)>() {
+                for (time, snapshot) in pending1 {
+                    let mut accumulator = Accumulator::new();
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs1.push((time, accumulator));
+                }
+                for (time, snapshot) in pending2 {
+                    let mut accumulator = Accumulator::new();
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs2.push((time, accumulator));
+                }
+                for (time, keys) in keys {
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    to_do.push((time, keys));
+                }
+            }
+        }
+
+        let stats = stats::Handle::new();
+        let handle = stats.clone();
+
         // fabricate a data-parallel operator using the `unary_notify` pattern.
-        Collection::new(self.inner.binary_notify(&other.inner, exch1, exch2, "CoGroupBy", vec![], move |input1, input2, output, notificator| {
+        let stream = self.inner.binary_notify(&other.inner, exch1, exch2, "CoGroupBy", notify, move |input1, input2, output, notificator| {
 
             // 1. read each input, and stash it in our staging area
             while let Some((time, data)) = input1.next() {
+                logging::log(operator, "CoGroupBy", &time, Kind::Batch { input: 0, records: data.len() });
                 notificator.notify_at(&time);
-                inputs1.entry_or_insert(time.clone(), || Vec::new())
-                       .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs1.entry_or_insert(time.clone(), || Accumulator::new())
+                       .push(batch)
+                       .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             // 1. read each input, and stash it in our staging area
             while let Some((time, data)) = input2.next() {
+                logging::log(operator, "CoGroupBy", &time, Kind::Batch { input: 1, records: data.len() });
                 notificator.notify_at(&time);
-                inputs2.entry_or_insert(time.clone(), || Vec::new())
-                       .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs2.entry_or_insert(time.clone(), || Accumulator::new())
+                       .push(batch)
+                       .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             // 2. go through each time of interest that has reached completion
@@ -141,25 +203,13 @@ where G::Timestamp: LeastUpperBound {
             while let Some((index, _count)) = notificator.next() {
 
                 // 2a. fetch any data associated with this time.
-                if let Some(mut queue) = inputs1.remove_key(&index) {
+                if let Some(accumulator) = inputs1.remove_key(&index) {
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter1.extend(element.into_iter(), &|x| key_h(&(x.0).0));
-                        }
-                        let mut sorted = sorter1.finish(&|x| key_h(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
-                        sorted.truncate(256);
-                        sorter1.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).collect::<Vec<_>>();
-                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
-                    };
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter1, &|k| key_h(k));
+
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "CoGroupBy", &index, Kind::Sort { input: 0, keys: keys, nanoseconds: logging::now() - start });
 
                     if let Some(compact) = compact {
 
@@ -170,30 +220,23 @@ where G::Timestamp: LeastUpperBound {
                             }
                         }
 
+                        let size = compact.size();
+                        if let Some(ref mut log) = source1_log {
+                            log.append(&index, &compact).expect("failed to log difference");
+                        }
                         source1.set_difference(index.clone(), compact);
+                        logging::log(operator, "CoGroupBy", &index, Kind::Install { input: 0, links: source1.link_count(), times: source1.time_count(), size: size });
                     }
                 }
 
                 // 2a. fetch any data associated with this time.
-                if let Some(mut queue) = inputs2.remove_key(&index) {
+                if let Some(accumulator) = inputs2.remove_key(&index) {
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter2.extend(element.into_iter(), &|x| key_h(&(x.0).0));
-                        }
-                        let mut sorted = sorter2.finish(&|x| key_h(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
-                        sorted.truncate(256);
-                        sorter2.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).collect::<Vec<_>>();
-                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
-                    };
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter2, &|k| key_h(k));
+
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "CoGroupBy", &index, Kind::Sort { input: 1, keys: keys, nanoseconds: logging::now() - start });
 
                     if let Some(compact) = compact {
 
@@ -204,7 +247,12 @@ where G::Timestamp: LeastUpperBound {
                             }
                         }
 
+                        let size = compact.size();
+                        if let Some(ref mut log) = source2_log {
+                            log.append(&index, &compact).expect("failed to log difference");
+                        }
                         source2.set_difference(index.clone(), compact);
+                        logging::log(operator, "CoGroupBy", &index, Kind::Install { input: 1, links: source2.link_count(), times: source2.time_count(), size: size });
                     }
                 }
 
@@ -225,9 +273,15 @@ where G::Timestamp: LeastUpperBound {
                     keys.sort_by(|x,y| (key_h(&x), x).cmp(&(key_h(&y), y)));
                     keys.dedup();
 
+                    let start = logging::now();
+                    let processed = keys.len();
+
                     // accumulations for installation into result
                     let mut accumulation = Compact::new(0,0);
 
+                    // keys with empty inputs, which may have cancelled out entirely
+                    let mut empty = Vec::new();
+
                     for key in keys {
 
                         // acquire an iterator over the collection at `time`.
@@ -236,6 +290,7 @@ where G::Timestamp: LeastUpperBound {
 
                         // if we have some data, invoke logic to populate self.dst
                         if input1.peek().is_some() || input2.peek().is_some() { logic(&key, &mut input1, &mut input2, &mut buffer); }
+                        else { empty.push(key.clone()); }
 
                         buffer.sort_by(|x,y| x.0.cmp(&y.0));
 
@@ -254,12 +309,56 @@ where G::Timestamp: LeastUpperBound {
                         buffer.clear();
                     }
 
+                    logging::log(operator, "CoGroupBy", &index, Kind::Keys { keys: processed, nanoseconds: logging::now() - start });
+
                     if accumulation.vals.len() > 0 {
                         // println!("group2");
+                        if let Some(ref mut log) = result_log {
+                            log.append(&index, &accumulation).expect("failed to log difference");
+                        }
                         result.set_difference(index.clone(), accumulation);
                     }
+
+                    if empty.len() > 0 {
+                        retire.push((index.clone(), empty));
+                    }
                 }
             }
-        }))
+
+            // retire keys whose inputs and output have cancelled, once no future time can see them.
+            if retire.len() > 0 {
+                let frontier = notificator.frontier(0).iter().chain(notificator.frontier(1).iter()).cloned().collect::<Vec<_>>();
+                while let Some(position) = retire.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                    let (_, keys) = retire.swap_remove(position);
+                    for key in keys {
+                        source1.retire_key(&key, &frontier[..]);
+                        source2.retire_key(&key, &frontier[..]);
+                        result.retire_key(&key, &frontier[..]);
+                    }
+                }
+            }
+
+            // save our queues if a checkpoint awaits them; the traces are already logged.
+            if let Some(ref mut participant) = participant {
+                if participant.requested() {
+                    let frontier = notificator.frontier(0).iter().chain(notificator.frontier(1).iter()).cloned().collect::<Vec<_>>();
+                    participant.save(&frontier[..], |files| {
+                        let mut pending1 = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs1 {
+                            pending1.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        let mut pending2 = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs2 {
+                            pending2.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        Ok((pending1, pending2, to_do.clone()))
+                    });
+                }
+            }
+
+            stats.set(source1.statistics() + source2.statistics() + result.statistics() + Statistics::pending(inputs1.len() + inputs2.len(), to_do.len()));
+        });
+
+        (Collection::new(stream), handle)
     }
 }
@@ -7,7 +7,7 @@
 //! Several variants of `group` exist which allow more precise control over how grouping is done.
 //! For example, the `_by` suffixed variants take arbitrary data, but require a key-value selector
 //! to be applied to each record. The `_u` suffixed variants use unsigned integers as keys, and
-//! will use a dense array rather than a `HashMap` to store their keys.
+//! will use a dense array rather than a hash map to store their keys.
 //!
 //! The list of values are presented as an iterator which internally merges sorted lists of values.
 //! This ordering can be exploited in several cases to avoid computation when only the first few
@@ -32,8 +32,8 @@
 //! ```
 
 use std::default::Default;
+use std::rc::Rc;
 // use std::hash::Hasher;
-use std::collections::HashMap;
 use std::ops::DerefMut;
 
 use itertools::Itertools;
@@ -44,11 +44,17 @@ use timely::dataflow::operators::{Map, Unary};
 use timely::dataflow::channels::pact::Exchange;
 use timely_sort::{LSBRadixSorter, Unsigned};
 
-use collection::{LeastUpperBound, Lookup, Trace, Offset};
+use collection::{LeastUpperBound, Lookup, Trace, Offset, DenseMap, FromU64};
 use collection::trace::CollectionIterator;
+use collection::robin_hood::RHHMap;
 
 use iterators::coalesce::Coalesce;
 use collection::compact::Compact;
+use collection::accumulator::{Accumulator, Snapshot};
+use logging::{self, Kind};
+use stats::{self, Statistics};
+use checkpoint;
+use arrangement;
 
 /// Extension trait for the `group` differential dataflow method
 pub trait Group<G: Scope, K: Data, V: Data> : GroupBy<G, (K,V)>
@@ -57,6 +63,10 @@ pub trait Group<G: Scope, K: Data, V: Data> : GroupBy<G, (K,V)>
     /// Groups records by their first field, and applies reduction logic to the associated values.
     fn group<L, V2: Data>(&self, logic: L) -> Collection<G, (K,V2)>
         where L: Fn(&K, &mut CollectionIterator<V>, &mut Vec<(V2, Delta)>)+'static;
+
+    /// As `group`, but also returns a handle from which the output may be read at past times.
+    fn group_arranged<L, V2: Data>(&self, logic: L) -> (Collection<G, (K,V2)>, arrangement::Handle<K, G::Timestamp, V2>)
+        where L: Fn(&K, &mut CollectionIterator<V>, &mut Vec<(V2, Delta)>)+'static;
 }
 
 impl<G: Scope, K: Data+Default, V: Data+Default> Group<G, K, V> for Collection<G, (K,V)>
@@ -68,13 +78,25 @@ where G::Timestamp: LeastUpperBound {
                 |&(ref k,_)| k.hashed(),
                 |k| k.hashed(),
                 |k,v2| ((*k).clone(), (*v2).clone()),
-                |_| HashMap::new(),//RHHMap::new(|x: &K| x.hashed() as usize),
+                |_| RHHMap::new(|x: &K| x.hashed() as usize),
                 logic
             )
     }
+    fn group_arranged<L, V2: Data>(&self, logic: L) -> (Collection<G, (K,V2)>, arrangement::Handle<K, G::Timestamp, V2>)
+        where L: Fn(&K, &mut CollectionIterator<V>, &mut Vec<(V2, Delta)>)+'static {
+            let (collection, _, arranged) = self.group_by_core_arranged(
+                |x| x,
+                |&(ref k,_)| k.hashed(),
+                |k| k.hashed(),
+                |k,v2| ((*k).clone(), (*v2).clone()),
+                |_| RHHMap::new(|x: &K| x.hashed() as usize),
+                logic
+            );
+            (collection, arranged)
+    }
 }
 
-pub trait GroupUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data> : GroupBy<G, (U,V)>
+pub trait GroupUnsigned<G: Scope, U: FromU64+Data+Default, V: Data> : GroupBy<G, (U,V)>
     where G::Timestamp: LeastUpperBound {
     fn group_u<L, V2: Data>(&self, logic: L) -> Collection<G, (U, V2)>
         where L: Fn(&U, &mut CollectionIterator<V>, &mut Vec<(V2, i32)>)+'static {
@@ -83,13 +105,13 @@ pub trait GroupUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data> : GroupBy<G
                 |&(ref k,_)| k.as_u64(),
                 |k| k.clone(),
                 |k, v| (k.clone(), (*v).clone()),
-                |x| (Vec::new(), x),
+                |x| DenseMap::new(x),
                 logic)
     }
 }
 
 // implement `GroupBy` for any stream implementing `Unary` and `Map` (most of them).
-impl<G: Scope, U: Unsigned+Data+Default, V: Data, S> GroupUnsigned<G, U, V> for S
+impl<G: Scope, U: FromU64+Data+Default, V: Data, S> GroupUnsigned<G, U, V> for S
 where G::Timestamp: LeastUpperBound,
       S: GroupBy<G, (U,V)> { }
 
@@ -98,7 +120,7 @@ where G::Timestamp: LeastUpperBound,
 impl<G: Scope, D1: Data> GroupBy<G, D1> for Collection<G, D1>
 where G::Timestamp: LeastUpperBound {
     fn group_by_u<
-        U:     Data+Unsigned+Default,
+        U:     Data+FromU64+Default,
         V1:    Data,
         V2:    Data,
         D2:    Data,
@@ -112,7 +134,7 @@ where G::Timestamp: LeastUpperBound {
                                     |&(ref k,_)| k.as_u64(),
                                     |k| k.clone(),
                                     reduc,
-                                    |x| (Vec::new(), x),
+                                    |x| DenseMap::new(x),
                                     logic)
     }
 }
@@ -150,13 +172,13 @@ where G::Timestamp: LeastUpperBound {
         Reduc: Fn(&K, &V2)->D2+'static,
     >
     (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, logic: Logic) -> Collection<G, D2> {
-        self.group_by_core(kv, part, key_h, reduc, |_| HashMap::new(), logic)
+        self.group_by_core(kv, part, key_h, reduc, |_| RHHMap::new(|x: &K| x.hashed() as usize), logic)
     }
 
     /// A specialization of the `group_by` method to the case that the key type `K` is an unsigned
     /// integer, and the strategy for indexing by key is simply to index into a vector.
     fn group_by_u<
-        U:     Data+Unsigned+Default,
+        U:     Data+FromU64+Default,
         V1:    Data,
         V2:    Data,
         D2:    Data,
@@ -183,7 +205,47 @@ pub trait GroupByCore<G: Scope, D1: Data> {
         Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
         Reduc: Fn(&K, &V2)->D2+'static,
     >
-    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D2>;
+    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D2> {
+        self.group_by_core_with_stats(kv, part, key_h, reduc, look, logic).0
+    }
+
+    /// As `group_by_core`, but also returns a handle reporting the sizes of the operator's state.
+    fn group_by_core_with_stats<
+        K:     Data,
+        V1:    Data,
+        V2:    Data,
+        D2:    Data,
+        KV:    Fn(D1)->(K,V1)+'static,
+        Part:  Fn(&D1)->u64+'static,
+        U:     Unsigned+Default,
+        KH:    Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
+        Reduc: Fn(&K, &V2)->D2+'static,
+    >
+    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> (Collection<G, D2>, stats::Handle) {
+        let (collection, stats, _) = self.group_by_core_arranged(kv, part, key_h, reduc, look, logic);
+        (collection, stats)
+    }
+
+    /// As `group_by_core_with_stats`, but also returns a handle from which the output trace may be
+    /// read at past times; see `arrangement`.
+    fn group_by_core_arranged<
+        K:     Data,
+        V1:    Data,
+        V2:    Data,
+        D2:    Data,
+        KV:    Fn(D1)->(K,V1)+'static,
+        Part:  Fn(&D1)->u64+'static,
+        U:     Unsigned+Default,
+        KH:    Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
+        Reduc: Fn(&K, &V2)->D2+'static,
+    >
+    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> (Collection<G, D2>, stats::Handle, arrangement::Handle<K, G::Timestamp, V2>);
 
 }
 
@@ -192,7 +254,7 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
     /// The lowest level `group*` implementation, which is parameterized by the type of storage to
     /// use for mapping keys `K` to `Offset`, an internal `CollectionTrace` type. This method should
     /// probably rarely be used directly.
-    fn group_by_core<
+    fn group_by_core_arranged<
         K:     Data,
         V1:    Data,
         V2:    Data,
@@ -206,7 +268,7 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
         Logic: Fn(&K, &mut CollectionIterator<V1>, &mut Vec<(V2, i32)>)+'static,
         Reduc: Fn(&K, &V2)->D2+'static,
     >
-    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> Collection<G, D2> {
+    (&self, kv: KV, part: Part, key_h: KH, reduc: Reduc, look: LookG, logic: Logic) -> (Collection<G, D2>, stats::Handle, arrangement::Handle<K, G::Timestamp, V2>) {
 
         // A pair of source and result `CollectionTrace` instances.
         // TODO : The hard-coded 0 means we don't know how many bits we can shave off of each int
@@ -226,7 +288,8 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
         }
 
         let mut source = Trace::new(look(log_peers));
-        let mut result = Trace::new(look(log_peers));
+        let (result, arranged) = arrangement::shared(Trace::new(look(log_peers)));
+        let arranged_handle = arranged.clone();
 
         // A map from times to received (key, val, wgt) triples.
         let mut inputs = Vec::new();
@@ -234,6 +297,10 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
         // A map from times to a list of keys that need processing at that time.
         let mut to_do = Vec::new();
 
+        // A map from times to a list of keys whose input was empty at that time, and which may be
+        // retired from both traces once the frontier has passed the time.
+        let mut retire = Vec::new();
+
         // temporary storage for operator implementations to populate
         let mut buffer = vec![];
         let mut heap1 = vec![];
@@ -243,15 +310,46 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
         let exch = Exchange::new(move |&(ref x,_)| part(x));
 
         let mut sorter = LSBRadixSorter::new();
+        let kv = Rc::new(kv);
+
+        let operator = logging::new_operator();
+
+        // log differences for checkpoints, and restore the traces and queues of any checkpoint.
+        let mut participant = checkpoint::participant("GroupBy");
+        let mut source_log = participant.as_ref().map(|x| x.log("source", |time, compact| source.set_difference(time, compact)));
+        let mut result_log = participant.as_ref().map(|x| x.log("result", |time, compact| result.borrow_mut().set_difference(time, compact)));
+        let mut notify = Vec::new();
+        if let Some(ref participant) = participant {
+            if let Some((pending, keys)) = participant.restored::<(Vec<(G::Timestamp, Snapshot<K, V1>)>, Vec<(G::Timestamp, Vec<K>)>)>() {
+                for (time, snapshot) in pending {
+                    let mut accumulator = Accumulator::mapped(kv.clone());
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs.push((time, accumulator));
+                }
+                for (time, keys) in keys {
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    to_do.push((time, keys));
+                }
+            }
+        }
+
+        let stats = stats::Handle::new();
+        let handle = stats.clone();
 
         // fabricate a data-parallel operator using the `unary_notify` pattern.
-        Collection::new(self.inner.unary_notify(exch, "GroupBy", vec![], move |input, output, notificator| {
+        let stream = self.inner.unary_notify(exch, "GroupBy", notify, move |input, output, notificator| {
+
+            let mut result = result.borrow_mut();
 
             // 1. read each input, and stash it in our staging area
             while let Some((time, data)) = input.next() {
+                logging::log(operator, "GroupBy", &time, Kind::Batch { input: 0, records: data.len() });
                 notificator.notify_at(&time);
-                inputs.entry_or_insert(time.clone(), || Vec::new())
-                      .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs.entry_or_insert(time.clone(), || Accumulator::mapped(kv.clone()))
+                      .push(batch)
+                      .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             // 2. go through each time of interest that has reached completion
@@ -260,25 +358,13 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
             while let Some((index, _count)) = notificator.next() {
 
                 // 2a. fetch any data associated with this time.
-                if let Some(mut queue) = inputs.remove_key(&index) {
+                if let Some(accumulator) = inputs.remove_key(&index) {
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter.extend(element.into_iter().map(|(d,w)| (kv(d),w)), &|x| key_h(&(x.0).0));
-                        }
-                        let mut sorted = sorter.finish(&|x| key_h(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
-                        sorted.truncate(256);
-                        sorter.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).map(|(d,w)| (kv(d),w)).collect::<Vec<_>>();
-                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
-                    };
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter, &|k| key_h(k));
+
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "GroupBy", &index, Kind::Sort { input: 0, keys: keys, nanoseconds: logging::now() - start });
 
                     if let Some(compact) = compact {
 
@@ -291,7 +377,12 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
 
                         // add the accumulation to the trace source.
                         // println!("group1");
+                        let size = compact.size();
+                        if let Some(ref mut log) = source_log {
+                            log.append(&index, &compact).expect("failed to log difference");
+                        }
                         source.set_difference(index.clone(), compact);
+                        logging::log(operator, "GroupBy", &index, Kind::Install { input: 0, links: source.link_count(), times: source.time_count(), size: size });
                     }
                 }
 
@@ -308,9 +399,15 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
                     keys.sort_by(|x,y| (key_h(&x), x).cmp(&(key_h(&y), y)));
                     keys.dedup();
 
+                    let start = logging::now();
+                    let processed = keys.len();
+
                     // accumulations for installation into result
                     let mut accumulation = Compact::new(0,0);
 
+                    // keys with empty input, which may have cancelled out entirely
+                    let mut empty = Vec::new();
+
                     for key in keys {
 
                         // acquire an iterator over the collection at `time`.
@@ -318,6 +415,7 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
 
                         // if we have some data, invoke logic to populate self.dst
                         if input.peek().is_some() { logic(&key, &mut input, &mut buffer); }
+                        else { empty.push(key.clone()); }
 
                         buffer.sort_by(|x,y| x.0.cmp(&y.0));
 
@@ -337,11 +435,50 @@ impl<G: Scope, D1: Data> GroupByCore<G, D1> for Collection<G, D1> where G::Times
                         buffer.clear();
                     }
 
+                    logging::log(operator, "GroupBy", &index, Kind::Keys { keys: processed, nanoseconds: logging::now() - start });
+
                     if accumulation.vals.len() > 0 {
+                        if let Some(ref mut log) = result_log {
+                            log.append(&index, &accumulation).expect("failed to log difference");
+                        }
                         result.set_difference(index.clone(), accumulation);
                     }
+
+                    if empty.len() > 0 {
+                        retire.push((index.clone(), empty));
+                    }
                 }
             }
-        }))
+
+            // 3. retire keys whose input and output have cancelled, once no future time can see them.
+            let frontier = notificator.frontier(0);
+            while let Some(position) = retire.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                let (_, keys) = retire.swap_remove(position);
+                for key in keys {
+                    source.retire_key(&key, frontier);
+                    if result.retire_key(&key, frontier) {
+                        arranged.compact(frontier);
+                    }
+                }
+            }
+            arranged.advance(frontier);
+
+            // 4. save our queues if a checkpoint awaits them; the traces are already logged.
+            if let Some(ref mut participant) = participant {
+                if participant.requested() {
+                    participant.save(notificator.frontier(0), |files| {
+                        let mut pending = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs {
+                            pending.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        Ok((pending, to_do.clone()))
+                    });
+                }
+            }
+
+            stats.set(source.statistics() + result.statistics() + Statistics::pending(inputs.len(), to_do.len()));
+        });
+
+        (Collection::new(stream), handle, arranged_handle)
     }
 }
@@ -15,9 +15,13 @@ use timely::dataflow::channels::pact::Exchange;
 
 use timely_communication::Allocate;
 
-use collection::{Trace, LeastUpperBound, Lookup, Offset};
+use collection::{Trace, LeastUpperBound, Lookup, Offset, DenseMap, FromU64};
 use collection::compact::Compact;
+use collection::accumulator::{Accumulator, Snapshot};
 use collection::robin_hood::RHHMap;
+use logging::{self, Kind};
+use stats::{self, Statistics};
+use checkpoint;
 use timely_sort::{LSBRadixSorter, Unsigned};
 
 /// Join implementations for `(key,val)` data.
@@ -71,7 +75,7 @@ where G::Timestamp: LeastUpperBound, S: JoinBy<G, (K,V)> { }
 
 
 /// Join implementations for `(unsigned_int, val)` data.
-pub trait JoinUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data> : JoinBy<G, (U,V)> where G::Timestamp: LeastUpperBound {
+pub trait JoinUnsigned<G: Scope, U: FromU64+Data+Default, V: Data> : JoinBy<G, (U,V)> where G::Timestamp: LeastUpperBound {
 
     /// Matches pairs of `(key, val1)` and `(key, val2)` data based `key`.
     fn join_u<V2>(&self, other: &Collection<G, (U, V2)>) -> Collection<G, (U, V, V2)>
@@ -83,7 +87,7 @@ pub trait JoinUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data> : JoinBy<G,
             |&(ref k,_)| k.as_u64(),
             |k| k.clone(),
             |k,v1,v2| (k.clone(), v1.clone(), v2.clone()),
-            &|x| (Vec::new(), x))
+            &|x| DenseMap::new(x))
     }
     /// Matches pairs of `(key,val1)` and `(key,val2)` records based on `key` and applies a reduction function.
     fn join_map_u<V2, D, R>(&self, other: &Collection<G, (U, V2)>, logic: R) -> Collection<G, D>
@@ -91,12 +95,12 @@ pub trait JoinUnsigned<G: Scope, U: Unsigned+Data+Default, V: Data> : JoinBy<G,
           D: Data,
           R: Fn(&U, &V, &V2)->D+'static,
           G::Timestamp: LeastUpperBound+Debug {
-        self.join_by_core(other, |x| x, |x| x, |&(ref k,_)| k.as_u64(), |&(ref k,_)| k.as_u64(), |k| k.clone(), logic, &|x| (Vec::new(), x))
+        self.join_by_core(other, |x| x, |x| x, |&(ref k,_)| k.as_u64(), |&(ref k,_)| k.as_u64(), |k| k.clone(), logic, &|x| DenseMap::new(x))
     }
 
 }
 
-impl<G: Scope, U: Unsigned+Data+Default, V: Data, S> JoinUnsigned<G, U, V> for S
+impl<G: Scope, U: FromU64+Data+Default, V: Data, S> JoinUnsigned<G, U, V> for S
 where G::Timestamp: LeastUpperBound,
       S: JoinBy<G, (U,V)> { }
 
@@ -107,7 +111,7 @@ where G::Timestamp: LeastUpperBound {
         (&self, other: &Collection<G, D2>, kv1: F1, kv2: F2, result: RF) -> Collection<G, R>
 
         where
-            U:  Unsigned+Data+Default,
+            U:  FromU64+Data+Default,
             V1: Data,
             V2: Data,
             D2: Data,
@@ -124,7 +128,7 @@ where G::Timestamp: LeastUpperBound {
                         |&(ref k,_)| k.as_u64(),
                         |k| k.clone(),
                         result,
-                        &|x| (Vec::new(), x))
+                        &|x| DenseMap::new(x))
     }
 
     fn join_by<
@@ -174,7 +178,7 @@ pub trait JoinBy<G: Scope, D1: Data> : JoinByCore<G, D1> where G::Timestamp: Lea
         (&self, other: &Collection<G, D2>, kv1: F1, kv2: F2, result: RF) -> Collection<G, R>
 
         where
-            U:  Unsigned+Data+Default,
+            U:  FromU64+Data+Default,
             V1: Data,
             V2: Data,
             D2: Data,
@@ -191,7 +195,7 @@ pub trait JoinBy<G: Scope, D1: Data> : JoinByCore<G, D1> where G::Timestamp: Lea
     /// second stream. The key-val selector and reconstruction function are available to help avoid
     /// storing a redundant copy of the key in the value payload.
     fn semijoin_by_u<
-        U:  Unsigned+Data+Default,
+        U:  FromU64+Data+Default,
         V1: Data+Default+'static,
         F1: Fn(D1)->(U,V1)+'static,
         RF: Fn(&U,&V1)->D1+'static,
@@ -260,11 +264,40 @@ pub trait JoinByCore<G: Scope, D1: Data> {
              part2: H2,
              key_h: KH,
              result: RF,
-             look:  &GC)  -> Collection<G, R>;
+             look:  &GC)  -> Collection<G, R> {
+        self.join_by_core_with_stats(stream2, kv1, kv2, part1, part2, key_h, result, look).0
+    }
+
+    /// As `join_by_core`, but also returns a handle reporting the sizes of the operator's state.
+    fn join_by_core_with_stats<
+        K:  Data,
+        V1: Data,
+        V2: Data,
+        D2: Data,
+        F1: Fn(D1)->(K,V1)+'static,
+        F2: Fn(D2)->(K,V2)+'static,
+        H1: Fn(&D1)->u64+'static,
+        H2: Fn(&D2)->u64+'static,
+        U:  Unsigned+Data+Default,
+        KH: Fn(&K)->U+'static,
+        R:  Data,
+        RF: Fn(&K,&V1,&V2)->R+'static,
+        LC: Lookup<K, Offset>+'static,
+        GC: Fn(u64)->LC,
+    >
+            (&self,
+             stream2: &Collection<G, D2>,
+             kv1: F1,
+             kv2: F2,
+             part1: H1,
+             part2: H2,
+             key_h: KH,
+             result: RF,
+             look:  &GC)  -> (Collection<G, R>, stats::Handle);
 }
 
 impl<G: Scope, D1: Data> JoinByCore<G, D1> for Collection<G, D1> where G::Timestamp: LeastUpperBound {
-    fn join_by_core<
+    fn join_by_core_with_stats<
         K:  Data,
         V1: Data,
         V2: Data,
@@ -288,7 +321,7 @@ impl<G: Scope, D1: Data> JoinByCore<G, D1> for Collection<G, D1> where G::Timest
              part2: H2,
              key_h: KH,
              result: RF,
-             look:  &GC)  -> Collection<G, R> {
+             look:  &GC)  -> (Collection<G, R>, stats::Handle) {
 
         // TODO : pay more attention to the number of peers
         // TODO : find a better trait to sub-trait so we can read .builder
@@ -308,13 +341,49 @@ impl<G: Scope, D1: Data> JoinByCore<G, D1> for Collection<G, D1> where G::Timest
 
         let mut outbuf = Vec::new();    // Vec<(T, Vec<(R,i32)>)> for buffering output.
 
+        let mut retire1 = Vec::new();   // Vec<(T, Vec<K>)> of retracted keys, to check once passed.
+        let mut retire2 = Vec::new();   // Vec<(T, Vec<K>)> of retracted keys, to check once passed.
+
         let exch1 = Exchange::new(move |&(ref r, _)| part1(r));
         let exch2 = Exchange::new(move |&(ref r, _)| part2(r));
 
         let mut sorter1 = LSBRadixSorter::new();
         let mut sorter2 = LSBRadixSorter::new();
+        let kv1 = Rc::new(kv1);
+        let kv2 = Rc::new(kv2);
+
+        let operator = logging::new_operator();
+
+        // log differences for checkpoints, and restore the traces and queues of any checkpoint.
+        let mut participant = checkpoint::participant("Join");
+        let mut trace1_log = participant.as_ref().map(|x| x.log("trace1", |time, compact| trace1.as_mut().unwrap().set_difference(time, compact)));
+        let mut trace2_log = participant.as_ref().map(|x| x.log("trace2", |time, compact| trace2.as_mut().unwrap().set_difference(time, compact)));
+        let mut notify = Vec::new();
+        if let Some(ref participant) = participant {
+            if let Some((pending1, pending2, pending)) = participant.restored::<(Vec<(G::Timestamp, Snapshot<K, V1>)>, Vec<(G::Timestamp, Snapshot<K, V2>)>, Vec<(G::Timestamp, Vec<(R, i32)>)>)>() {
+                for (time, snapshot) in pending1 {
+                    let mut accumulator = Accumulator::mapped(kv1.clone());
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs1.push((time, accumulator));
+                }
+                for (time, snapshot) in pending2 {
+                    let mut accumulator = Accumulator::mapped(kv2.clone());
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs2.push((time, accumulator));
+                }
+                for (time, buffer) in pending {
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    outbuf.push((time, buffer));
+                }
+            }
+        }
+
+        let stats = stats::Handle::new();
+        let handle = stats.clone();
 
-        Collection::new(self.inner.binary_notify(&stream2.inner, exch1, exch2, "Join", vec![], move |input1, input2, output, notificator| {
+        let stream = self.inner.binary_notify(&stream2.inner, exch1, exch2, "Join", notify, move |input1, input2, output, notificator| {
 
             // consider shutting down each trace if the opposing input has closed out
             if trace2.is_some() && notificator.frontier(0).len() == 0 && inputs1.len() == 0 { trace2 = None; }
@@ -323,78 +392,77 @@ impl<G: Scope, D1: Data> JoinByCore<G, D1> for Collection<G, D1> where G::Timest
             // read input 1, push key, (val,wgt) to queues
             while let Some((time, data)) = input1.next() {
                 notificator.notify_at(&time);
-                inputs1.entry_or_insert(time.clone(), || Vec::new())
-                       .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                logging::log(operator, "Join", &time, Kind::Batch { input: 0, records: data.len() });
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs1.entry_or_insert(time.clone(), || Accumulator::mapped(kv1.clone()))
+                       .push(batch)
+                       .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             // read input 2, push key, (val,wgt) to queues
             while let Some((time, data)) = input2.next() {
                 notificator.notify_at(&time);
-                inputs2.entry_or_insert(time.clone(), || Vec::new())
-                       .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                logging::log(operator, "Join", &time, Kind::Batch { input: 1, records: data.len() });
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs2.entry_or_insert(time.clone(), || Accumulator::mapped(kv2.clone()))
+                       .push(batch)
+                       .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             // check to see if we have inputs to process
             while let Some((time, _count)) = notificator.next() {
 
-                if let Some(mut queue) = inputs1.remove_key(&time) {
+                if let Some(accumulator) = inputs1.remove_key(&time) {
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter1.extend(element.into_iter().map(|(d,w)| (kv1(d),w)), &|x| key_h(&(x.0).0));
-                        }
-                        let mut sorted = sorter1.finish(&|x| key_h(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
-                        sorted.truncate(256);
-                        sorter1.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).map(|(d,w)| (kv1(d),w)).collect::<Vec<_>>();
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter1, &|k| key_h(k));
 
-                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
-                    };
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "Join", &time, Kind::Sort { input: 0, keys: keys, nanoseconds: logging::now() - start });
 
                     if let Some(compact) = compact {
                         if let Some(trace) = trace2.as_ref() {
+                            let start = logging::now();
                             process_diffs(&time, &compact, &trace, &result, &mut outbuf);
+                            logging::log(operator, "Join", &time, Kind::Keys { keys: compact.keys.len(), nanoseconds: logging::now() - start });
                         }
 
                         if let Some(trace) = trace1.as_mut() {
+                            let retracted = compact.retracted_keys();
+                            if retracted.len() > 0 { retire1.push((time.clone(), retracted)); }
+                            let size = compact.size();
+                            if let Some(ref mut log) = trace1_log {
+                                log.append(&time, &compact).expect("failed to log difference");
+                            }
                             trace.set_difference(time.clone(), compact);
+                            logging::log(operator, "Join", &time, Kind::Install { input: 0, links: trace.link_count(), times: trace.time_count(), size: size });
                         }
                     }
                 }
 
-                if let Some(mut queue) = inputs2.remove_key(&time) {
+                if let Some(accumulator) = inputs2.remove_key(&time) {
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter2.extend(element.into_iter().map(|(d,w)| (kv2(d),w)), &|x| key_h(&(x.0).0));
-                        }
-                        let mut sorted = sorter2.finish(&|x| key_h(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key_h(k));
-                        sorted.truncate(256);
-                        sorter2.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).map(|(d,w)| (kv2(d),w)).collect::<Vec<_>>();
-                        vec.sort_by(|x,y| key_h(&(x.0).0).cmp(&key_h((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key_h(k))
-                    };
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter2, &|k| key_h(k));
+
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "Join", &time, Kind::Sort { input: 1, keys: keys, nanoseconds: logging::now() - start });
 
                     if let Some(compact) = compact {
                         if let Some(trace) = trace1.as_ref() {
+                            let start = logging::now();
                             process_diffs(&time, &compact, &trace, &|k,x,y| result(k,y,x), &mut outbuf);
+                            logging::log(operator, "Join", &time, Kind::Keys { keys: compact.keys.len(), nanoseconds: logging::now() - start });
                         }
                         if let Some(trace) = trace2.as_mut() {
+                            let retracted = compact.retracted_keys();
+                            if retracted.len() > 0 { retire2.push((time.clone(), retracted)); }
+                            let size = compact.size();
+                            if let Some(ref mut log) = trace2_log {
+                                log.append(&time, &compact).expect("failed to log difference");
+                            }
                             trace.set_difference(time.clone(), compact);
+                            logging::log(operator, "Join", &time, Kind::Install { input: 1, links: trace.link_count(), times: trace.time_count(), size: size });
                         }
                     }
                 }
@@ -420,7 +488,48 @@ impl<G: Scope, D1: Data> JoinByCore<G, D1> for Collection<G, D1> where G::Timest
                 //     output.session(&time).give_iterator(vals.drain(..));
                 // }
             }
-        }))
+
+            // retire keys whose differences have cancelled, once no future time can see them.
+            if retire1.len() > 0 || retire2.len() > 0 {
+                let frontier = notificator.frontier(0).iter().chain(notificator.frontier(1).iter()).cloned().collect::<Vec<_>>();
+                while let Some(position) = retire1.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                    let (_, keys) = retire1.swap_remove(position);
+                    if let Some(trace) = trace1.as_mut() {
+                        for key in keys { trace.retire_key(&key, &frontier[..]); }
+                    }
+                }
+                while let Some(position) = retire2.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                    let (_, keys) = retire2.swap_remove(position);
+                    if let Some(trace) = trace2.as_mut() {
+                        for key in keys { trace.retire_key(&key, &frontier[..]); }
+                    }
+                }
+            }
+
+            // save our queues if a checkpoint awaits them; the traces are already logged.
+            if let Some(ref mut participant) = participant {
+                if participant.requested() {
+                    let frontier = notificator.frontier(0).iter().chain(notificator.frontier(1).iter()).cloned().collect::<Vec<_>>();
+                    participant.save(&frontier[..], |files| {
+                        let mut pending1 = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs1 {
+                            pending1.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        let mut pending2 = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs2 {
+                            pending2.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        Ok((pending1, pending2, outbuf.clone()))
+                    });
+                }
+            }
+
+            let stats1 = trace1.as_ref().map(|x| x.statistics()).unwrap_or(Default::default());
+            let stats2 = trace2.as_ref().map(|x| x.statistics()).unwrap_or(Default::default());
+            stats.set(stats1 + stats2 + Statistics::pending(inputs1.len() + inputs2.len(), outbuf.len()));
+        });
+
+        (Collection::new(stream), handle)
     }
 }
 
@@ -14,6 +14,9 @@ pub use self::consolidate::ConsolidateExt;
 pub use self::iterate::IterateExt;
 pub use self::join::Join;
 pub use self::threshold::Threshold;
+pub use self::upsert::Upsert;
+pub use self::changelog::Changelog;
+pub use self::sink::FileSink;
 
 pub mod threshold;
 pub mod group;
@@ -21,3 +24,6 @@ pub mod cogroup;
 pub mod consolidate;
 pub mod iterate;
 pub mod join;
+pub mod upsert;
+pub mod changelog;
+pub mod sink;
@@ -0,0 +1,229 @@
+//! Writing collections to files, one consolidated batch per completed time.
+//!
+//! Writing from `inspect` leaves files holding unconsolidated updates for times that may not yet
+//! be complete. The `write_batches` sink instead buffers the updates at each time, and once the
+//! input frontier has passed the time, consolidates them and writes them to a new file in one go,
+//! writing to a temporary file and renaming it into place. Readers therefore only ever see whole
+//! batches, each holding the complete and final updates at one time.
+//!
+//! Each worker writes the updates assigned to it to its own subdirectory, `worker-{index}`, in
+//! files `batch-{sequence}` numbered in the order written; the first line of each names its time.
+//! After writing batches, and whenever its input frontier advances, the worker records the frontier
+//! and the next sequence number in the file `committed`. A restarted writer reads this record, and
+//! discards input at times the recorded frontier has passed, whose batches were already written, so
+//! that input may be replayed from an earlier time without duplicating output. Batch files with
+//! later sequence numbers, written after the last record, are removed and will be written again.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! counts.write_batches("output/counts", |&(ref word, count), wgt| format!("{}\t{}\t{}", word, count, wgt));
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
+
+use ::{Collection, Data};
+use timely::dataflow::*;
+use timely::dataflow::operators::Unary;
+use timely::dataflow::channels::pact::Exchange;
+
+use collection::{LeastUpperBound, Lookup};
+use collection::persist::{write_durably, write_record, read_record};
+use iterators::coalesce::Coalesce;
+use logging::{self, Kind};
+
+/// Extension trait for the `write_batches` differential dataflow sink.
+pub trait FileSink<G: Scope, D: Data> where G::Timestamp: LeastUpperBound {
+    /// Writes the consolidated updates at each completed time to a file in `directory`, formatting
+    /// each record and its weight as a line with `format`.
+    ///
+    /// Returns a stream with no data, whose frontier may be probed to learn which times have been
+    /// written. Failing to write a file is fatal.
+    fn write_batches<P, F>(&self, directory: P, format: F) -> Stream<G, ()>
+    where P: AsRef<Path>, F: Fn(&D, i32)->String+'static;
+}
+
+impl<G: Scope, D: Data> FileSink<G, D> for Collection<G, D> where G::Timestamp: LeastUpperBound {
+    fn write_batches<P, F>(&self, directory: P, format: F) -> Stream<G, ()>
+    where P: AsRef<Path>, F: Fn(&D, i32)->String+'static {
+
+        let directory = directory.as_ref().join(format!("worker-{}", self.inner.scope().index()));
+        let (mut sequence, committed) = open::<G::Timestamp>(&directory).expect("failed to open batch directory");
+
+        // A map from times to received (record, wgt) pairs.
+        let mut inputs = Vec::new();
+
+        // The frontier most recently recorded in `committed`.
+        let mut recorded = committed.clone();
+
+        let operator = logging::new_operator();
+
+        let exch = Exchange::new(|x: &(D, i32)| x.0.hashed());
+        self.inner.unary_notify(exch, "FileSink", vec![], move |input, _output, notificator| {
+
+            // 1. read each input, discarding that at times already written.
+            while let Some((time, data)) = input.next() {
+                logging::log(operator, "FileSink", &time, Kind::Batch { input: 0, records: data.len() });
+                let written = committed.as_ref().map(|f| !f.iter().any(|x| x <= &time)).unwrap_or(false);
+                if !written {
+                    notificator.notify_at(&time);
+                    inputs.entry_or_insert(time.clone(), || Vec::new())
+                          .extend(::std::mem::replace(data.deref_mut(), Vec::new()));
+                }
+            }
+
+            // 2. write each completed time, each only after those of times before it.
+            let mut ready = Vec::new();
+            while let Some((index, _count)) = notificator.next() {
+                ready.push(index);
+            }
+
+            let mut written = false;
+            while let Some(position) = (0 .. ready.len()).position(|i| !ready.iter().any(|x| x < &ready[i])) {
+                let index = ready.swap_remove(position);
+                if let Some(mut batch) = inputs.remove_key(&index) {
+                    batch.sort_by(|x, y| x.0.cmp(&y.0));
+                    let batch = batch.into_iter().coalesce().collect::<Vec<_>>();
+                    if batch.len() > 0 {
+                        let path = directory.join(format!("batch-{:010}", sequence));
+                        write_durably(&path, |writer, _| {
+                            try!(writeln!(writer, "# {:?}", index));
+                            for &(ref datum, wgt) in &batch {
+                                try!(writeln!(writer, "{}", format(datum, wgt)));
+                            }
+                            Ok(())
+                        }).expect("failed to write batch");
+                        sequence += 1;
+                        written = true;
+                    }
+                }
+            }
+
+            // 3. record what has been written, so that a restarted writer neither repeats nor loses it.
+            // times whose updates cancelled write no batch, but must not be replayed either.
+            let frontier = notificator.frontier(0).to_vec();
+            if written || recorded.as_ref() != Some(&frontier) {
+                let mut record = (sequence, frontier.clone());
+                write_durably(&directory.join("committed"), |writer, bytes| {
+                    write_record(writer, &mut record, bytes)
+                }).expect("failed to record written batches");
+                recorded = Some(frontier);
+            }
+        })
+    }
+}
+
+/// Prepares `directory` for writing, returning the next sequence number and the recorded frontier.
+///
+/// Removes temporary files, and batch files written after the recorded frontier.
+fn open<T: ::timely::Data>(directory: &Path) -> io::Result<(u64, Option<Vec<T>>)> {
+
+    try!(fs::create_dir_all(directory));
+
+    let committed = directory.join("committed");
+    let (sequence, frontier) = if committed.exists() {
+        let mut reader = BufReader::new(try!(File::open(&committed)));
+        let (sequence, frontier): (u64, Vec<T>) = try!(read_record(&mut reader));
+        (sequence, Some(frontier))
+    }
+    else {
+        (0, None)
+    };
+
+    for entry in try!(fs::read_dir(directory)) {
+        let path: PathBuf = try!(entry).path();
+        let name = path.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or(String::new());
+        // temporary files, and batches numbered from `sequence` on, are stale.
+        let number = if name.starts_with("batch-") { name[6 ..].parse::<u64>().ok() } else { None };
+        if name.ends_with(".tmp") || number.map(|x| x >= sequence).unwrap_or(false) {
+            try!(fs::remove_file(&path));
+        }
+    }
+
+    Ok((sequence, frontier))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+
+    use timely::dataflow::operators::Map;
+    use timely::progress::nested::product::Product;
+    use timely::progress::timestamp::RootTimestamp;
+
+    use ::Collection;
+    use collection::persist::{write_durably, write_record};
+    use testing::execute;
+    use super::{FileSink, open};
+
+    fn write(directory: &Path, updates: Vec<Vec<(u64, i32)>>) {
+        let directory = directory.to_path_buf();
+        execute(1, updates, move |input| {
+            let written = input.write_batches(&directory, |x, wgt| format!("{} {}", x, wgt));
+            Collection::new(written.map(|_| (0u64, 0)))
+        });
+    }
+
+    // the files in `directory` other than `committed`, in name order, with the lines after the
+    // first, which names the time.
+    fn batches(directory: &Path) -> Vec<(String, String)> {
+        let mut result = Vec::new();
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if name != "committed" {
+                let mut contents = String::new();
+                File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+                let body = contents.splitn(2, '\n').nth(1).unwrap_or("").to_owned();
+                result.push((name, body));
+            }
+        }
+        result.sort();
+        result
+    }
+
+    fn batch(name: &str, body: &str) -> (String, String) { (name.to_owned(), body.to_owned()) }
+
+    #[test]
+    fn replay_and_recover() {
+
+        let directory = ::std::env::temp_dir().join(format!("differential-sink-{}", ::time::precise_time_ns()));
+        let worker = directory.join("worker-0");
+
+        // the updates at time 2 cancel, and write no batch.
+        let mut updates = vec![vec![(1, 1), (2, 1)], vec![(1, -1)], vec![(3, 1), (3, -1)]];
+        write(&directory, updates.clone());
+        assert_eq!(batches(&worker), vec![batch("batch-0000000000", "1 1\n2 1\n"),
+                                          batch("batch-0000000001", "1 -1\n")]);
+
+        // the frontier is recorded once it advances, even past times that wrote no batch.
+        assert_eq!(open::<Product<RootTimestamp, u64>>(&worker).unwrap(), (2, Some(vec![])));
+
+        // as if the writer failed after recording time 0 and while writing later batches: the batch
+        // for time 1 and anything written after it are stale, as are temporary files.
+        let mut record = (1u64, vec![RootTimestamp::new(1u64)]);
+        let committed = worker.join("committed");
+        write_durably(&committed, |writer, bytes| write_record(writer, &mut record, bytes)).unwrap();
+        File::create(worker.join("batch-0000000001")).unwrap();
+        File::create(worker.join("batch-0000000007")).unwrap();
+        File::create(worker.join("batch-0000000002.tmp")).unwrap();
+
+        // replay the input from time 0, with a further time; time 0 must not be written again.
+        updates.push(vec![(4, 1)]);
+        write(&directory, updates);
+        assert_eq!(batches(&worker), vec![
+            batch("batch-0000000000", "1 1\n2 1\n"),
+            batch("batch-0000000001", "1 -1\n"),
+            batch("batch-0000000002", "4 1\n"),
+        ]);
+        assert_eq!(open::<Product<RootTimestamp, u64>>(&worker).unwrap(), (3, Some(vec![])));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
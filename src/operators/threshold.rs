@@ -14,27 +14,47 @@ use timely_sort::{LSBRadixSorter, Unsigned};
 use collection::{LeastUpperBound, Lookup};
 use collection::count::{Count, Offset};
 use collection::compact::Compact;
+use collection::accumulator::{Accumulator, Snapshot};
+use logging::{self, Kind};
+use stats::{self, Statistics};
+use checkpoint;
 
 /// Extension trait for the `group` differential dataflow method
 pub trait Threshold<G: Scope, D: Data+Default+'static>
     where G::Timestamp: LeastUpperBound {
+    /// Replaces the count of each record with `function` applied to the record and its count.
+    ///
+    /// `key_h` partitions and sorts records, and `look` constructs the map from records to their
+    /// counts; `|_| RHHMap::new(|x: &D| x.hashed() as usize)` is a good choice unless records are
+    /// dense unsigned integers, in which case `|x| DenseMap::new(x)` is better.
     fn threshold<
         F: Fn(&D, i32)->i32+'static,
         U: Unsigned+Default+'static,
         KeyH: Fn(&D)->U+'static,
         Look:  Lookup<D, Offset>+'static,
         LookG: Fn(u64)->Look+'static,
-        >(&self, key_h: KeyH, look: LookG, function: F) -> Collection<G, D>;
+        >(&self, key_h: KeyH, look: LookG, function: F) -> Collection<G, D> {
+        self.threshold_with_stats(key_h, look, function).0
+    }
+
+    /// As `threshold`, but also returns a handle reporting the sizes of the operator's state.
+    fn threshold_with_stats<
+        F: Fn(&D, i32)->i32+'static,
+        U: Unsigned+Default+'static,
+        KeyH: Fn(&D)->U+'static,
+        Look:  Lookup<D, Offset>+'static,
+        LookG: Fn(u64)->Look+'static,
+        >(&self, key_h: KeyH, look: LookG, function: F) -> (Collection<G, D>, stats::Handle);
 }
 
 impl<G: Scope, D: Data+Default+'static> Threshold<G, D> for Collection<G, D> where G::Timestamp: LeastUpperBound {
-    fn threshold<
+    fn threshold_with_stats<
         F: Fn(&D, i32)->i32+'static,
         U: Unsigned+Default+'static,
         KeyH: Fn(&D)->U+'static,
         Look:  Lookup<D, Offset>+'static,
         LookG: Fn(u64)->Look+'static,
-        >(&self, key_h: KeyH, look: LookG, function: F) -> Collection<G, D> {
+        >(&self, key_h: KeyH, look: LookG, function: F) -> (Collection<G, D>, stats::Handle) {
 
         let mut source = Count::new(look(0));
         let mut result = Count::new(look(0));
@@ -45,41 +65,63 @@ impl<G: Scope, D: Data+Default+'static> Threshold<G, D> for Collection<G, D> whe
         // A map from times to a list of keys that need processing at that time.
         let mut to_do = Vec::new();
 
+        // A map from times to a list of keys whose count was zero at that time, and which may be
+        // retired from both traces once the frontier has passed the time.
+        let mut retire = Vec::new();
+
         let mut sorter = LSBRadixSorter::new();
+        let kv = Rc::new(|d: D| (d, ()));
+
+        let operator = logging::new_operator();
+
+        // log differences for checkpoints, and restore the traces and queues of any checkpoint.
+        let mut participant = checkpoint::participant("Count");
+        let mut source_log = participant.as_ref().map(|x| x.log("source", |time, compact| source.set_difference(time, compact)));
+        let mut result_log = participant.as_ref().map(|x| x.log("result", |time, compact| result.set_difference(time, compact)));
+        let mut notify = Vec::new();
+        if let Some(ref participant) = participant {
+            if let Some((pending, keys)) = participant.restored::<(Vec<(G::Timestamp, Snapshot<D, ()>)>, Vec<(G::Timestamp, Vec<D>)>)>() {
+                for (time, snapshot) in pending {
+                    let mut accumulator = Accumulator::mapped(kv.clone());
+                    accumulator.restore(snapshot, participant.directory()).unwrap_or_else(|error| panic!("failed to restore input: {}", error));
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    inputs.push((time, accumulator));
+                }
+                for (time, keys) in keys {
+                    if !notify.contains(&time) { notify.push(time.clone()); }
+                    to_do.push((time, keys));
+                }
+            }
+        }
 
         let key1 = Rc::new(key_h);
         let key2 = key1.clone();
 
-        Collection::new(self.inner.unary_notify(Exchange::new(move |x: &(D, i32)| key1(&x.0).as_u64()), "Count", vec![], move |input, output, notificator| {
+        let stats = stats::Handle::new();
+        let handle = stats.clone();
+
+        let stream = self.inner.unary_notify(Exchange::new(move |x: &(D, i32)| key1(&x.0).as_u64()), "Count", notify, move |input, output, notificator| {
 
             while let Some((time, data)) = input.next() {
+                logging::log(operator, "Count", &time, Kind::Batch { input: 0, records: data.len() });
                 notificator.notify_at(&time);
-                inputs.entry_or_insert(time.clone(), || Vec::new())
-                      .push(::std::mem::replace(data.deref_mut(), Vec::new()));
+                let batch = ::std::mem::replace(data.deref_mut(), Vec::new());
+                inputs.entry_or_insert(time.clone(), || Accumulator::mapped(kv.clone()))
+                      .push(batch)
+                      .unwrap_or_else(|error| panic!("failed to spill input to disk: {}", error));
             }
 
             while let Some((index, _count)) = notificator.next() {
 
                 // 2a. fetch any data associated with this time.
-                if let Some(mut queue) = inputs.remove_key(&index) {
+                if let Some(accumulator) = inputs.remove_key(&index) {
+
+                    let start = logging::now();
+                    let compact = accumulator.finish(&mut sorter, &|k| key2(k));
+
+                    let keys = compact.as_ref().map(|x| x.keys.len()).unwrap_or(0);
+                    logging::log(operator, "Count", &index, Kind::Sort { input: 0, keys: keys, nanoseconds: logging::now() - start });
 
-                    // sort things; radix if many, .sort_by if few.
-                    let compact = if queue.len() > 1 {
-                        for element in queue.into_iter() {
-                            sorter.extend(element.into_iter().map(|(d,w)| ((d,()),w)), &|x| key2(&(x.0).0));
-                        }
-                        let mut sorted = sorter.finish(&|x| key2(&(x.0).0));
-                        let result = Compact::from_radix(&mut sorted, &|k| key2(k));
-                        sorted.truncate(256);
-                        sorter.recycle(sorted);
-                        result
-                    }
-                    else {
-                        let mut vec = queue.pop().unwrap();
-                        let mut vec = vec.drain(..).map(|(d,w)| ((d,()),w)).collect::<Vec<_>>();
-                        vec.sort_by(|x,y| key2(&(x.0).0).cmp(&key2((&(y.0).0))));
-                        Compact::from_radix(&mut vec![vec], &|k| key2(k))
-                    };
                     if let Some(compact) = compact {
 
                         for key in &compact.keys {
@@ -89,7 +131,12 @@ impl<G: Scope, D: Data+Default+'static> Threshold<G, D> for Collection<G, D> whe
                             }
                         }
 
+                        let size = compact.size();
+                        if let Some(ref mut log) = source_log {
+                            log.append(&index, &compact).expect("failed to log difference");
+                        }
                         source.set_difference(index.clone(), compact);
+                        logging::log(operator, "Count", &index, Kind::Install { input: 0, links: source.link_count(), times: source.time_count(), size: size });
                     }
                 }
 
@@ -109,12 +156,19 @@ impl<G: Scope, D: Data+Default+'static> Threshold<G, D> for Collection<G, D> whe
                     keys.sort_by(|x,y| (key2(x), x).cmp(&(key2(y), y)));
                     keys.dedup();
 
+                    let start = logging::now();
+                    let processed = keys.len();
+
                     // accumulations for installation into result
                     let mut accumulation = Compact::new(0,0);
 
+                    // keys with zero count, which may have cancelled out entirely
+                    let mut empty = Vec::new();
+
                     for key in keys {
 
                         let count = source.get_count(&key, &index);
+                        if count == 0 { empty.push(key.clone()); }
                         let output = if count > 0 { function(&key, count) } else { 0 };
                         let current = result.get_count(&key, &index);
 
@@ -126,12 +180,47 @@ impl<G: Scope, D: Data+Default+'static> Threshold<G, D> for Collection<G, D> whe
                         }
                     }
 
+                    logging::log(operator, "Count", &index, Kind::Keys { keys: processed, nanoseconds: logging::now() - start });
+
                     if accumulation.vals.len() > 0 {
+                        if let Some(ref mut log) = result_log {
+                            log.append(&index, &accumulation).expect("failed to log difference");
+                        }
                         result.set_difference(index.clone(), accumulation);
                     }
+
+                    if empty.len() > 0 {
+                        retire.push((index.clone(), empty));
+                    }
                 }
             }
 
-        }))
+            // retire keys whose input and output have cancelled, once no future time can see them.
+            let frontier = notificator.frontier(0);
+            while let Some(position) = retire.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                let (_, keys) = retire.swap_remove(position);
+                for key in keys {
+                    source.retire_key(&key, frontier);
+                    result.retire_key(&key, frontier);
+                }
+            }
+
+            // save our queues if a checkpoint awaits them; the traces are already logged.
+            if let Some(ref mut participant) = participant {
+                if participant.requested() {
+                    participant.save(notificator.frontier(0), |files| {
+                        let mut pending = Vec::new();
+                        for &(ref time, ref accumulator) in &inputs {
+                            pending.push((time.clone(), try!(accumulator.snapshot(files))));
+                        }
+                        Ok((pending, to_do.clone()))
+                    });
+                }
+            }
+
+            stats.set(source.statistics() + result.statistics() + Statistics::pending(inputs.len(), to_do.len()));
+        });
+
+        (Collection::new(stream), handle)
     }
 }
@@ -0,0 +1,259 @@
+//! Conversion of keyed upserts into differences.
+//!
+//! Many sources report the state of a key rather than changes to a collection: "key `k` now has
+//! value `v`", or "key `k` is deleted". The `upsert` operator consumes a stream of such updates,
+//! `(K, Option<V>)`, maintains the value of each key in a trace, and produces the collection of
+//! `(K, V)` pairs whose differences retract each key's previous value and introduce its new one.
+//!
+//! Updates at a time are applied in the order they are received, so that the last update for a key
+//! at a time determines its value. Updates for a key are routed to one worker, and timely preserves
+//! the order of records sent between a pair of workers, so this is the order in which they were
+//! introduced as long as each key is introduced by a single worker.
+//!
+//! Times are applied once complete, and in an order consistent with the partial order on times. An
+//! update replaces the value accumulated at its time; updates to a key at incomparable times both
+//! apply at their least upper bound, where the key may then have more than one value.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let (input, updates) = scope.new_input::<(String, Option<u64>)>();
+//! let prices = updates.upsert();
+//! ```
+
+use std::ops::DerefMut;
+
+use ::{Collection, Data};
+use timely::dataflow::*;
+use timely::dataflow::operators::Unary;
+use timely::dataflow::channels::pact::Exchange;
+
+use timely_sort::Unsigned;
+
+use collection::{LeastUpperBound, Lookup, Trace, Offset};
+use collection::compact::Compact;
+use collection::robin_hood::RHHMap;
+use arrangement::Query;
+use logging::{self, Kind};
+use stats::{self, Statistics};
+use checkpoint;
+
+/// Extension trait for the `upsert` differential dataflow method.
+pub trait Upsert<G: Scope, K: Data, V: Data> where G::Timestamp: LeastUpperBound {
+
+    /// Converts updates setting (`Some`) or deleting (`None`) the value of keys into a collection
+    /// of the current value of each key.
+    fn upsert(&self) -> Collection<G, (K, V)> {
+        self.upsert_by(|k| k.hashed(), |_| RHHMap::new(|x: &K| x.hashed() as usize))
+    }
+
+    /// As `upsert`, with `key_h` to partition and sort keys, and `look` to construct the map from
+    /// keys to the operator's trace.
+    fn upsert_by<
+        U:     Unsigned+Default,
+        KeyH:  Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        >(&self, key_h: KeyH, look: LookG) -> Collection<G, (K, V)> {
+        self.upsert_with_stats(key_h, look).0
+    }
+
+    /// As `upsert_by`, but also returns a handle reporting the sizes of the operator's state.
+    fn upsert_with_stats<
+        U:     Unsigned+Default,
+        KeyH:  Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        >(&self, key_h: KeyH, look: LookG) -> (Collection<G, (K, V)>, stats::Handle);
+}
+
+impl<G: Scope, K: Data, V: Data> Upsert<G, K, V> for Stream<G, (K, Option<V>)> where G::Timestamp: LeastUpperBound {
+    fn upsert_with_stats<
+        U:     Unsigned+Default,
+        KeyH:  Fn(&K)->U+'static,
+        Look:  Lookup<K, Offset>+'static,
+        LookG: Fn(u64)->Look,
+        >(&self, key_h: KeyH, look: LookG) -> (Collection<G, (K, V)>, stats::Handle) {
+
+        let peers = self.scope().peers();
+        let mut log_peers = 0;
+        while (1 << (log_peers + 1)) <= peers {
+            log_peers += 1;
+        }
+
+        // the value of each key, as a trace of differences.
+        let mut values = Trace::new(look(log_peers));
+
+        // A map from times to received updates, in the order received.
+        let mut inputs = Vec::new();
+
+        // A map from times to a list of keys deleted at that time, which may be retired from the
+        // trace once the frontier has passed the time.
+        let mut retire = Vec::new();
+
+        let operator = logging::new_operator();
+
+        // log differences for checkpoints, and restore the trace and queue of any checkpoint.
+        let mut participant = checkpoint::participant("Upsert");
+        let mut values_log = participant.as_ref().map(|x| x.log("values", |time, compact| values.set_difference(time, compact)));
+        let mut notify = Vec::new();
+        if let Some(pending) = participant.as_ref().and_then(|x| x.restored::<Vec<(G::Timestamp, Vec<(K, Option<V>)>)>>()) {
+            for (time, updates) in pending {
+                notify.push(time.clone());
+                inputs.push((time, updates));
+            }
+        }
+
+        let key_h = ::std::rc::Rc::new(key_h);
+        let key_x = key_h.clone();
+
+        let stats = stats::Handle::new();
+        let handle = stats.clone();
+
+        let exch = Exchange::new(move |x: &(K, Option<V>)| key_x(&x.0).as_u64());
+        let stream = self.unary_notify(exch, "Upsert", notify, move |input, output, notificator| {
+
+            // 1. read each input, and stash it in our staging area.
+            while let Some((time, data)) = input.next() {
+                logging::log(operator, "Upsert", &time, Kind::Batch { input: 0, records: data.len() });
+                notificator.notify_at(&time);
+                inputs.entry_or_insert(time.clone(), || Vec::new())
+                      .extend(::std::mem::replace(data.deref_mut(), Vec::new()));
+            }
+
+            // 2. apply the updates of completed times, each only after those of times before it.
+            let mut ready = Vec::new();
+            while let Some((index, _count)) = notificator.next() {
+                ready.push(index);
+            }
+            while let Some(position) = (0 .. ready.len()).position(|i| !ready.iter().any(|x| x < &ready[i])) {
+
+                let index = ready.swap_remove(position);
+
+                if let Some(updates) = inputs.remove_key(&index) {
+
+                    let start = logging::now();
+
+                    // the last update for each key wins; sort stably, and keep the last of each run.
+                    let mut updates = updates;
+                    updates.sort_by(|x,y| (key_h(&x.0), &x.0).cmp(&(key_h(&y.0), &y.0)));
+                    let mut latest: Vec<(K, Option<V>)> = Vec::with_capacity(updates.len());
+                    for (key, val) in updates {
+                        let replace = latest.last().map(|x| x.0 == key).unwrap_or(false);
+                        if replace { latest.pop(); }
+                        latest.push((key, val));
+                    }
+
+                    let processed = latest.len();
+                    let mut session = output.session(&index);
+                    let mut accumulation = Compact::new(0,0);
+                    let mut deleted = Vec::new();
+
+                    for (key, val) in latest {
+
+                        // retract the current values, and introduce the new one.
+                        let mut diffs = values.collection(&key, &index)
+                                              .into_iter()
+                                              .map(|(v, w)| (v, -w))
+                                              .collect::<Vec<_>>();
+                        match val {
+                            Some(val) => diffs.push((val, 1)),
+                            None => deleted.push(key.clone()),
+                        }
+                        diffs.sort_by(|x,y| x.0.cmp(&y.0));
+
+                        let mut compact = accumulation.session();
+                        let mut diffs = diffs.into_iter().peekable();
+                        while let Some((val, mut wgt)) = diffs.next() {
+                            while diffs.peek().map(|x| x.0 == val).unwrap_or(false) {
+                                wgt += diffs.next().unwrap().1;
+                            }
+                            if wgt != 0 {
+                                session.give(((key.clone(), val.clone()), wgt));
+                                compact.push(val, wgt);
+                            }
+                        }
+                        compact.done(key);
+                    }
+
+                    logging::log(operator, "Upsert", &index, Kind::Keys { keys: processed, nanoseconds: logging::now() - start });
+
+                    if accumulation.vals.len() > 0 {
+                        let size = accumulation.size();
+                        if let Some(ref mut log) = values_log {
+                            log.append(&index, &accumulation).expect("failed to log difference");
+                        }
+                        values.set_difference(index.clone(), accumulation);
+                        logging::log(operator, "Upsert", &index, Kind::Install { input: 0, links: values.link_count(), times: values.time_count(), size: size });
+                    }
+
+                    if deleted.len() > 0 {
+                        retire.push((index.clone(), deleted));
+                    }
+                }
+            }
+
+            // 3. retire deleted keys, once no future time can see them.
+            let frontier = notificator.frontier(0);
+            while let Some(position) = retire.iter().position(|x| frontier.iter().all(|f| &x.0 <= f)) {
+                let (_, keys) = retire.swap_remove(position);
+                for key in keys {
+                    values.retire_key(&key, frontier);
+                }
+            }
+
+            // 4. save our queue if a checkpoint awaits it; the trace is already logged.
+            if let Some(ref mut participant) = participant {
+                if participant.requested() {
+                    participant.save(notificator.frontier(0), |_| Ok(inputs.clone()));
+                }
+            }
+
+            stats.set(values.statistics() + Statistics::pending(inputs.len(), 0));
+        });
+
+        (Collection::new(stream), handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use timely::dataflow::operators::Map;
+
+    use testing::execute;
+    use super::Upsert;
+
+    // upserts at each time, as `(key, value)` with `None` deleting the key.
+    fn upserts() -> Vec<Vec<((u64, Option<u64>), i32)>> {
+        vec![
+            // insert three keys, delete an absent key, and set one key twice.
+            vec![((1, Some(10)), 1), ((2, Some(20)), 1), ((3, None), 1), ((4, Some(40)), 1), ((4, Some(41)), 1)],
+            // update a key, delete a key, and delete an absent key again.
+            vec![((1, Some(11)), 1), ((2, None), 1), ((3, None), 1)],
+            // re-insert a deleted key, and set a key to its current value.
+            vec![((2, Some(21)), 1), ((4, Some(41)), 1)],
+        ]
+    }
+
+    #[test]
+    fn upsert_differences() {
+        let output = execute(1, upserts(), |input| input.inner.map(|(update, _)| update).upsert());
+        assert_eq!(output, vec![
+            (0, (1, 10), 1), (0, (2, 20), 1), (0, (4, 41), 1),
+            (1, (1, 10), -1), (1, (1, 11), 1), (1, (2, 20), -1),
+            (2, (2, 21), 1),
+        ]);
+    }
+
+    #[test]
+    fn upsert_workers_agree() {
+        // updates for a key at a time may reach its worker in any order from several workers, so
+        // drop the second update for key 4.
+        let mut updates = upserts();
+        updates[0].pop();
+        let single = execute(1, updates.clone(), |input| input.inner.map(|(update, _)| update).upsert());
+        let multiple = execute(3, updates.clone(), |input| input.inner.map(|(update, _)| update).upsert());
+        assert_eq!(single, multiple);
+    }
+}
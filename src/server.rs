@@ -0,0 +1,253 @@
+//! Serving reads of maintained collections to other local processes.
+//!
+//! A `Server` listens on a loopback TCP address or a Unix socket, and answers requests for the
+//! collection of a key using an `arrangement::Handle`. The server is not a thread of its own: the
+//! worker calls `poll` between steps of the computation, which accepts connections, reads requests,
+//! and answers those it can without blocking.
+//!
+//! Requests are answered as of the most recent time passed to `advance_to`, and only once the
+//! probe supplied to the server has passed that time, so that each reply reflects all input up to
+//! and including the time, and nothing after it. Requests received before then wait.
+//!
+//! A server reads only the trace of the worker that polls it, and a worker's trace holds only the
+//! keys exchanged to that worker: the key `key` is held by worker `key_h(key) % peers`, where
+//! `key_h` is the hash the arranging operator exchanges keys by (`Data::hashed`, for
+//! `group_arranged`). Each worker therefore binds a server of its own, and clients send each
+//! request to the server of the worker holding its key. A server asked for a key held by another
+//! worker replies with an error naming that worker, rather than an empty collection.
+//!
+//! The protocol is line oriented. Each request is a line `get <key>`, where the key is parsed with
+//! `FromStr`. A reply is a line `ok <count>` followed by `count` lines `<value> <multiplicity>`, or
+//! a single line `error <message>`; for a key held by worker `n`, the message is `worker <n>`.
+//! Replies are sent in the order requests were received.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let (mut input, probe, labels) = computation.scoped::<u64,_,_>(|scope| { ... });
+//! let address = ("127.0.0.1", 7000 + computation.index() as u16);
+//! let mut server = Server::bind_tcp(address, computation.index(), computation.peers(), |k: &u32| k.hashed(), labels, probe).unwrap();
+//! for round in 0.. {
+//!     ... supply input for round, and advance to round + 1 ...
+//!     server.advance_to(RootTimestamp::new(round));
+//!     while server.probe().le(&RootTimestamp::new(round)) { computation.step(); server.poll().unwrap(); }
+//! }
+//! ```
+
+use std::fmt::Display;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::str::FromStr;
+
+use timely::progress::Timestamp;
+use timely::dataflow::operators::probe;
+
+use arrangement;
+use socket::{Listener, Connection};
+
+/// Answers requests for the collections of keys in one worker's part of an arranged collection.
+pub struct Server<K, T: Timestamp, V> {
+    listener: Listener,
+    connections: Vec<Connection>,
+    arranged: arrangement::Handle<K, T, V>,
+    probe: probe::Handle<T>,
+    time: Option<T>,
+    owner: Owner<K>,
+}
+
+impl<K: FromStr, T: Timestamp, V: Display> Server<K, T, V> {
+
+    /// Listens at `address`, which must be a loopback address, serving reads of the `index`th of
+    /// `peers` workers' part of `arranged`, whose keys the arranging operator exchanged by `key_h`.
+    pub fn bind_tcp<A, H>(address: A, index: usize, peers: usize, key_h: H, arranged: arrangement::Handle<K, T, V>, probe: probe::Handle<T>) -> io::Result<Server<K, T, V>>
+    where A: ToSocketAddrs, H: Fn(&K)->u64+'static {
+        let owner = try!(Owner::new(index, peers, key_h));
+        let listener = try!(Listener::bind_tcp(address));
+        Ok(Server::new(listener, owner, arranged, probe))
+    }
+
+    /// Listens at a Unix socket created at `path`, serving reads as for `bind_tcp`.
+    ///
+    /// A socket left at `path` by an earlier process is replaced; any other file is an error. The
+    /// socket is removed when the server is dropped.
+    #[cfg(unix)]
+    pub fn bind_unix<P, H>(path: P, index: usize, peers: usize, key_h: H, arranged: arrangement::Handle<K, T, V>, probe: probe::Handle<T>) -> io::Result<Server<K, T, V>>
+    where P: AsRef<Path>, H: Fn(&K)->u64+'static {
+        let owner = try!(Owner::new(index, peers, key_h));
+        let listener = try!(Listener::bind_unix(path));
+        Ok(Server::new(listener, owner, arranged, probe))
+    }
+
+    fn new(listener: Listener, owner: Owner<K>, arranged: arrangement::Handle<K, T, V>, probe: probe::Handle<T>) -> Server<K, T, V> {
+        Server {
+            listener: listener,
+            connections: Vec::new(),
+            arranged: arranged,
+            probe: probe,
+            time: None,
+            owner: owner,
+        }
+    }
+
+    /// The address the server listens at, if it listens on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> { self.listener.local_addr() }
+
+    /// The probe whose frontier must pass a time before requests are answered as of it.
+    pub fn probe(&self) -> &probe::Handle<T> { &self.probe }
+
+    /// Sets the time as of which requests are answered, once the probe has passed it.
+    ///
+    /// The worker should call this with each time it finishes supplying input for.
+    pub fn advance_to(&mut self, time: T) {
+        self.time = Some(time);
+    }
+
+    /// Accepts connections, reads requests, and answers those it can, without blocking.
+    ///
+    /// Returns an error only if accepting connections fails; failed connections are dropped.
+    pub fn poll(&mut self) -> io::Result<()> {
+
+        try!(self.listener.accept(&mut self.connections));
+
+        // requests may be answered only as of a time the probe has passed.
+        let time = match self.time {
+            Some(ref time) if !self.probe.le(time) => Some(time.clone()),
+            _ => None,
+        };
+
+        for connection in self.connections.iter_mut() {
+            connection.read();
+            if let Some(ref time) = time {
+                while let Some(request) = connection.lines.pop_front() {
+                    respond(&self.arranged, &self.owner, time, &request, &mut connection.output);
+                }
+            }
+            connection.write();
+        }
+
+        self.connections.retain(|x| !x.done());
+        Ok(())
+    }
+}
+
+// identifies the worker holding each key, as the arranging operator exchanged keys.
+struct Owner<K> {
+    index: usize,
+    peers: usize,
+    key_h: Box<Fn(&K)->u64>,
+}
+
+impl<K> Owner<K> {
+    fn new<H: Fn(&K)->u64+'static>(index: usize, peers: usize, key_h: H) -> io::Result<Owner<K>> {
+        if index < peers { Ok(Owner { index: index, peers: peers, key_h: Box::new(key_h) }) }
+        else {
+            let message = format!("worker index {} out of range for {} workers", index, peers);
+            Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+        }
+    }
+    // the index of the worker holding `key`.
+    fn of(&self, key: &K) -> usize {
+        ((self.key_h)(key) % self.peers as u64) as usize
+    }
+}
+
+// appends to `output` the reply to `request`, reading `arranged` at `time` if `owner` holds the key.
+fn respond<K: FromStr, T: Timestamp, V: Display>(arranged: &arrangement::Handle<K, T, V>, owner: &Owner<K>, time: &T, request: &str, output: &mut Vec<u8>) {
+    let mut words = request.split_whitespace();
+    let reply = match (words.next(), words.next(), words.next()) {
+        (Some("get"), Some(key), None) => {
+            match key.parse::<K>() {
+                Ok(ref key) if owner.of(key) != owner.index => format!("error worker {}\n", owner.of(key)),
+                Ok(key) => {
+                    match arranged.get(&key, time) {
+                        Some(collection) => {
+                            let mut reply = format!("ok {}\n", collection.len());
+                            for (val, wgt) in collection {
+                                reply.push_str(&format!("{} {}\n", val, wgt));
+                            }
+                            reply
+                        },
+                        None => "error time not readable\n".to_owned(),
+                    }
+                },
+                Err(_) => "error invalid key\n".to_owned(),
+            }
+        },
+        _ => "error expected `get <key>`\n".to_owned(),
+    };
+    output.extend_from_slice(reply.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::io::{Read, Write, BufRead, BufReader, ErrorKind};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use timely::{self, Configuration};
+    use timely::dataflow::Scope;
+    use timely::dataflow::operators::{Input, Probe};
+    use timely::progress::timestamp::RootTimestamp;
+
+    use arrangement;
+    use collection::Trace;
+    use collection::compact::Compact;
+    use super::Server;
+
+    #[test]
+    fn refuse_bad_index() {
+        timely::execute(Configuration::Thread, |root| {
+            let (_, handle) = arrangement::shared(Trace::<u64, _, u64, _>::new(HashMap::new()));
+            let probe = root.scoped::<u64,_,_>(|scope| scope.new_input::<u64>().1.probe().0);
+            let error = Server::bind_tcp("127.0.0.1:0", 2, 2, |k: &u64| *k, handle, probe).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn reply_once_probe_passes() {
+        timely::execute(Configuration::Thread, |root| {
+
+            let (trace, handle) = arrangement::shared(Trace::new(HashMap::new()));
+            let mut compact = Compact::new(0, 0);
+            compact.extend(vec![((1u64, 10u64), 1), ((1, 11), 2)].into_iter());
+            trace.borrow_mut().set_difference(RootTimestamp::new(0), compact);
+            handle.advance(&[RootTimestamp::new(1)]);
+
+            let (mut input, probe) = root.scoped::<u64,_,_>(|scope| {
+                let (input, stream) = scope.new_input::<u64>();
+                (input, stream.probe().0)
+            });
+
+            // serve as the second of two workers, which holds the odd keys.
+            let mut server = Server::bind_tcp("127.0.0.1:0", 1, 2, |k: &u64| *k, handle, probe).unwrap();
+            let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            client.write_all(b"get 1\nget 2\nget x\nfoo\n").unwrap();
+            server.advance_to(RootTimestamp::new(0));
+
+            // the input has not advanced past time 0, so the requests must wait.
+            for _ in 0 .. 20 {
+                root.step();
+                server.poll().unwrap();
+                ::std::thread::sleep(Duration::from_millis(1));
+            }
+            client.set_nonblocking(true).unwrap();
+            let early = client.read(&mut [0u8; 16]);
+            assert_eq!(early.err().map(|e| e.kind()), Some(ErrorKind::WouldBlock));
+            client.set_nonblocking(false).unwrap();
+
+            input.advance_to(1);
+            while server.probe().le(&RootTimestamp::new(0)) { root.step(); }
+            for _ in 0 .. 10 { server.poll().unwrap(); }
+
+            let mut reader = BufReader::new(client);
+            let mut reply = String::new();
+            for _ in 0 .. 6 { reader.read_line(&mut reply).unwrap(); }
+            assert_eq!(reply, "ok 2\n10 1\n11 2\nerror worker 0\nerror invalid key\nerror expected `get <key>`\n");
+        });
+    }
+}
@@ -0,0 +1,198 @@
+//! Nonblocking line-oriented connections over loopback TCP or Unix sockets.
+//!
+//! Both the `server` and the socket input source talk to other local processes a line at a time,
+//! without threads of their own: the worker polls them between steps of the computation, and they
+//! accept connections, read and write what they can, and return without blocking.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// A source of connections.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+
+    /// Listens at `address`, which must be a loopback address.
+    pub fn bind_tcp<A: ToSocketAddrs>(address: A) -> io::Result<Listener> {
+        let listener = try!(TcpListener::bind(address));
+        if !try!(listener.local_addr()).ip().is_loopback() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "must listen at a loopback address"));
+        }
+        try!(listener.set_nonblocking(true));
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Listens at a Unix socket created at `path`.
+    ///
+    /// A socket left at `path` by an earlier process is replaced; any other file is an error. The
+    /// socket is removed when the listener is dropped.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Listener> {
+        use std::os::unix::fs::FileTypeExt;
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = ::std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                try!(::std::fs::remove_file(&path));
+            }
+        }
+        let listener = try!(UnixListener::bind(&path));
+        try!(listener.set_nonblocking(true));
+        Ok(Listener::Unix(listener, path))
+    }
+
+    /// The address the listener listens at, if it listens on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_, _) => None,
+        }
+    }
+
+    /// Accepts any waiting connections, adding them to `connections`.
+    pub fn accept(&self, connections: &mut Vec<Connection>) -> io::Result<()> {
+        loop {
+            let accepted = match *self {
+                Listener::Tcp(ref listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+                #[cfg(unix)]
+                Listener::Unix(ref listener, _) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+            };
+            match accepted {
+                Ok(stream) => {
+                    try!(stream.set_nonblocking(true));
+                    connections.push(Connection::new(stream));
+                },
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => { },
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        match *self {
+            Listener::Tcp(_) => { },
+            #[cfg(unix)]
+            Listener::Unix(_, ref path) => { let _ = ::std::fs::remove_file(path); },
+        }
+    }
+}
+
+/// A connection to a client.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(ref stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// A client connection, with its unparsed input, unhandled lines, and unsent output.
+pub struct Connection {
+    stream: Stream,
+    input: Vec<u8>,
+    pub lines: VecDeque<String>,
+    pub output: Vec<u8>,
+    finished: bool,     // the client has closed its end; answer what remains and close ours.
+    failed: bool,       // the connection has failed, and should be dropped.
+}
+
+impl Connection {
+
+    fn new(stream: Stream) -> Connection {
+        Connection {
+            stream: stream,
+            input: Vec::new(),
+            lines: VecDeque::new(),
+            output: Vec::new(),
+            finished: false,
+            failed: false,
+        }
+    }
+
+    /// Reads whatever input is available, and splits complete non-empty lines off into `lines`.
+    pub fn read(&mut self) {
+        let mut buffer = [0u8; 4096];
+        while !self.finished && !self.failed {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.finished = true,
+                Ok(bytes) => self.input.extend_from_slice(&buffer[..bytes]),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => { },
+                Err(_) => self.failed = true,
+            }
+        }
+        while let Some(position) = self.input.iter().position(|&x| x == b'\n') {
+            let line = self.input.drain(.. position + 1).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line).trim().to_owned();
+            if line.len() > 0 {
+                self.lines.push_back(line);
+            }
+        }
+    }
+
+    /// Writes as much of `output` as the stream will accept.
+    pub fn write(&mut self) {
+        while self.output.len() > 0 && !self.failed {
+            match self.stream.write(&self.output[..]) {
+                Ok(0) => self.failed = true,
+                Ok(bytes) => { self.output.drain(.. bytes); },
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => { },
+                Err(_) => self.failed = true,
+            }
+        }
+    }
+
+    /// Reports whether the connection has failed, or has been closed by the client and has nothing
+    /// left to handle or send, and so may be dropped.
+    pub fn done(&self) -> bool {
+        self.failed || (self.finished && self.lines.is_empty() && self.output.is_empty())
+    }
+}
@@ -0,0 +1,89 @@
+//! Statistics describing the state held by differential dataflow operators.
+//!
+//! The stateful operators (`group`, `join`, `cogroup`, and `threshold`) maintain traces of their
+//! inputs and outputs, as well as queues of data received but not yet processed. Each has a variant
+//! of its lowest level method suffixed with `_with_stats`, which returns a `Handle` alongside the
+//! output collection. The handle reports the sizes of these structures as of the most recent time
+//! the operator was scheduled, which is handy for dashboards and for noticing state that grows
+//! without bound.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let (counts, stats) = edges.threshold_with_stats(|x| x.hashed(), |_| HashMap::new(), |_, w| w);
+//! ...
+//! println!("threshold holds {} keys in {} links", stats.get().keys, stats.get().links);
+//! ```
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::ops::Add;
+
+/// The sizes of the structures maintained by an operator on one worker.
+///
+/// Trace statistics are summed across all traces the operator maintains; `group`, for example,
+/// maintains traces of both its input and its output.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Statistics {
+    /// The number of distinct keys indexed.
+    pub keys: usize,
+    /// The number of `(key, time)` links.
+    pub links: usize,
+    /// The number of differences installed, one for each time with data.
+    pub times: usize,
+    /// The number of bytes occupied by values and their weights.
+    pub value_bytes: usize,
+    /// The number of times with received input awaiting notification, summed across inputs.
+    pub pending_inputs: usize,
+    /// The number of times with keys to reconsider or output to send, awaiting notification.
+    pub pending_work: usize,
+}
+
+impl Statistics {
+    /// Statistics describing only pending work, as counted from an operator's queues.
+    pub fn pending(inputs: usize, work: usize) -> Statistics {
+        Statistics {
+            pending_inputs: inputs,
+            pending_work: work,
+            .. Default::default()
+        }
+    }
+}
+
+impl Add for Statistics {
+    type Output = Statistics;
+    fn add(self, other: Statistics) -> Statistics {
+        Statistics {
+            keys:           self.keys + other.keys,
+            links:          self.links + other.links,
+            times:          self.times + other.times,
+            value_bytes:    self.value_bytes + other.value_bytes,
+            pending_inputs: self.pending_inputs + other.pending_inputs,
+            pending_work:   self.pending_work + other.pending_work,
+        }
+    }
+}
+
+/// A shared reference to the most recently reported `Statistics` of an operator.
+///
+/// The operator holds one copy of the handle and updates it each time it is scheduled; the other
+/// copy is returned to the dataflow constructor, and may be read at any point from the worker.
+#[derive(Clone, Default)]
+pub struct Handle {
+    stats: Rc<RefCell<Statistics>>,
+}
+
+impl Handle {
+    /// Allocates a new handle reporting empty statistics.
+    pub fn new() -> Handle {
+        Handle { stats: Rc::new(RefCell::new(Default::default())) }
+    }
+    /// Reads the most recently reported statistics.
+    pub fn get(&self) -> Statistics {
+        *self.stats.borrow()
+    }
+    /// Reports new statistics, replacing those previously reported.
+    pub fn set(&self, stats: Statistics) {
+        *self.stats.borrow_mut() = stats;
+    }
+}
@@ -0,0 +1,382 @@
+//! Randomized testing of differential dataflow computations against reference implementations.
+//!
+//! Differential dataflow operators are intricate, and their bugs tend to show up only for specific
+//! sequences of changes (a record deleted at one time and re-added at a later time, a key whose
+//! values all cancel, and so on). The most reliable way we know to find such bugs is to drive a
+//! computation with many random insertions and deletions, and at each time compare the
+//! accumulated output against the result of a simple computation over the accumulated input.
+//!
+//! The `check` method does exactly this. It takes a function generating random input records, a
+//! function constructing the differential dataflow computation, and a reference function written
+//! in plain Rust. It runs the computation with several workers over several rounds of updates,
+//! and reports the first `(record, time, expected, actual)` at which the two disagree.
+//!
+//! The `execute` method underlying `check` is also useful on its own. It runs a computation with
+//! some number of workers on fixed inputs, distributing the inputs among the workers the same way
+//! every time, and returns the consolidated outputs of all workers sorted by time and record.
+//! Bugs that only appear with several workers (for example, in how operators index keys once they
+//! have been exchanged) can then be caught by comparing against a single-worker execution.
+//!
+//! #Examples
+//!
+//! ```ignore
+//! let settings = Settings { workers: 3, rounds: 10, updates: 100, seed: 0 };
+//! let result = check(
+//!     settings,
+//!     |rng| (rng.gen_range(0, 10) as u32, rng.gen_range(0, 10) as u32),
+//!     |edges| edges.group(|_, s, t| t.push((*s.peek().unwrap().0, 1))),
+//!     |edges| {
+//!         let mut mins = ::std::collections::BTreeMap::new();
+//!         for &((k, v), _) in edges {
+//!             let min = mins.entry(k).or_insert(v);
+//!             if v < *min { *min = v; }
+//!         }
+//!         mins.into_iter().map(|x| (x, 1)).collect()
+//!     }
+//! );
+//! assert!(result.is_ok(), "divergence: {:?}", result);
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+
+use timely::{self, Configuration};
+use timely::dataflow::Scope;
+use timely::dataflow::scopes::{Child, Root};
+use timely::dataflow::operators::Input;
+use timely::progress::timestamp::RootTimestamp;
+use timely_communication::Allocator;
+
+use ::{Collection, Data, Delta};
+
+/// The scope in which computations under test are constructed.
+pub type TestScope = Child<Root<Allocator>, u64>;
+
+/// A small deterministic pseudo-random number generator.
+///
+/// This is an xorshift generator, which is plenty random for generating test inputs and has the
+/// advantage of producing the same sequence everywhere from the same seed, without depending on
+/// the `rand` crate.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    /// Constructs a new generator from `seed`.
+    pub fn new(seed: u64) -> Generator {
+        // the all-zero state is a fixed point of xorshift, so mix in a constant.
+        Generator { state: seed ^ 0x9E3779B97F4A7C15 }
+    }
+    /// Returns the next pseudo-random `u64`.
+    #[inline]
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+    /// Returns a pseudo-random `u64` in the interval `[lower, upper)`.
+    #[inline]
+    pub fn gen_range(&mut self, lower: u64, upper: u64) -> u64 {
+        assert!(lower < upper);
+        lower + self.next_u64() % (upper - lower)
+    }
+}
+
+/// Parameters of a randomized test.
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+    /// The number of worker threads to execute the computation with.
+    pub workers: usize,
+    /// The number of distinct times at which updates are introduced.
+    pub rounds: usize,
+    /// The number of updates introduced at each time.
+    pub updates: usize,
+    /// The seed from which all updates are generated.
+    pub seed: u64,
+}
+
+/// The first point at which a computation and its reference implementation disagree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence<R> {
+    /// The record whose multiplicity differs.
+    pub record: R,
+    /// The time at which the multiplicity differs.
+    pub time: u64,
+    /// The multiplicity produced by the reference implementation.
+    pub expected: Delta,
+    /// The multiplicity produced by the computation.
+    pub actual: Delta,
+}
+
+/// Produces the updates to introduce at each round, deterministically from `settings.seed`.
+///
+/// Each update is either the insertion of a newly generated record, or the deletion of a record
+/// inserted and not yet deleted, so that the accumulated input never has negative multiplicities.
+pub fn random_updates<D: Data, G: Fn(&mut Generator)->D>(settings: &Settings, generate: &G) -> Vec<Vec<(D, Delta)>> {
+
+    let mut rng = Generator::new(settings.seed);
+    let mut present = Vec::new();
+    let mut result = Vec::with_capacity(settings.rounds);
+
+    for _ in 0 .. settings.rounds {
+        let mut round = Vec::with_capacity(settings.updates);
+        for _ in 0 .. settings.updates {
+            if present.len() > 0 && rng.gen_range(0, 3) == 0 {
+                let index = rng.gen_range(0, present.len() as u64) as usize;
+                round.push((present.swap_remove(index), -1));
+            }
+            else {
+                let record = generate(&mut rng);
+                present.push(record.clone());
+                round.push((record, 1));
+            }
+        }
+        result.push(round);
+    }
+
+    result
+}
+
+/// Accumulates `(record, delta)` pairs into a map from records to their non-zero multiplicities.
+pub fn accumulate<D: Ord, I: Iterator<Item=(D, Delta)>>(updates: I) -> BTreeMap<D, Delta> {
+    let mut result = BTreeMap::new();
+    for (record, delta) in updates {
+        *result.entry(record).or_insert(0) += delta;
+    }
+    result.into_iter().filter(|x| x.1 != 0).collect()
+}
+
+/// Executes `logic` with `workers` workers, introducing `inputs[t]` at each time `t`.
+///
+/// The updates at each time are distributed round-robin by their position in `inputs[t]`, so
+/// that the assignment of updates to workers is the same from run to run. Each time is completed
+/// before the updates of the next time are introduced. The outputs of all workers are gathered,
+/// consolidated by time and record, and returned as `(time, record, delta)` triples sorted first
+/// by time and then by record. This is meant to make multi-worker unit tests reproducible, and
+/// comparable with single-worker executions of the same computation.
+pub fn execute<D, R, L>(workers: usize, inputs: Vec<Vec<(D, Delta)>>, logic: L) -> Vec<(u64, R, Delta)>
+where D: Data,
+      R: Data,
+      L: Fn(&Collection<TestScope, D>)->Collection<TestScope, R>+Send+Sync+'static {
+
+    let inputs = Arc::new(Mutex::new(inputs));
+    let outputs = Arc::new(Mutex::new(Vec::new()));
+
+    let worker_outputs = outputs.clone();
+    timely::execute(Configuration::Process(workers), move |root| {
+
+        let index = root.index();
+        let peers = root.peers();
+
+        // copy out this worker's share of the updates at each time.
+        let updates = inputs.lock().unwrap().iter().map(|updates| {
+            updates.iter()
+                   .enumerate()
+                   .filter(|&(position, _)| position % peers == index)
+                   .map(|(_, update)| update.clone())
+                   .collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+
+        let sink = worker_outputs.clone();
+        let (mut input, probe) = root.scoped::<u64,_,_>(|scope| {
+            let (input, stream) = scope.new_input();
+            let (probe, _) = logic(&Collection::new(stream))
+                .inspect_batch(move |time, data| {
+                    let mut sink = sink.lock().unwrap();
+                    for &(ref record, delta) in data {
+                        sink.push(((time.inner, record.clone()), delta));
+                    }
+                })
+                .probe();
+            (input, probe)
+        });
+
+        for (round, updates) in updates.into_iter().enumerate() {
+            for update in updates.into_iter() {
+                input.send(update);
+            }
+            input.advance_to(round as u64 + 1);
+            while probe.le(&RootTimestamp::new(round as u64)) { root.step(); }
+        }
+    });
+
+    let outputs = ::std::mem::replace(&mut *outputs.lock().unwrap(), Vec::new());
+    accumulate(outputs.into_iter())
+        .into_iter()
+        .map(|((time, record), delta)| (time, record, delta))
+        .collect()
+}
+
+/// Executes `logic` on random updates and compares its output against `reference` at each time.
+///
+/// The computation is run by `execute` with `settings.workers` workers, over updates produced by
+/// `random_updates`. After each round, the outputs of all workers at times up to and including the
+/// round are accumulated and compared with the result of applying `reference` to the accumulated
+/// input, which is presented as a sorted list of records and their (positive) multiplicities. The
+/// first disagreement, in order of time and then record, is reported.
+pub fn check<D, R, G, L, F>(settings: Settings, generate: G, logic: L, reference: F) -> Result<(), Divergence<R>>
+where D: Data,
+      R: Data,
+      G: Fn(&mut Generator)->D,
+      L: Fn(&Collection<TestScope, D>)->Collection<TestScope, R>+Send+Sync+'static,
+      F: Fn(&[(D, Delta)])->Vec<(R, Delta)> {
+
+    let updates = random_updates(&settings, &generate);
+    let outputs = execute(settings.workers, updates.clone(), logic);
+
+    let mut inputs = Vec::new();
+    let mut actual = Vec::new();
+    let mut outputs = outputs.into_iter().peekable();
+
+    for (round, updates) in updates.into_iter().enumerate() {
+
+        let time = round as u64;
+        inputs.extend(updates.into_iter());
+        while outputs.peek().map(|x| x.0 <= time) == Some(true) {
+            let (_, record, delta) = outputs.next().unwrap();
+            actual.push((record, delta));
+        }
+
+        let accumulated = accumulate(inputs.iter().cloned()).into_iter().collect::<Vec<_>>();
+        let expected = accumulate(reference(&accumulated[..]).into_iter());
+        let observed = accumulate(actual.iter().cloned());
+
+        // walk the two sorted maps together, looking for the first disagreement.
+        let mut expected = expected.into_iter().peekable();
+        let mut observed = observed.into_iter().peekable();
+        loop {
+            let divergence = match (expected.peek().cloned(), observed.peek().cloned()) {
+                (None, None) => break,
+                (Some((record, count)), None) => Some((record, count, 0)),
+                (None, Some((record, count))) => Some((record, 0, count)),
+                (Some((record1, count1)), Some((record2, count2))) => {
+                    if record1 < record2 { Some((record1, count1, 0)) }
+                    else if record1 > record2 { Some((record2, 0, count2)) }
+                    else {
+                        expected.next();
+                        observed.next();
+                        if count1 != count2 { Some((record1, count1, count2)) } else { None }
+                    }
+                }
+            };
+
+            if let Some((record, expected, actual)) = divergence {
+                return Err(Divergence { record: record, time: time, expected: expected, actual: actual });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::{BTreeMap, HashMap};
+
+    use operators::*;
+    use operators::join::JoinUnsigned;
+    use super::{Settings, check, execute, random_updates};
+
+    fn settings() -> Settings {
+        Settings { workers: 3, rounds: 8, updates: 20, seed: 0 }
+    }
+
+    #[test] fn group_min() {
+        let result = check(
+            settings(),
+            |rng| (rng.gen_range(0, 10) as u32, rng.gen_range(0, 10) as u32),
+            |input| input.group(|_, s, t| t.push((*s.peek().unwrap().0, 1))),
+            |input| {
+                let mut mins = BTreeMap::new();
+                for &((key, val), _) in input {
+                    let min = mins.entry(key).or_insert(val);
+                    if val < *min { *min = val; }
+                }
+                mins.into_iter().map(|x| (x, 1)).collect()
+            });
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test] fn join_self() {
+        let result = check(
+            settings(),
+            |rng| (rng.gen_range(0, 10) as u32, rng.gen_range(0, 10) as u32),
+            |input| input.join(input),
+            |input| {
+                let mut output = Vec::new();
+                for &((key1, val1), wgt1) in input {
+                    for &((key2, val2), wgt2) in input {
+                        if key1 == key2 {
+                            output.push(((key1, val1, val2), wgt1 * wgt2));
+                        }
+                    }
+                }
+                output
+            });
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test] fn cogroup_counts() {
+        let result = check(
+            settings(),
+            |rng| (rng.gen_range(0, 10) as u32, rng.gen_range(0, 10) as u32),
+            |input| input.cogroup_by_inner(
+                &input.map(|(key, val)| (key, val + 100)),
+                |key| *key as u64,
+                |key, count| (*key, *count),
+                |_| HashMap::new(),
+                |_, s1, s2, t| {
+                    let count = s1.fold(0, |sum, (_, wgt)| sum + wgt) + s2.fold(0, |sum, (_, wgt)| sum + wgt);
+                    t.push((count, 1));
+                }),
+            |input| {
+                let mut counts = BTreeMap::new();
+                for &((key, _), wgt) in input {
+                    *counts.entry(key).or_insert(0) += 2 * wgt;
+                }
+                counts.into_iter().map(|x| (x, 1)).collect()
+            });
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test] fn threshold_distinct() {
+        let result = check(
+            settings(),
+            |rng| rng.gen_range(0, 20) as u32,
+            |input| input.threshold(|x| *x as u64, |_| HashMap::new(), |_, _| 1),
+            |input| input.iter().map(|&(record, _)| (record, 1)).collect());
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test] fn iterate_halving() {
+        let result = check(
+            settings(),
+            |rng| rng.gen_range(0, 64) as u32,
+            |input| input.iterate(|inner| {
+                inner.map(|x| if x % 2 == 0 { x / 2 } else { x })
+                     .consolidate()
+            }),
+            |input| {
+                input.iter().map(|&(mut record, wgt)| {
+                    while record > 0 && record % 2 == 0 { record /= 2; }
+                    (record, wgt)
+                }).collect()
+            });
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test] fn execute_workers_agree() {
+        let updates = random_updates(&settings(), &|rng| (rng.gen_range(0, 100) as u32, rng.gen_range(0, 100) as u32));
+        let single = execute(1, updates.clone(), |input| input.join_map_u(input, |k, v1, v2| (*k, *v1, *v2)));
+        let multiple = execute(4, updates.clone(), |input| input.join_map_u(input, |k, v1, v2| (*k, *v1, *v2)));
+        assert_eq!(single, multiple);
+    }
+}
@@ -0,0 +1,405 @@
+//! Partially ordered timestamps for collections indexed by more than one notion of time.
+//!
+//! Timely's `Product` pairs a scope's timestamp with that of its parent, and is only created by
+//! nesting scopes. The types here are timestamps in their own right, usable for any scope including
+//! the top-level scope of a dataflow, and implement `Lattice` so that `group`, `join`, `threshold`
+//! and the other differential operators accept them.
+//!
+//! `Pair` orders pairs of times by the product order, and is suited to bitemporal collections,
+//! indexed both by the time an event occurred and the time the system learned of it. The collection
+//! "as of event time `e`, known by system time `s`" accumulates the differences at times less or
+//! equal to `Pair::new(e, s)`. `VectorClock` orders fixed-length arrays of counters coordinatewise,
+//! for example one counter for each of several independent sources.
+//!
+//! #Examples
+//!
+//! An input handle advances through a chain of times, so a bitemporal input is introduced at the
+//! current system time and then delayed to each record's event time:
+//!
+//! ```ignore
+//! let (mut input, probe) = computation.scoped::<Pair<u64, u64>,_,_>(|scope| {
+//!     let (input, stream) = scope.new_input();
+//!     let stream = stream.delay(|x: &((u64, String), i32), time| {
+//!         RootTimestamp::new(Pair::new((x.0).0, time.inner.second))
+//!     });
+//!     let counts = Collection::new(stream).map(|(_, word)| (word, ())).group(|_, s, t| {
+//!         t.push((s.fold(0, |sum, (_, w)| sum + w), 1))
+//!     });
+//!     let probe = counts.inner.probe();
+//!     (input, probe.0)
+//! });
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt::{Debug, Error, Formatter};
+
+use abomonation::Abomonation;
+use timely::progress::{Timestamp, PathSummary};
+
+use collection::{LeastUpperBound, Lattice};
+
+/// A pair of times, ordered by the product order: one pair is less or equal to another when both
+/// of its coordinates are.
+///
+/// `Pair` is its own path summary type, advancing each coordinate by the corresponding summary.
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Default, Ord)]
+pub struct Pair<T1, T2> {
+    /// The first coordinate.
+    pub first: T1,
+    /// The second coordinate.
+    pub second: T2,
+}
+
+impl<T1, T2> Pair<T1, T2> {
+    /// Constructs a new pair from its two coordinates.
+    pub fn new(first: T1, second: T2) -> Pair<T1, T2> {
+        Pair {
+            first: first,
+            second: second,
+        }
+    }
+}
+
+impl<T1: Debug, T2: Debug> Debug for Pair<T1, T2> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str(&format!("({:?}, {:?})", self.first, self.second))
+    }
+}
+
+impl<T1: PartialOrd, T2: PartialOrd> PartialOrd for Pair<T1, T2> {
+    #[inline(always)]
+    fn partial_cmp(&self, other: &Pair<T1, T2>) -> Option<Ordering> {
+        match (self <= other, other <= self) {
+            (true, true)   => Some(Ordering::Equal),
+            (true, false)  => Some(Ordering::Less),
+            (false, true)  => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+    #[inline(always)]
+    fn le(&self, other: &Pair<T1, T2>) -> bool {
+        self.first <= other.first && self.second <= other.second
+    }
+    #[inline(always)]
+    fn ge(&self, other: &Pair<T1, T2>) -> bool {
+        self.first >= other.first && self.second >= other.second
+    }
+}
+
+impl<T1: LeastUpperBound, T2: LeastUpperBound> LeastUpperBound for Pair<T1, T2> {
+    #[inline(always)]
+    fn max() -> Self { Pair::new(<T1 as LeastUpperBound>::max(), <T2 as LeastUpperBound>::max()) }
+    fn least_upper_bound(&self, other: &Pair<T1, T2>) -> Pair<T1, T2> {
+        Pair::new(self.first.least_upper_bound(&other.first), self.second.least_upper_bound(&other.second))
+    }
+}
+
+impl<T1: Lattice, T2: Lattice> Lattice for Pair<T1, T2> {
+    #[inline(always)]
+    fn minimum() -> Self { Pair::new(T1::minimum(), T2::minimum()) }
+    fn greatest_lower_bound(&self, other: &Pair<T1, T2>) -> Pair<T1, T2> {
+        Pair::new(self.first.greatest_lower_bound(&other.first), self.second.greatest_lower_bound(&other.second))
+    }
+}
+
+impl<T1: Timestamp, T2: Timestamp> Timestamp for Pair<T1, T2> {
+    type Summary = Pair<T1::Summary, T2::Summary>;
+}
+
+impl<T1: Timestamp, T2: Timestamp> PathSummary<Pair<T1, T2>> for Pair<T1::Summary, T2::Summary> {
+    #[inline]
+    fn results_in(&self, src: &Pair<T1, T2>) -> Pair<T1, T2> {
+        Pair::new(self.first.results_in(&src.first), self.second.results_in(&src.second))
+    }
+    #[inline]
+    fn followed_by(&self, other: &Self) -> Self {
+        Pair::new(self.first.followed_by(&other.first), self.second.followed_by(&other.second))
+    }
+}
+
+impl<T1: Abomonation, T2: Abomonation> Abomonation for Pair<T1, T2> {
+    unsafe fn embalm(&mut self) { self.first.embalm(); self.second.embalm(); }
+    unsafe fn entomb(&self, bytes: &mut Vec<u8>) { self.first.entomb(bytes); self.second.entomb(bytes); }
+    unsafe fn exhume<'a, 'b>(&'a mut self, mut bytes: &'b mut [u8]) -> Option<&'b mut [u8]> {
+        let tmp = bytes; bytes = if let Some(bytes) = self.first.exhume(tmp) { bytes } else { return None };
+        let tmp = bytes; bytes = if let Some(bytes) = self.second.exhume(tmp) { bytes } else { return None };
+        Some(bytes)
+    }
+}
+
+/// A fixed-length array of counters, ordered coordinatewise.
+///
+/// Implemented for arrays `[u64; N]` with `N` from one to eight. Like `Pair`, a `VectorClock` is
+/// its own path summary type, adding the summary's counters to those of the time. Sums saturate at
+/// `u64::max_value()` rather than wrapping, which would take a time backwards.
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Default)]
+pub struct VectorClock<A> {
+    /// The counters, one for each coordinate.
+    pub clocks: A,
+}
+
+impl<A> VectorClock<A> {
+    /// Constructs a new vector clock from its counters.
+    pub fn new(clocks: A) -> VectorClock<A> {
+        VectorClock { clocks: clocks }
+    }
+}
+
+impl<A: Debug> Debug for VectorClock<A> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Debug::fmt(&self.clocks, f)
+    }
+}
+
+impl<A: Abomonation> Abomonation for VectorClock<A> {
+    unsafe fn embalm(&mut self) { self.clocks.embalm(); }
+    unsafe fn entomb(&self, bytes: &mut Vec<u8>) { self.clocks.entomb(bytes); }
+    unsafe fn exhume<'a, 'b>(&'a mut self, bytes: &'b mut [u8]) -> Option<&'b mut [u8]> {
+        self.clocks.exhume(bytes)
+    }
+}
+
+macro_rules! implement_vector_clock {
+    ($($size:expr,)*) => (
+        $(
+            // a total order for sorting, as required by input handles; it extends the partial order.
+            impl Ord for VectorClock<[u64; $size]> {
+                #[inline]
+                fn cmp(&self, other: &Self) -> Ordering { self.clocks.cmp(&other.clocks) }
+            }
+
+            impl PartialOrd for VectorClock<[u64; $size]> {
+                #[inline]
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    match (self <= other, other <= self) {
+                        (true, true)   => Some(Ordering::Equal),
+                        (true, false)  => Some(Ordering::Less),
+                        (false, true)  => Some(Ordering::Greater),
+                        (false, false) => None,
+                    }
+                }
+                #[inline]
+                fn le(&self, other: &Self) -> bool {
+                    self.clocks.iter().zip(other.clocks.iter()).all(|(x, y)| x <= y)
+                }
+                #[inline]
+                fn ge(&self, other: &Self) -> bool {
+                    self.clocks.iter().zip(other.clocks.iter()).all(|(x, y)| x >= y)
+                }
+            }
+
+            impl LeastUpperBound for VectorClock<[u64; $size]> {
+                fn max() -> Self { VectorClock::new([u64::max_value(); $size]) }
+                fn least_upper_bound(&self, other: &Self) -> Self {
+                    let mut result = *self;
+                    for (x, y) in result.clocks.iter_mut().zip(other.clocks.iter()) {
+                        *x = x.least_upper_bound(y);
+                    }
+                    result
+                }
+            }
+
+            impl Lattice for VectorClock<[u64; $size]> {
+                fn minimum() -> Self { VectorClock::new([0; $size]) }
+                fn greatest_lower_bound(&self, other: &Self) -> Self {
+                    let mut result = *self;
+                    for (x, y) in result.clocks.iter_mut().zip(other.clocks.iter()) {
+                        *x = x.greatest_lower_bound(y);
+                    }
+                    result
+                }
+            }
+
+            impl Timestamp for VectorClock<[u64; $size]> {
+                type Summary = VectorClock<[u64; $size]>;
+            }
+
+            impl PathSummary<VectorClock<[u64; $size]>> for VectorClock<[u64; $size]> {
+                #[inline]
+                fn results_in(&self, src: &Self) -> Self { self.followed_by(src) }
+                #[inline]
+                fn followed_by(&self, other: &Self) -> Self {
+                    let mut result = *self;
+                    for (x, y) in result.clocks.iter_mut().zip(other.clocks.iter()) {
+                        *x = x.saturating_add(*y);
+                    }
+                    result
+                }
+            }
+        )*
+    )
+}
+
+implement_vector_clock!(1, 2, 3, 4, 5, 6, 7, 8,);
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use timely::{self, Configuration};
+    use timely::dataflow::Scope;
+    use timely::dataflow::scopes::{Child, Root};
+    use timely::dataflow::operators::{Input, Delay};
+    use timely::progress::PathSummary;
+    use timely::progress::nested::product::Product;
+    use timely::progress::timestamp::RootTimestamp;
+    use timely_communication::Allocator;
+
+    use ::{Collection, Data, Delta};
+    use collection::{LeastUpperBound, Lattice};
+    use operators::{Group, Join, Threshold};
+    use testing::accumulate;
+    use super::{Pair, VectorClock};
+
+    type PairScope = Child<Root<Allocator>, Pair<u64, u64>>;
+
+    // updates as `(system time, event time, (key, val), delta)`; keys 1 and 2 each see differences
+    // at incomparable times, whose least upper bounds the operators must also consider.
+    const LEFT: &'static [(u64, u64, (u32, u32), Delta)] = &[
+        (0, 1, (1, 10), 1), (0, 0, (2, 12), 1),
+        (1, 0, (1, 11), 1),
+        (2, 1, (1, 10), -1), (2, 2, (2, 12), -1),
+    ];
+    const RIGHT: &'static [(u64, u64, (u32, u32), Delta)] = &[
+        (0, 0, (1, 20), 1),
+        (1, 2, (1, 21), 1),
+        (2, 0, (2, 22), 1),
+    ];
+
+    // records are introduced at the system time, and delayed to their event time.
+    fn delay(x: &((u64, (u32, u32)), Delta), time: &Product<RootTimestamp, Pair<u64, u64>>) -> Product<RootTimestamp, Pair<u64, u64>> {
+        RootTimestamp::new(Pair::new((x.0).0, time.inner.second))
+    }
+
+    // runs `logic` on `LEFT` and `RIGHT`, returning its output updates.
+    fn execute<R, L>(logic: L) -> Vec<(Pair<u64, u64>, R, Delta)>
+    where R: Data,
+          L: Fn(&Collection<PairScope, (u32, u32)>, &Collection<PairScope, (u32, u32)>)->Collection<PairScope, R>+Send+Sync+'static {
+
+        let outputs = Arc::new(Mutex::new(Vec::new()));
+        let worker_outputs = outputs.clone();
+        timely::execute(Configuration::Thread, move |root| {
+            let sink = worker_outputs.clone();
+            let (mut left, mut right) = root.scoped::<Pair<u64, u64>,_,_>(|scope| {
+                let (left, stream1) = scope.new_input();
+                let (right, stream2) = scope.new_input();
+                let stream1 = Collection::new(stream1.delay(delay)).map(|(_, record)| record);
+                let stream2 = Collection::new(stream2.delay(delay)).map(|(_, record)| record);
+                logic(&stream1, &stream2).inspect_batch(move |time, data| {
+                    let mut sink = sink.lock().unwrap();
+                    for &(ref record, delta) in data {
+                        sink.push((time.inner, record.clone(), delta));
+                    }
+                });
+                (left, right)
+            });
+
+            for system in 0 .. 3 {
+                for &(s, e, record, delta) in LEFT { if s == system { left.send(((e, record), delta)); } }
+                for &(s, e, record, delta) in RIGHT { if s == system { right.send(((e, record), delta)); } }
+                left.advance_to(Pair::new(0, system + 1));
+                right.advance_to(Pair::new(0, system + 1));
+            }
+        });
+
+        let mut outputs = outputs.lock().unwrap();
+        ::std::mem::replace(&mut *outputs, Vec::new())
+    }
+
+    // accumulates the updates at times less or equal to `query`.
+    fn at<D: Ord+Clone>(updates: &[(Pair<u64, u64>, D, Delta)], query: &Pair<u64, u64>) -> Vec<(D, Delta)> {
+        accumulate(updates.iter().filter(|x| x.0 <= *query).map(|x| (x.1.clone(), x.2))).into_iter().collect()
+    }
+
+    fn input(updates: &[(u64, u64, (u32, u32), Delta)]) -> Vec<(Pair<u64, u64>, (u32, u32), Delta)> {
+        updates.iter().map(|&(s, e, record, delta)| (Pair::new(e, s), record, delta)).collect()
+    }
+
+    #[test]
+    fn pair_join() {
+        let output = execute(|left, right| left.join(right));
+        for event in 0 .. 4 {
+            for system in 0 .. 4 {
+                let query = Pair::new(event, system);
+                let mut expected = Vec::new();
+                for ((key1, val1), wgt1) in at(&input(LEFT), &query) {
+                    for ((key2, val2), wgt2) in at(&input(RIGHT), &query) {
+                        if key1 == key2 { expected.push(((key1, val1, val2), wgt1 * wgt2)); }
+                    }
+                }
+                let expected = accumulate(expected.into_iter()).into_iter().collect::<Vec<_>>();
+                assert_eq!(at(&output, &query), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pair_threshold() {
+        let output = execute(|left, _| {
+            left.map(|(key, _)| key).threshold(|x| *x as u64, |_| HashMap::new(), |_, _| 1)
+        });
+        for event in 0 .. 4 {
+            for system in 0 .. 4 {
+                let query = Pair::new(event, system);
+                let keys = at(&input(LEFT), &query).into_iter().map(|((key, _), count)| (key, count));
+                let expected = accumulate(keys).into_iter().map(|(key, _)| (key, 1)).collect::<Vec<_>>();
+                assert_eq!(at(&output, &query), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pair_group() {
+        // each key's least value, and its number of distinct values.
+        let output = execute(|left, _| {
+            left.group(|_, s, t| {
+                let least = *s.peek().unwrap().0;
+                t.push(((least, s.count() as u32), 1));
+            })
+        });
+        for event in 0 .. 4 {
+            for system in 0 .. 4 {
+                let query = Pair::new(event, system);
+                let mut expected = Vec::new();
+                for ((key, val), _) in at(&input(LEFT), &query) {
+                    if expected.last().map(|x: &(u32, u32, u32)| x.0 == key).unwrap_or(false) {
+                        expected.last_mut().unwrap().2 += 1;
+                    }
+                    else {
+                        expected.push((key, val, 1));
+                    }
+                }
+                let expected = expected.into_iter().map(|(key, least, count)| ((key, (least, count)), 1)).collect::<Vec<_>>();
+                assert_eq!(at(&output, &query), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pair_order() {
+        let a = Pair::new(1u64, 4u64);
+        let b = Pair::new(3u64, 2u64);
+        assert!(!(a <= b) && !(b <= a));
+        assert!(a <= a.least_upper_bound(&b));
+        assert_eq!(a.least_upper_bound(&b), Pair::new(3, 4));
+        assert_eq!(a.greatest_lower_bound(&b), Pair::new(1, 2));
+        assert_eq!(Pair::<u64, u64>::minimum(), Pair::new(0, 0));
+        assert_eq!(Pair::new(1u64, 0u64).results_in(&a), Pair::new(2, 4));
+    }
+
+    #[test]
+    fn vector_clock_order() {
+        let a = VectorClock::new([1u64, 0, 5]);
+        let b = VectorClock::new([0u64, 2, 5]);
+        assert!(!(a <= b) && !(b <= a));
+        assert!(VectorClock::new([0u64, 0, 5]) < a);
+        assert_eq!(a.least_upper_bound(&b), VectorClock::new([1, 2, 5]));
+        assert_eq!(a.greatest_lower_bound(&b), VectorClock::new([0, 0, 5]));
+        assert_eq!(a.followed_by(&b), VectorClock::new([1, 2, 10]));
+        let c = VectorClock::new([u64::max_value() - 1, 0, u64::max_value()]);
+        assert_eq!(a.followed_by(&c), VectorClock::new([u64::max_value(), 0, u64::max_value()]));
+        assert!(a <= c.results_in(&a));
+    }
+}